@@ -0,0 +1,637 @@
+use crate::codec::{BrotliCodec, Codec};
+use crate::crypto::Encryption;
+use crate::envelope::{payload_checksum, Envelope};
+use crate::{WvmDataSettler, WvmDataSettlerError};
+use async_trait::async_trait;
+use futures::future::join_all;
+use object_store::aws::AmazonS3Builder;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, PutPayload};
+use std::collections::VecDeque;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// Partially settled payloads remembered for retries
+const PARTIAL_CAPACITY: usize = 64;
+
+/// Name a payload is stored under by the filesystem and object store backends.
+///
+/// Enveloped blocks are keyed by chain, number and hash, so reorged blocks don't overwrite
+/// each other. Other payloads (batches, chunk manifests) are keyed by their SHA-256.
+pub fn payload_key(payload: &[u8]) -> String {
+    match Envelope::decode(payload) {
+        Ok(envelope) => format!(
+            "{}/{:012}-{}",
+            envelope.header.chain_id,
+            envelope.header.block_number,
+            hex::encode(envelope.header.block_hash)
+        ),
+        Err(_) => format!("payloads/{}", hex::encode(payload_checksum(payload))),
+    }
+}
+
+/// Destination a processed payload is settled to by `FanOutSettler`.
+#[async_trait]
+pub trait SettlementBackend: Send + Sync {
+    /// Identifies the backend in a `FanOutResult`
+    fn name(&self) -> &str;
+
+    /// Whether `settle` returns a settlement tx hash, as opposed to a file path or key
+    fn is_chain(&self) -> bool {
+        false
+    }
+
+    /// Stores `payload`, returning where it went: a tx hash, a file path or an object key
+    async fn settle(&mut self, payload: &[u8]) -> Result<String, WvmDataSettlerError>;
+
+    /// Forwarded from `WvmDataSettler::bump_gas`, backends without gas can ignore it
+    fn bump_gas(&mut self, _percent: u64) {}
+
+    /// Forwarded from `WvmDataSettler::forget_tx`
+    fn forget_tx(&mut self, _tx_hash: &str) -> Result<(), WvmDataSettlerError> {
+        Ok(())
+    }
+}
+
+/// Settles to WeaveVM, or any chain, through a `WvmDataSettler`.
+pub struct WvmBackend<S> {
+    name: String,
+    settler: S,
+}
+
+impl<S> WvmBackend<S>
+where
+    S: WvmDataSettler + Send + Sync,
+{
+    pub fn new(name: impl Into<String>, settler: S) -> Self {
+        Self {
+            name: name.into(),
+            settler,
+        }
+    }
+
+    pub fn settler(&self) -> &S {
+        &self.settler
+    }
+}
+
+#[async_trait]
+impl<S> SettlementBackend for WvmBackend<S>
+where
+    S: WvmDataSettler + Send + Sync,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_chain(&self) -> bool {
+        true
+    }
+
+    async fn settle(&mut self, payload: &[u8]) -> Result<String, WvmDataSettlerError> {
+        self.settler.send_wvm_calldata(payload.to_vec()).await
+    }
+
+    fn bump_gas(&mut self, percent: u64) {
+        self.settler.bump_gas(percent)
+    }
+
+    fn forget_tx(&mut self, tx_hash: &str) -> Result<(), WvmDataSettlerError> {
+        self.settler.forget_tx(tx_hash)
+    }
+}
+
+/// Archives payloads to `<dir>/<payload_key>.bin`.
+/// Files are written to a temporary file, synced and renamed, so readers never see partial
+/// payloads, even after a crash.
+pub struct FilesystemBackend {
+    name: String,
+    dir: PathBuf,
+}
+
+impl FilesystemBackend {
+    pub fn new(name: impl Into<String>, dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        create_dir_all(&dir)?;
+        Ok(Self {
+            name: name.into(),
+            dir,
+        })
+    }
+}
+
+#[async_trait]
+impl SettlementBackend for FilesystemBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn settle(&mut self, payload: &[u8]) -> Result<String, WvmDataSettlerError> {
+        let path = self.dir.join(format!("{}.bin", payload_key(payload)));
+        write_synced(&path, payload)
+            .await
+            .map_err(WvmDataSettlerError::Store)?;
+        Ok(path.display().to_string())
+    }
+}
+
+/// Writes `payload` next to `path`, syncs it and renames it into place
+async fn write_synced(path: &Path, payload: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("bin.tmp");
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    file.write_all(payload).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// Uploads payloads to `<prefix>/<payload_key>.bin` in an object store.
+pub struct ObjectStoreBackend {
+    name: String,
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+}
+
+impl ObjectStoreBackend {
+    pub fn new(name: impl Into<String>, store: Arc<dyn ObjectStore>, prefix: &str) -> Self {
+        Self {
+            name: name.into(),
+            store,
+            prefix: ObjectPath::from(prefix),
+        }
+    }
+
+    ///
+    /// Uploads to an S3-compatible bucket, with credentials, region and endpoint read from
+    /// the `AWS_*` environment variables
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the backend in a `FanOutResult`
+    /// * `bucket` - bucket receiving the payloads
+    /// * `prefix` - key prefix, may be empty
+    pub fn s3(
+        name: impl Into<String>,
+        bucket: &str,
+        prefix: &str,
+    ) -> Result<Self, WvmDataSettlerError> {
+        let store = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()
+            .map_err(|e| WvmDataSettlerError::Config(format!("invalid S3 settings: {}", e)))?;
+        Ok(Self::new(name, Arc::new(store), prefix))
+    }
+}
+
+#[async_trait]
+impl SettlementBackend for ObjectStoreBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn settle(&mut self, payload: &[u8]) -> Result<String, WvmDataSettlerError> {
+        let key = format!("{}.bin", payload_key(payload));
+        let location = self
+            .prefix
+            .parts()
+            .chain(ObjectPath::from(key).parts())
+            .collect();
+        self.store
+            .put(&location, PutPayload::from(payload.to_vec()))
+            .await
+            .map_err(|e| WvmDataSettlerError::Store(std::io::Error::other(e)))?;
+        Ok(location.to_string())
+    }
+}
+
+/// How many backends of a `FanOutSettler` must store a payload for it to count as settled.
+///
+/// The policy counts every backend alike. Whether a chain backend must be among the
+/// successful ones is set separately with `FanOutSettler::with_chain_required`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuccessPolicy {
+    All,
+    Any,
+    Quorum(usize),
+}
+
+impl SuccessPolicy {
+    /// Successful backends required out of `total`
+    pub fn required(&self, total: usize) -> usize {
+        match self {
+            SuccessPolicy::All => total,
+            SuccessPolicy::Any => 1,
+            SuccessPolicy::Quorum(n) => *n,
+        }
+    }
+
+    /// `required`, failing for a quorum of 0 or of more than `total` backends
+    pub fn check(&self, total: usize) -> Result<usize, WvmDataSettlerError> {
+        match self {
+            SuccessPolicy::Quorum(n) if *n == 0 || *n > total => Err(WvmDataSettlerError::Config(
+                format!("quorum of {} out of {} backends", n, total),
+            )),
+            _ => Ok(self.required(total)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BackendOutcome {
+    pub backend: String,
+    /// Set for backends settling on chain, see `SettlementBackend::is_chain`
+    pub is_chain: bool,
+    /// Location returned by the backend
+    pub result: Result<String, WvmDataSettlerError>,
+}
+
+/// Outcome of settling one payload to every backend, in backend order.
+#[derive(Debug)]
+pub struct FanOutResult {
+    pub outcomes: Vec<BackendOutcome>,
+    pub required: usize,
+    /// Whether a chain backend must succeed on top of the policy
+    pub chain_required: bool,
+}
+
+impl FanOutResult {
+    pub fn succeeded(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|outcome| outcome.result.is_ok())
+            .count()
+    }
+
+    /// Whether the success policy was met
+    pub fn is_settled(&self) -> bool {
+        self.succeeded() >= self.required
+    }
+
+    /// Location returned by `backend`, if it succeeded
+    pub fn location(&self, backend: &str) -> Option<&str> {
+        self.outcomes
+            .iter()
+            .find(|outcome| outcome.backend == backend)
+            .and_then(|outcome| outcome.result.as_deref().ok())
+    }
+
+    ///
+    /// Location reported once the success policy is met: the tx hash of the first
+    /// successful chain backend, or the location of the first successful backend when no
+    /// chain backend succeeded and none is required
+    ///
+    /// Fails with a `FanOut` error carrying the first chain failure, or any failure if
+    /// the chain backends succeeded, until the policy is met and, if required, a chain
+    /// backend succeeded.
+    pub fn into_result(self) -> Result<String, WvmDataSettlerError> {
+        let succeeded = self.succeeded();
+        let total = self.outcomes.len();
+        let settled = self.is_settled();
+        if self.chain_required && !self.outcomes.iter().any(|outcome| outcome.is_chain) {
+            return Err(WvmDataSettlerError::Config(
+                "no chain settlement backend configured".to_string(),
+            ));
+        }
+
+        let mut tx_hash = None;
+        let mut location = None;
+        let mut chain_error = None;
+        let mut first_error = None;
+        for outcome in self.outcomes {
+            match outcome.result {
+                Ok(settled_at) if outcome.is_chain && tx_hash.is_none() => {
+                    tx_hash = Some(settled_at)
+                }
+                Ok(settled_at) if location.is_none() => location = Some(settled_at),
+                Err(error) if outcome.is_chain && chain_error.is_none() => {
+                    chain_error = Some(error)
+                }
+                Err(error) if first_error.is_none() => first_error = Some(error),
+                _ => {}
+            }
+        }
+
+        match (settled, tx_hash, location, chain_error.or(first_error)) {
+            (true, Some(tx_hash), _, _) => Ok(tx_hash),
+            (true, None, Some(location), _) if !self.chain_required => Ok(location),
+            (_, _, _, Some(source)) => Err(WvmDataSettlerError::FanOut {
+                succeeded,
+                required: self.required,
+                total,
+                source: Box::new(source),
+            }),
+            // Not reachable: unsettled or without a location means a backend failed
+            _ => Err(WvmDataSettlerError::Config(
+                "fan-out settled without a location".to_string(),
+            )),
+        }
+    }
+}
+
+/// Settles every payload to several backends concurrently, for example WeaveVM, a local
+/// archive and an S3 bucket, and succeeds according to its `SuccessPolicy`.
+///
+/// As a `WvmDataSettler`, it reports the tx hash of its first successful chain backend.
+/// When the policy is met without one, it reports the location of the first successful
+/// backend instead, unless `with_chain_required` is set. Retrying a payload only resends
+/// it to the backends that failed it. Backends settle one payload at a time, so a
+/// pipelined settler behind a `WvmBackend` settles serially.
+pub struct FanOutSettler {
+    backends: Vec<Box<dyn SettlementBackend>>,
+    policy: SuccessPolicy,
+    chain_required: bool,
+    codec: Box<dyn Codec>,
+    encryption: Option<Encryption>,
+    /// Payloads some backends failed, by checksum with the successful locations, oldest first
+    partial: VecDeque<([u8; 32], Vec<Option<String>>)>,
+}
+
+impl FanOutSettler {
+    pub fn new(policy: SuccessPolicy) -> Self {
+        Self {
+            backends: Vec::new(),
+            policy,
+            chain_required: false,
+            codec: Box::new(BrotliCodec::DEFAULT),
+            encryption: None,
+            partial: VecDeque::new(),
+        }
+    }
+
+    pub fn with_backend(mut self, backend: impl SettlementBackend + 'static) -> Self {
+        self.backends.push(Box::new(backend));
+        self
+    }
+
+    /// Codec applied by `process_block`, Brotli by default
+    pub fn with_codec(mut self, codec: impl Codec + 'static) -> Self {
+        self.codec = Box::new(codec);
+        self
+    }
+
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Only counts a payload as settled once a chain backend succeeded on top of the
+    /// policy, so that `send_wvm_calldata` always returns a tx hash. Needed when the tx hash
+    /// is followed, e.g. by a `ConfirmationTracker` or in revert records.
+    pub fn with_chain_required(mut self, required: bool) -> Self {
+        self.chain_required = required;
+        self
+    }
+
+    pub fn policy(&self) -> SuccessPolicy {
+        self.policy
+    }
+
+    /// Settles `payload` to every backend that did not store it yet and reports the outcome
+    /// of each. Fails if the success policy does not fit the backends.
+    pub async fn fan_out(&mut self, payload: &[u8]) -> Result<FanOutResult, WvmDataSettlerError> {
+        let required = self.policy.check(self.backends.len())?;
+        let checksum = payload_checksum(payload);
+        let settled = match self
+            .partial
+            .iter()
+            .position(|(partial, _)| *partial == checksum)
+        {
+            Some(position) => self.partial.remove(position).unwrap().1,
+            None => vec![None; self.backends.len()],
+        };
+
+        let results = join_all(self.backends.iter_mut().zip(settled).map(
+            |(backend, location)| async move {
+                match location {
+                    Some(location) => Ok(location),
+                    None => backend.settle(payload).await,
+                }
+            },
+        ))
+        .await;
+
+        let outcomes: Vec<BackendOutcome> = self
+            .backends
+            .iter()
+            .zip(results)
+            .map(|(backend, result)| BackendOutcome {
+                backend: backend.name().to_string(),
+                is_chain: backend.is_chain(),
+                result,
+            })
+            .collect();
+        if outcomes.iter().any(|outcome| outcome.result.is_err()) {
+            let locations = outcomes
+                .iter()
+                .map(|outcome| outcome.result.as_ref().ok().cloned())
+                .collect();
+            if self.partial.len() == PARTIAL_CAPACITY {
+                self.partial.pop_front();
+            }
+            self.partial.push_back((checksum, locations));
+        }
+
+        Ok(FanOutResult {
+            outcomes,
+            required,
+            chain_required: self.chain_required,
+        })
+    }
+}
+
+#[async_trait]
+impl WvmDataSettler for FanOutSettler {
+    fn codec(&self) -> &dyn Codec {
+        self.codec.as_ref()
+    }
+
+    fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+
+    fn bump_gas(&mut self, percent: u64) {
+        for backend in &mut self.backends {
+            backend.bump_gas(percent);
+        }
+    }
+
+    fn forget_tx(&mut self, tx_hash: &str) -> Result<(), WvmDataSettlerError> {
+        for backend in &mut self.backends {
+            backend.forget_tx(tx_hash)?;
+        }
+        Ok(())
+    }
+
+    async fn send_wvm_calldata(
+        &mut self,
+        block_data: Vec<u8>,
+    ) -> Result<String, WvmDataSettlerError> {
+        self.fan_out(&block_data).await?.into_result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{
+        payload_key, FanOutSettler, FilesystemBackend, ObjectStoreBackend, SuccessPolicy,
+        WvmBackend,
+    };
+    use crate::envelope::BlockMeta;
+    use crate::mock::{MockFailure, MockWvmSink};
+    use crate::{WvmDataSettler, WvmDataSettlerError};
+    use object_store::memory::InMemory;
+    use object_store::path::Path as ObjectPath;
+    use object_store::ObjectStore;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn test_meta(block_number: u64) -> BlockMeta {
+        BlockMeta {
+            chain_id: 9496,
+            block_number,
+            block_hash: [7; 32],
+        }
+    }
+
+    fn quorum_settler(sink: &MockWvmSink, dir: &Path, store: Arc<InMemory>) -> FanOutSettler {
+        FanOutSettler::new(SuccessPolicy::Quorum(2))
+            .with_backend(WvmBackend::new("weavevm", sink.clone()))
+            .with_backend(FilesystemBackend::new("archive", dir).unwrap())
+            .with_backend(ObjectStoreBackend::new("s3", store, "da"))
+    }
+
+    #[tokio::test]
+    pub async fn test_fan_out_quorum() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = MockWvmSink::new();
+        let store = Arc::new(InMemory::new());
+        let mut settler = quorum_settler(&sink, dir.path(), store.clone());
+
+        let payload = settler
+            .process_block_enveloped(&vec![1u8; 64], test_meta(7))
+            .unwrap();
+        assert!(payload_key(&payload).starts_with("9496/000000000007-0707"));
+
+        sink.fail_next(MockFailure::Transport);
+        let result = settler.fan_out(&payload).await.unwrap();
+        assert!(result.is_settled());
+        assert_eq!(result.succeeded(), 2);
+        assert!(result.location("weavevm").is_none());
+
+        let file = result.location("archive").unwrap();
+        assert_eq!(std::fs::read(file).unwrap(), payload);
+        let object = ObjectPath::from(result.location("s3").unwrap());
+        let stored = store.get(&object).await.unwrap().bytes().await.unwrap();
+        assert_eq!(stored.as_ref(), payload.as_slice());
+        // Settled by the policy, reported with the archive location for lack of a tx hash
+        assert_eq!(result.into_result().unwrap(), file);
+    }
+
+    #[tokio::test]
+    pub async fn test_fan_out_retries_only_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = MockWvmSink::new();
+        let store = Arc::new(InMemory::new());
+        let mut settler = quorum_settler(&sink, dir.path(), store.clone());
+
+        let payload = settler
+            .process_block_enveloped(&vec![1u8; 64], test_meta(7))
+            .unwrap();
+        let other = settler
+            .process_block_enveloped(&vec![2u8; 64], test_meta(8))
+            .unwrap();
+        sink.fail_next(MockFailure::Transport);
+        let result = settler.fan_out(&payload).await.unwrap();
+        let object = ObjectPath::from(result.location("s3").unwrap());
+        sink.fail_next(MockFailure::Transport);
+        settler.fan_out(&other).await.unwrap();
+
+        // Interleaved retries only go to WeaveVM, whose tx hash is reported
+        store.delete(&object).await.unwrap();
+        let tx_hash = settler.send_wvm_calldata(payload.clone()).await.unwrap();
+        assert_eq!(sink.payload(&tx_hash).unwrap(), payload);
+        assert!(store.get(&object).await.is_err());
+        let tx_hash = settler.send_wvm_calldata(other.clone()).await.unwrap();
+        assert_eq!(sink.payload(&tx_hash).unwrap(), other);
+        assert_eq!(sink.submissions().len(), 2);
+    }
+
+    #[tokio::test]
+    pub async fn test_fan_out_chain_required() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = MockWvmSink::new();
+        let mut settler = FanOutSettler::new(SuccessPolicy::Any)
+            .with_chain_required(true)
+            .with_backend(WvmBackend::new("weavevm", sink.clone()))
+            .with_backend(FilesystemBackend::new("archive", dir.path()).unwrap());
+        let payload = settler
+            .process_block_enveloped(&vec![1u8; 64], test_meta(7))
+            .unwrap();
+
+        // Met by the policy, but a tx hash is required
+        sink.fail_next(MockFailure::Transport);
+        assert!(matches!(
+            settler.send_wvm_calldata(payload.clone()).await,
+            Err(WvmDataSettlerError::FanOut {
+                succeeded: 1,
+                required: 1,
+                ..
+            })
+        ));
+        settler.send_wvm_calldata(payload).await.unwrap();
+        assert_eq!(sink.submissions().len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_fan_out_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = MockWvmSink::new();
+        let mut settler = FanOutSettler::new(SuccessPolicy::All)
+            .with_backend(WvmBackend::new("weavevm", sink.clone()))
+            .with_backend(FilesystemBackend::new("archive", dir.path()).unwrap());
+        let payload = settler
+            .process_block_enveloped(&vec![1u8; 64], test_meta(7))
+            .unwrap();
+
+        sink.fail_next(MockFailure::Transport);
+        let error = settler
+            .send_wvm_calldata(payload.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            WvmDataSettlerError::FanOut {
+                succeeded: 1,
+                required: 2,
+                ..
+            }
+        ));
+        assert!(error.is_retryable());
+        settler.send_wvm_calldata(payload).await.unwrap();
+        assert_eq!(sink.submissions().len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_fan_out_invalid_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = MockWvmSink::new();
+
+        for policy in [SuccessPolicy::Quorum(0), SuccessPolicy::Quorum(3)] {
+            let mut settler = FanOutSettler::new(policy)
+                .with_backend(WvmBackend::new("weavevm", sink.clone()))
+                .with_backend(FilesystemBackend::new("archive", dir.path()).unwrap());
+            let payload = settler
+                .process_block_enveloped(&vec![1u8; 64], test_meta(7))
+                .unwrap();
+            assert!(matches!(
+                settler.send_wvm_calldata(payload).await,
+                Err(WvmDataSettlerError::Config(_))
+            ));
+        }
+        assert!(sink.submissions().is_empty());
+    }
+}
@@ -0,0 +1,333 @@
+use crate::envelope::BlockMeta;
+use crate::journal::SettlementJournal;
+use crate::retry::IdempotencyStore;
+use crate::WvmDataSettler;
+use async_trait::async_trait;
+use borsh::BorshSerialize;
+use eyre::{eyre, Error};
+use serde::{Deserialize, Serialize};
+use std::fs::{read_to_string, rename, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Historical blocks to archive, e.g. a `ProviderBlockSource` over the node database.
+#[async_trait]
+pub trait BlockSource: Send + Sync {
+    type Payload: BorshSerialize + Send + Sync + 'static;
+
+    /// Highest block available from the source
+    async fn tip(&self) -> Result<u64, Error>;
+
+    /// `None` if the source does not hold `block_number`
+    async fn block(&self, block_number: u64) -> Result<Option<(BlockMeta, Self::Payload)>, Error>;
+}
+
+#[derive(Debug, Clone)]
+pub struct BackfillConfig {
+    pub from: u64,
+    /// Last block to archive, inclusive. Defaults to the source tip when the run starts
+    pub to: Option<u64>,
+    /// Minimum delay between two settlements, `None` to settle as fast as the settler allows
+    pub min_interval: Option<Duration>,
+    /// Blocks settled between two checkpoint writes
+    pub checkpoint_every: u64,
+}
+
+impl Default for BackfillConfig {
+    fn default() -> Self {
+        Self {
+            from: 0,
+            to: None,
+            min_interval: Some(Duration::from_millis(500)),
+            checkpoint_every: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct CheckpointRecord {
+    #[serde(rename = "nextBlock")]
+    next_block: u64,
+}
+
+/// Next block of an interrupted backfill, persisted as JSON.
+///
+/// Written to a temporary file and renamed over the previous checkpoint,
+/// so a crash leaves either the old or the new checkpoint behind.
+pub struct BackfillCheckpoint {
+    path: PathBuf,
+    next_block: Option<u64>,
+}
+
+impl BackfillCheckpoint {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let next_block = if path.exists() {
+            let record: CheckpointRecord = serde_json::from_str(&read_to_string(&path)?)?;
+            Some(record.next_block)
+        } else {
+            None
+        };
+        Ok(Self { path, next_block })
+    }
+
+    /// First block not yet settled, `None` before the first checkpoint
+    pub fn next_block(&self) -> Option<u64> {
+        self.next_block
+    }
+
+    pub fn store(&mut self, next_block: u64) -> std::io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&CheckpointRecord { next_block })?)?;
+        file.sync_all()?;
+        rename(&tmp, &self.path)?;
+        self.next_block = Some(next_block);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackfillReport {
+    /// Blocks settled by this run
+    pub archived: u64,
+    /// Blocks the journal already held a settlement for
+    pub skipped: u64,
+    /// First block after the backfilled range
+    pub next_block: u64,
+}
+
+/// Archives a range of historical blocks through the same `process_block_enveloped`
+/// pipeline as `WvmDaExEx`, for nodes that enabled the ExEx after genesis.
+///
+/// Progress is checkpointed every `checkpoint_every` blocks and a rerun resumes after
+/// the checkpoint. Blocks settled after the last checkpoint are posted again on resume,
+/// unless a journal records them.
+pub struct Backfill<B, S> {
+    source: B,
+    settler: S,
+    config: BackfillConfig,
+    checkpoint: Option<BackfillCheckpoint>,
+    journal: Option<SettlementJournal>,
+}
+
+impl<B, S> Backfill<B, S>
+where
+    B: BlockSource,
+    S: WvmDataSettler + Send + Sync,
+{
+    pub fn new(source: B, settler: S, config: BackfillConfig) -> Self {
+        Self {
+            source,
+            settler,
+            config,
+            checkpoint: None,
+            journal: None,
+        }
+    }
+
+    pub fn with_checkpoint(mut self, checkpoint: BackfillCheckpoint) -> Self {
+        self.checkpoint = Some(checkpoint);
+        self
+    }
+
+    /// Records every block in `journal`, and skips blocks it already holds a settlement for.
+    /// Passing the ExEx journal file keeps blocks it archived from being posted twice, but
+    /// only while the node is stopped: two handles on one file keep separate state and
+    /// compaction replaces the file under the other one.
+    pub fn with_journal(mut self, journal: SettlementJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    pub fn settler(&self) -> &S {
+        &self.settler
+    }
+
+    pub fn into_settler(self) -> S {
+        self.settler
+    }
+
+    /// Settles every block of the configured range after the checkpoint
+    pub async fn run(&mut self) -> Result<BackfillReport, Error> {
+        let to = match self.config.to {
+            Some(to) => to,
+            None => self.source.tip().await?,
+        };
+        let from = self
+            .checkpoint
+            .as_ref()
+            .and_then(BackfillCheckpoint::next_block)
+            .map_or(self.config.from, |next| next.max(self.config.from));
+
+        let mut report = BackfillReport {
+            next_block: from,
+            ..Default::default()
+        };
+        let mut next_send = Instant::now();
+        let mut unsaved = 0;
+
+        for block_number in from..=to {
+            let (meta, payload) =
+                self.source.block(block_number).await?.ok_or_else(|| {
+                    eyre!("Block {} is not available from the source", block_number)
+                })?;
+
+            if self
+                .journal
+                .as_ref()
+                .is_some_and(|journal| journal.settled(&meta).is_some())
+            {
+                report.skipped += 1;
+            } else {
+                if let Some(min_interval) = self.config.min_interval {
+                    tokio::time::sleep_until(next_send).await;
+                    next_send = Instant::now() + min_interval;
+                }
+                if let Err(error) = self.settle(meta, payload).await {
+                    if unsaved > 0 {
+                        self.store_checkpoint(report.next_block)?;
+                    }
+                    return Err(error);
+                }
+                report.archived += 1;
+            }
+
+            report.next_block = block_number + 1;
+            unsaved += 1;
+            if unsaved >= self.config.checkpoint_every {
+                self.store_checkpoint(report.next_block)?;
+                unsaved = 0;
+            }
+        }
+
+        if unsaved > 0 {
+            self.store_checkpoint(report.next_block)?;
+        }
+        Ok(report)
+    }
+
+    async fn settle(&mut self, meta: BlockMeta, payload: B::Payload) -> Result<(), Error> {
+        if let Some(journal) = &mut self.journal {
+            journal.mark_pending(meta.block_number, meta.block_hash)?;
+        }
+
+        let block_data = self
+            .settler
+            .process_block_enveloped_async(payload, meta)
+            .await?;
+        match self.settler.send_wvm_calldata(block_data).await {
+            Ok(tx_hash) => {
+                if let Some(journal) = &mut self.journal {
+                    journal.mark_submitted(meta.block_number, meta.block_hash, &tx_hash)?;
+                }
+                Ok(())
+            }
+            Err(error) => {
+                if let Some(journal) = &mut self.journal {
+                    journal.mark_failed(meta.block_number, meta.block_hash, &error.to_string())?;
+                }
+                Err(error.into())
+            }
+        }
+    }
+
+    fn store_checkpoint(&mut self, next_block: u64) -> std::io::Result<()> {
+        match &mut self.checkpoint {
+            Some(checkpoint) => checkpoint.store(next_block),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backfill::{Backfill, BackfillCheckpoint, BackfillConfig, BlockSource};
+    use crate::envelope::{BlockMeta, Envelope};
+    use crate::journal::SettlementJournal;
+    use crate::mock::{MockFailure, MockWvmSink};
+    use async_trait::async_trait;
+    use eyre::Error;
+    use std::time::{Duration, Instant};
+
+    struct TestSource {
+        tip: u64,
+    }
+
+    #[async_trait]
+    impl BlockSource for TestSource {
+        type Payload = Vec<u8>;
+
+        async fn tip(&self) -> Result<u64, Error> {
+            Ok(self.tip)
+        }
+
+        async fn block(&self, block_number: u64) -> Result<Option<(BlockMeta, Vec<u8>)>, Error> {
+            let meta = BlockMeta {
+                chain_id: 9496,
+                block_number,
+                block_hash: [block_number as u8; 32],
+            };
+            Ok((block_number <= self.tip).then(|| (meta, vec![block_number as u8; 8])))
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_backfill_resumes_from_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint_path = dir.path().join("checkpoint.json");
+        let journal_path = dir.path().join("journal");
+
+        let sink = MockWvmSink::new();
+        let config = BackfillConfig {
+            from: 2,
+            min_interval: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+
+        let mut backfill = Backfill::new(TestSource { tip: 4 }, sink.clone(), config.clone())
+            .with_checkpoint(BackfillCheckpoint::open(&checkpoint_path).unwrap());
+        let started = Instant::now();
+        let report = backfill.run().await.unwrap();
+        assert_eq!((report.archived, report.next_block), (3, 5));
+        assert!(started.elapsed() >= Duration::from_millis(40));
+
+        // Fails settling block 5
+        sink.fail_next(MockFailure::Transport);
+        let mut backfill = Backfill::new(TestSource { tip: 6 }, sink.clone(), config.clone())
+            .with_checkpoint(BackfillCheckpoint::open(&checkpoint_path).unwrap());
+        assert!(backfill.run().await.is_err());
+        assert_eq!(
+            BackfillCheckpoint::open(&checkpoint_path)
+                .unwrap()
+                .next_block(),
+            Some(5)
+        );
+
+        // Block 5 was settled by the live ExEx in the meantime
+        let mut journal = SettlementJournal::open(&journal_path).unwrap();
+        journal.mark_submitted(5, [5; 32], "0x05").unwrap();
+        let mut backfill = Backfill::new(TestSource { tip: 6 }, sink.clone(), config)
+            .with_checkpoint(BackfillCheckpoint::open(&checkpoint_path).unwrap())
+            .with_journal(journal);
+        let report = backfill.run().await.unwrap();
+        assert_eq!(
+            (report.archived, report.skipped, report.next_block),
+            (1, 1, 7)
+        );
+
+        let settled: Vec<u64> = sink
+            .submissions()
+            .iter()
+            .map(|submission| {
+                Envelope::decode(&submission.payload)
+                    .unwrap()
+                    .header
+                    .block_number
+            })
+            .collect();
+        assert_eq!(settled, vec![2, 3, 4, 6]);
+    }
+}
@@ -0,0 +1,492 @@
+use crate::cost::BudgetPressure;
+use crate::merkle::{MerkleProof, MerkleTree};
+use crate::{WvmDataSettler, WvmDataSettlerError};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Leading bytes of every multi-block payload.
+pub const BATCH_MAGIC: [u8; 4] = *b"WVMB";
+pub const BATCH_VERSION: u8 = 1;
+
+/// magic | version | entry_count | merkle_root
+const BATCH_HEADER_LEN: usize = 4 + 1 + 4 + 32;
+/// block_number | offset | len
+const BATCH_ENTRY_LEN: usize = 8 + 4 + 4;
+
+#[derive(Debug, Error)]
+pub enum BatchError {
+    #[error("Invalid batch magic bytes")]
+    InvalidMagic,
+
+    #[error("Unsupported batch version: {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Batch truncated: expected {expected} bytes, got {actual}")]
+    Truncated { expected: usize, actual: usize },
+
+    #[error("Batch Merkle root does not match its blocks")]
+    RootMismatch,
+
+    #[error("Batch entry of block {0} points outside the batch data")]
+    InvalidEntry(u64),
+
+    #[error("Batch of {0} bytes is too large for its entries to address")]
+    TooLarge(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// Upper bound for the encoded batch size
+    pub max_bytes: usize,
+    pub max_blocks: usize,
+    /// Maximum time the oldest pending block waits before the batch is settled
+    pub max_delay: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1024 * 1024,
+            max_blocks: 64,
+            max_delay: Duration::from_secs(12),
+        }
+    }
+}
+
+/// Location of a single block inside an encoded batch.
+/// `offset` is absolute, so `&calldata[offset..offset + len]` is the block payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchEntry {
+    pub block_number: u64,
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// Indexed multi-block payload settled in a single WeaveVM transaction,
+/// committing to its blocks with a Merkle root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchPayload {
+    pub entries: Vec<BatchEntry>,
+    /// Root of the `MerkleTree` over the batch blocks
+    pub merkle_root: [u8; 32],
+    pub data: Vec<u8>,
+}
+
+impl BatchPayload {
+    pub fn new(blocks: &[(u64, Vec<u8>)]) -> Result<Self, BatchError> {
+        let data_len = blocks.iter().map(|(_, b)| b.len()).sum();
+        let too_large = || BatchError::TooLarge(Self::encoded_len(blocks.len(), data_len));
+        let mut offset = BATCH_HEADER_LEN + BATCH_ENTRY_LEN * blocks.len();
+        let mut entries = Vec::with_capacity(blocks.len());
+        let mut data = Vec::with_capacity(data_len);
+
+        for (block_number, block_data) in blocks {
+            entries.push(BatchEntry {
+                block_number: *block_number,
+                offset: u32::try_from(offset).map_err(|_| too_large())?,
+                len: u32::try_from(block_data.len()).map_err(|_| too_large())?,
+            });
+            data.extend_from_slice(block_data);
+            offset += block_data.len();
+        }
+
+        let merkle_root = MerkleTree::new(
+            blocks
+                .iter()
+                .map(|(block_number, block_data)| (*block_number, block_data.as_slice())),
+        )
+        .root();
+
+        Ok(Self {
+            entries,
+            merkle_root,
+            data,
+        })
+    }
+
+    pub fn encoded_len(block_count: usize, data_len: usize) -> usize {
+        BATCH_HEADER_LEN + BATCH_ENTRY_LEN * block_count + data_len
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buff = Vec::with_capacity(Self::encoded_len(self.entries.len(), self.data.len()));
+        buff.extend_from_slice(&BATCH_MAGIC);
+        buff.push(BATCH_VERSION);
+        buff.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        buff.extend_from_slice(&self.merkle_root);
+        for entry in &self.entries {
+            buff.extend_from_slice(&entry.block_number.to_be_bytes());
+            buff.extend_from_slice(&entry.offset.to_be_bytes());
+            buff.extend_from_slice(&entry.len.to_be_bytes());
+        }
+        buff.extend_from_slice(&self.data);
+        buff
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, BatchError> {
+        if data.len() < BATCH_HEADER_LEN {
+            return Err(BatchError::Truncated {
+                expected: BATCH_HEADER_LEN,
+                actual: data.len(),
+            });
+        }
+        if data[..4] != BATCH_MAGIC {
+            return Err(BatchError::InvalidMagic);
+        }
+        if data[4] != BATCH_VERSION {
+            return Err(BatchError::UnsupportedVersion(data[4]));
+        }
+
+        let count = u32::from_be_bytes(data[5..9].try_into().unwrap()) as usize;
+        let data_start = BATCH_ENTRY_LEN
+            .checked_mul(count)
+            .and_then(|entries_len| entries_len.checked_add(BATCH_HEADER_LEN))
+            .unwrap_or(usize::MAX);
+        if data.len() < data_start {
+            return Err(BatchError::Truncated {
+                expected: data_start,
+                actual: data.len(),
+            });
+        }
+
+        let entries: Vec<BatchEntry> = data[BATCH_HEADER_LEN..data_start]
+            .chunks_exact(BATCH_ENTRY_LEN)
+            .map(|raw| BatchEntry {
+                block_number: u64::from_be_bytes(raw[..8].try_into().unwrap()),
+                offset: u32::from_be_bytes(raw[8..12].try_into().unwrap()),
+                len: u32::from_be_bytes(raw[12..16].try_into().unwrap()),
+            })
+            .collect();
+
+        for entry in &entries {
+            let start = entry.offset as usize;
+            let end = start.checked_add(entry.len as usize);
+            if start < data_start || !matches!(end, Some(end) if end <= data.len()) {
+                return Err(BatchError::InvalidEntry(entry.block_number));
+            }
+        }
+
+        let batch = Self {
+            entries,
+            merkle_root: data[9..41].try_into().unwrap(),
+            data: data[data_start..].to_vec(),
+        };
+        if batch.merkle_tree().root() != batch.merkle_root {
+            return Err(BatchError::RootMismatch);
+        }
+
+        Ok(batch)
+    }
+
+    fn data_start(&self) -> usize {
+        BATCH_HEADER_LEN + BATCH_ENTRY_LEN * self.entries.len()
+    }
+
+    /// Returns the payload of `block_number`, if it is part of this batch
+    pub fn block(&self, block_number: u64) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|e| e.block_number == block_number)
+            .and_then(|e| self.entry_data(e))
+    }
+
+    /// `None` for entries pointing outside `data`, which `decode` rejects
+    pub(crate) fn entry_data(&self, entry: &BatchEntry) -> Option<&[u8]> {
+        let start = (entry.offset as usize).checked_sub(self.data_start())?;
+        self.data.get(start..start.checked_add(entry.len as usize)?)
+    }
+
+    pub fn merkle_tree(&self) -> MerkleTree {
+        MerkleTree::new(self.entries.iter().map(|entry| {
+            (
+                entry.block_number,
+                self.entry_data(entry).unwrap_or_default(),
+            )
+        }))
+    }
+
+    /// Inclusion proof of `block_number` against `merkle_root`
+    pub fn prove(&self, block_number: u64) -> Option<MerkleProof> {
+        self.merkle_tree().prove(block_number)
+    }
+}
+
+/// Where a block ended up once its batch was settled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchReceipt {
+    pub block_number: u64,
+    pub tx_hash: String,
+    pub offset: u32,
+    pub len: u32,
+    pub merkle_root: [u8; 32],
+    /// Proves the block against `merkle_root` without the rest of the batch
+    pub proof: MerkleProof,
+}
+
+/// Collects processed blocks and settles them as one `BatchPayload` once
+/// the byte budget, block count or delay configured in `BatchConfig` is hit.
+pub struct BatchingWvmDataSettler<S> {
+    settler: S,
+    config: BatchConfig,
+    /// Budget pressure and the factor applied to `max_blocks` and `max_delay` while it is raised
+    pressure: Option<(BudgetPressure, u32)>,
+    pending: Vec<(u64, Vec<u8>)>,
+    pending_bytes: usize,
+    opened_at: Option<Instant>,
+}
+
+impl<S: WvmDataSettler + Send> BatchingWvmDataSettler<S> {
+    pub fn new(settler: S, config: BatchConfig) -> Self {
+        Self {
+            settler,
+            config,
+            pressure: None,
+            pending: vec![],
+            pending_bytes: 0,
+            opened_at: None,
+        }
+    }
+
+    /// Batches up to `factor` times more blocks, waiting as much longer, while `pressure`
+    /// is raised. `max_bytes` is a chain limit and stays unchanged.
+    pub fn with_budget_pressure(mut self, pressure: BudgetPressure, factor: u32) -> Self {
+        self.pressure = Some((pressure, factor.max(1)));
+        self
+    }
+
+    pub fn settler(&self) -> &S {
+        &self.settler
+    }
+
+    fn limits(&self) -> (usize, Duration) {
+        match &self.pressure {
+            Some((pressure, factor)) if pressure.is_raised() => (
+                self.config.max_blocks * *factor as usize,
+                self.config.max_delay * *factor,
+            ),
+            _ => (self.config.max_blocks, self.config.max_delay),
+        }
+    }
+
+    pub fn pending_blocks(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_due(&self) -> bool {
+        if self.pending.is_empty() {
+            return false;
+        }
+        let (max_blocks, max_delay) = self.limits();
+        self.pending.len() >= max_blocks
+            || BatchPayload::encoded_len(self.pending.len(), self.pending_bytes)
+                >= self.config.max_bytes
+            || self
+                .opened_at
+                .is_some_and(|opened_at| opened_at.elapsed() >= max_delay)
+    }
+
+    ///
+    /// Adds a processed block to the current batch, settling the pending blocks that are due.
+    /// Returns the receipts of every block settled by this call.
+    ///
+    /// The block stays pending whether or not settling succeeds, so it must not be pushed
+    /// again after an error: the next `push` or `flush` settles it.
+    ///
+    /// # Arguments
+    ///
+    /// * `block_number` - number of the block `block_data` was produced from
+    /// * `block_data` - output of `WvmDataSettler::process_block`
+    pub async fn push(
+        &mut self,
+        block_number: u64,
+        block_data: Vec<u8>,
+    ) -> Result<Vec<BatchReceipt>, WvmDataSettlerError> {
+        self.opened_at.get_or_insert_with(Instant::now);
+        self.pending_bytes += block_data.len();
+        self.pending.push((block_number, block_data));
+
+        let mut receipts = vec![];
+        while self.is_due() {
+            match self.settle_batch().await {
+                Ok(settled) => receipts.extend(settled),
+                Err(error) if receipts.is_empty() => return Err(error),
+                // The receipts of the settled batch must reach the caller, the failed blocks
+                // stay pending
+                Err(_) => break,
+            }
+        }
+        Ok(receipts)
+    }
+
+    /// Settles the current batch if its delay has expired. Meant to be polled on a timer.
+    pub async fn flush_if_due(&mut self) -> Result<Vec<BatchReceipt>, WvmDataSettlerError> {
+        if self.is_due() {
+            self.flush().await
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    /// Settles all pending blocks, in as many batches as `max_bytes` requires.
+    /// Blocks are kept pending if their batch fails, the error is only returned when
+    /// no batch was settled.
+    pub async fn flush(&mut self) -> Result<Vec<BatchReceipt>, WvmDataSettlerError> {
+        let mut receipts = vec![];
+        while !self.pending.is_empty() {
+            match self.settle_batch().await {
+                Ok(settled) => receipts.extend(settled),
+                Err(error) if receipts.is_empty() => return Err(error),
+                Err(_) => break,
+            }
+        }
+        Ok(receipts)
+    }
+
+    /// Settles the longest run of pending blocks that fits in `max_bytes`, at least one
+    async fn settle_batch(&mut self) -> Result<Vec<BatchReceipt>, WvmDataSettlerError> {
+        let mut count = 0;
+        let mut bytes = 0;
+        for (_, block_data) in &self.pending {
+            if count > 0
+                && BatchPayload::encoded_len(count + 1, bytes + block_data.len())
+                    > self.config.max_bytes
+            {
+                break;
+            }
+            count += 1;
+            bytes += block_data.len();
+        }
+
+        let batch = BatchPayload::new(&self.pending[..count]).map_err(|_| {
+            WvmDataSettlerError::PayloadTooLarge {
+                size: BatchPayload::encoded_len(count, bytes),
+                max: u32::MAX as usize,
+            }
+        })?;
+        let tx_hash = self.settler.send_wvm_calldata(batch.encode()).await?;
+
+        self.pending.drain(..count);
+        self.pending_bytes -= bytes;
+        self.opened_at = (!self.pending.is_empty()).then(Instant::now);
+
+        let tree = batch.merkle_tree();
+        Ok(batch
+            .entries
+            .iter()
+            .map(|entry| BatchReceipt {
+                block_number: entry.block_number,
+                tx_hash: tx_hash.clone(),
+                offset: entry.offset,
+                len: entry.len,
+                merkle_root: batch.merkle_root,
+                proof: tree
+                    .prove(entry.block_number)
+                    .expect("batch blocks are part of its tree"),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::{
+        BatchConfig, BatchError, BatchPayload, BatchingWvmDataSettler, BATCH_HEADER_LEN,
+    };
+    use crate::{WvmDataSettler, WvmDataSettlerError};
+    use async_trait::async_trait;
+
+    #[tokio::test]
+    pub async fn test_batching_settler() {
+        struct TestWvmDa {
+            sent: Vec<Vec<u8>>,
+            fail: bool,
+        }
+
+        #[async_trait]
+        impl WvmDataSettler for TestWvmDa {
+            async fn send_wvm_calldata(
+                &mut self,
+                block_data: Vec<u8>,
+            ) -> Result<String, WvmDataSettlerError> {
+                if self.fail {
+                    return Err(WvmDataSettlerError::from_rpc_error("connection reset"));
+                }
+                self.sent.push(block_data);
+                Ok(format!("0x{:02x}", self.sent.len()))
+            }
+        }
+
+        let config = BatchConfig {
+            max_blocks: 2,
+            ..Default::default()
+        };
+        let test_wvm_da = TestWvmDa {
+            sent: vec![],
+            fail: false,
+        };
+        let mut settler = BatchingWvmDataSettler::new(test_wvm_da, config);
+
+        assert!(settler.push(1, vec![1; 10]).await.unwrap().is_empty());
+        let receipts = settler.push(2, vec![2; 20]).await.unwrap();
+        assert!(settler.push(3, vec![3; 30]).await.unwrap().is_empty());
+
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(settler.settler().sent.len(), 1);
+        assert_eq!(settler.pending_blocks(), 1);
+
+        let calldata = &settler.settler().sent[0];
+        let second = &receipts[1];
+        assert_eq!(second.tx_hash, "0x01");
+        assert_eq!(
+            &calldata[second.offset as usize..(second.offset + second.len) as usize],
+            &[2; 20]
+        );
+
+        let batch = BatchPayload::decode(calldata).unwrap();
+        assert_eq!(batch.block(1), Some(&[1u8; 10][..]));
+        assert_eq!(batch.merkle_root, second.merkle_root);
+        assert!(second.proof.verify_payload(&batch.merkle_root, 2, &[2; 20]));
+        assert!(batch
+            .prove(1)
+            .unwrap()
+            .verify_payload(&second.merkle_root, 1, &[1; 10]));
+
+        // A block whose batch fails stays pending and is settled by the next flush
+        let mut settler = BatchingWvmDataSettler::new(
+            TestWvmDa {
+                sent: vec![],
+                fail: true,
+            },
+            BatchConfig {
+                max_bytes: BatchPayload::encoded_len(1, 40),
+                ..Default::default()
+            },
+        );
+        assert!(settler.push(1, vec![1; 30]).await.unwrap().is_empty());
+        assert!(settler.push(2, vec![2; 30]).await.is_err());
+        assert_eq!(settler.pending_blocks(), 2);
+        settler.settler.fail = false;
+        let receipts = settler.flush().await.unwrap();
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(settler.settler().sent.len(), 2);
+
+        let mut tampered = calldata.clone();
+        *tampered.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(
+            BatchPayload::decode(&tampered),
+            Err(BatchError::RootMismatch)
+        ));
+
+        // Entries pointing into the header or past the data are rejected
+        let offset_at = BATCH_HEADER_LEN + 8;
+        for (offset, len) in [(0u32, 10u32), (u32::MAX, 10), (100, u32::MAX)] {
+            let mut malformed = calldata.clone();
+            malformed[offset_at..offset_at + 4].copy_from_slice(&offset.to_be_bytes());
+            malformed[offset_at + 4..offset_at + 8].copy_from_slice(&len.to_be_bytes());
+            assert!(matches!(
+                BatchPayload::decode(&malformed),
+                Err(BatchError::InvalidEntry(1))
+            ));
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Archives the historical blocks of a stopped reth node to WeaveVM.
+//!
+//! Usage: `wvm-da-backfill <config.json>`. Rerunning with the same config resumes after
+//! the last checkpoint.
+
+use exex_wvm_da::{
+    Backfill, BackfillCheckpoint, BackfillConfig, BrotliCodec, ChainLimits, ChunkingWvmDataSettler,
+    DefaultWvmDataSettler, MemoryIdempotencyStore, PayloadProfile, ProviderBlockSource,
+    RetryPolicy, RetryingWvmDataSettler, SettlementJournal, WvmSettlerConfig,
+};
+use eyre::{eyre, Result};
+use reth::api::NodeTypesWithDBAdapter;
+use reth::chainspec::chain_value_parser;
+use reth::providers::providers::StaticFileProvider;
+use reth::providers::ProviderFactory;
+use reth_db::{open_db_read_only, DatabaseEnv};
+use reth_node_ethereum::EthereumNode;
+use serde::Deserialize;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Profile {
+    HeaderOnly,
+    #[default]
+    BlockWithSenders,
+    BlockWithReceipts,
+}
+
+impl From<Profile> for PayloadProfile {
+    fn from(profile: Profile) -> Self {
+        match profile {
+            Profile::HeaderOnly => PayloadProfile::HeaderOnly,
+            Profile::BlockWithSenders => PayloadProfile::BlockWithSenders,
+            Profile::BlockWithReceipts => PayloadProfile::BlockWithReceipts,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolConfig {
+    /// reth datadir, holding `db` and `static_files`
+    datadir: PathBuf,
+    /// Genesis file of the archived chain
+    genesis: PathBuf,
+    #[serde(default)]
+    profile: Profile,
+    from: u64,
+    /// Last block to archive, inclusive. Defaults to the node tip
+    #[serde(default)]
+    to: Option<u64>,
+    /// Minimum delay between two settlements, 0 to settle as fast as possible
+    #[serde(rename = "minIntervalMs", default)]
+    min_interval_ms: Option<u64>,
+    checkpoint: PathBuf,
+    /// Journal of the ExEx, to skip the blocks it already archived
+    #[serde(default)]
+    journal: Option<PathBuf>,
+    settler: WvmSettlerConfig,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .ok_or_else(|| eyre!("usage: wvm-da-backfill <config.json>"))?;
+    let config: ToolConfig = serde_json::from_str(&read_to_string(path)?)?;
+
+    // Parsed the way reth parses `--chain`, so the genesis format follows the node's
+    let genesis = config
+        .genesis
+        .to_str()
+        .ok_or_else(|| eyre!("genesis path is not valid UTF-8"))?;
+    let chain_spec = chain_value_parser(genesis)?;
+    let chain_id = chain_spec.chain.id();
+
+    let db = open_db_read_only(&config.datadir.join("db"), Default::default())?;
+    let static_files = StaticFileProvider::read_only(config.datadir.join("static_files"), false)?;
+    let factory = ProviderFactory::<NodeTypesWithDBAdapter<EthereumNode, Arc<DatabaseEnv>>>::new(
+        Arc::new(db),
+        chain_spec,
+        static_files,
+    );
+
+    let source = ProviderBlockSource::new(factory, chain_id, config.profile.into())?;
+    // Chunks are retried one by one, blocks too large for one transaction still settle
+    let settler = ChunkingWvmDataSettler::new(
+        RetryingWvmDataSettler::new(
+            DefaultWvmDataSettler::from_config(&config.settler, BrotliCodec::DEFAULT)?,
+            RetryPolicy::default(),
+            MemoryIdempotencyStore::default(),
+        ),
+        ChainLimits::default(),
+    );
+    let backfill_config = BackfillConfig {
+        from: config.from,
+        to: config.to,
+        min_interval: match config.min_interval_ms {
+            Some(0) => None,
+            Some(millis) => Some(Duration::from_millis(millis)),
+            None => BackfillConfig::default().min_interval,
+        },
+        ..Default::default()
+    };
+
+    let mut backfill = Backfill::new(source, settler, backfill_config)
+        .with_checkpoint(BackfillCheckpoint::open(&config.checkpoint)?);
+    if let Some(journal) = &config.journal {
+        backfill = backfill.with_journal(SettlementJournal::open(journal)?);
+    }
+
+    let report = backfill.run().await?;
+    println!(
+        "archived {} blocks, skipped {}, next block {}",
+        report.archived, report.skipped, report.next_block
+    );
+    Ok(())
+}
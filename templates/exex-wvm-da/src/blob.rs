@@ -0,0 +1,328 @@
+use crate::chunk::MANIFEST_MAGIC;
+use crate::codec::Codec;
+use crate::cost::{CostEstimate, TX_BASE_GAS};
+use crate::crypto::Encryption;
+use crate::index::INDEX_MAGIC;
+use crate::provider::GasPriceProvider;
+use crate::revert::REVERT_MAGIC;
+use crate::{PendingSettlement, WvmDataSettler, WvmDataSettlerError};
+use alloy_consensus::{BlobTransactionSidecar, SidecarBuilder, SidecarCoder, SimpleCoder};
+use alloy_eips::eip4844::{DATA_GAS_PER_BLOB, FIELD_ELEMENTS_PER_BLOB, MAX_BLOBS_PER_BLOCK};
+use async_trait::async_trait;
+
+/// Payload bytes per field element, `SimpleCoder` leaves the first byte of each empty
+const BYTES_PER_FIELD_ELEMENT: usize = 31;
+
+/// Number of blobs `build_sidecar` packs `len` bytes of payload into
+pub fn blob_count(len: usize) -> usize {
+    // The first field element holds the payload length
+    (len.div_ceil(BYTES_PER_FIELD_ELEMENT) + 1).div_ceil(FIELD_ELEMENTS_PER_BLOB as usize)
+}
+
+/// Blob gas of a blob transaction carrying `len` bytes of payload.
+pub fn estimate_blob_gas(len: usize) -> u64 {
+    blob_count(len) as u64 * DATA_GAS_PER_BLOB
+}
+
+/// Packs `data` into blobs and computes their KZG commitments and proofs with the Ethereum
+/// trusted setup. CPU heavy, `BlobWvmDataSettler` runs it on the blocking thread pool.
+pub fn build_sidecar(data: &[u8]) -> Result<BlobTransactionSidecar, WvmDataSettlerError> {
+    SidecarBuilder::<SimpleCoder>::from_slice(data)
+        .build()
+        .map_err(|e| WvmDataSettlerError::Blob(e.into()))
+}
+
+/// Payload packed into `sidecar` by `build_sidecar`
+pub fn decode_sidecar(sidecar: &BlobTransactionSidecar) -> Option<Vec<u8>> {
+    SimpleCoder::default()
+        .decode_all(&sidecar.blobs)?
+        .into_iter()
+        .next()
+}
+
+/// How a payload is posted to the settlement chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettlementMode {
+    Calldata,
+    /// Blob transaction (EIP-4844)
+    Blob,
+}
+
+/// Fees of the settlement chain when a payload is settled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeMarket {
+    /// Gas price in wei
+    pub gas_price: u128,
+    /// Blob base fee in wei, `None` on chains without blob transactions
+    pub blob_base_fee: Option<u128>,
+}
+
+impl FeeMarket {
+    pub async fn fetch<P>(prices: &P) -> Result<Self, WvmDataSettlerError>
+    where
+        P: GasPriceProvider + ?Sized + Sync,
+    {
+        let gas_price = prices
+            .gas_price()
+            .await
+            .map_err(|e| WvmDataSettlerError::Gas(e.into()))?;
+        let blob_base_fee = prices
+            .blob_base_fee()
+            .await
+            .map_err(|e| WvmDataSettlerError::Gas(e.into()))?;
+        Ok(Self {
+            gas_price,
+            blob_base_fee,
+        })
+    }
+
+    /// Estimated wei to settle `data` as calldata
+    pub fn calldata_cost(&self, data: &[u8]) -> u128 {
+        CostEstimate::calldata(data, self.gas_price).wei()
+    }
+
+    /// Estimated wei to settle `len` bytes in blobs, `None` without blob support
+    pub fn blob_cost(&self, len: usize) -> Option<u128> {
+        let blob_base_fee = self.blob_base_fee?;
+        Some(TX_BASE_GAS as u128 * self.gas_price + estimate_blob_gas(len) as u128 * blob_base_fee)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BlobConfig {
+    /// Payloads needing more blobs are settled as calldata
+    pub max_blobs: usize,
+    /// Maximum fee per blob gas as a percentage of the current blob base fee, leaving
+    /// headroom for the fee to rise until inclusion
+    pub blob_fee_percent: u64,
+    /// Blocks may be settled in blobs, which consensus clients prune after about 18 days
+    /// and no `CalldataProvider` reads back. Everything is settled as calldata until set.
+    pub allow_pruning: bool,
+}
+
+impl Default for BlobConfig {
+    fn default() -> Self {
+        Self {
+            max_blobs: MAX_BLOBS_PER_BLOCK,
+            blob_fee_percent: 200,
+            allow_pruning: false,
+        }
+    }
+}
+
+/// Records consumers read back to rebuild their view of the archive, never settled in blobs
+const CALLDATA_ONLY: [[u8; 4]; 3] = [INDEX_MAGIC, REVERT_MAGIC, MANIFEST_MAGIC];
+
+impl BlobConfig {
+    /// Blobs when pruning is allowed, `data` is block data, the chain supports blobs,
+    /// `data` fits in `max_blobs` and they are cheaper
+    pub fn mode_for(&self, data: &[u8], fees: &FeeMarket) -> SettlementMode {
+        if !self.allow_pruning
+            || CALLDATA_ONLY
+                .iter()
+                .any(|magic| data.starts_with(magic.as_slice()))
+            || blob_count(data.len()) > self.max_blobs
+        {
+            return SettlementMode::Calldata;
+        }
+        match fees.blob_cost(data.len()) {
+            Some(blob_cost) if blob_cost < fees.calldata_cost(data) => SettlementMode::Blob,
+            _ => SettlementMode::Calldata,
+        }
+    }
+}
+
+/// Settlers that can post payloads in blob transactions.
+#[async_trait]
+pub trait BlobSubmitter {
+    /// Broadcasts a blob transaction carrying `sidecar`, returning its hash
+    async fn send_blobs(
+        &mut self,
+        sidecar: BlobTransactionSidecar,
+        max_fee_per_blob_gas: u128,
+    ) -> Result<String, WvmDataSettlerError>;
+}
+
+/// Settles every payload as calldata or in blobs, whichever is cheaper at the current fees.
+///
+/// Blob payloads are not part of the transaction input, so a `CalldataProvider` can't read
+/// them back: they are served by consensus clients, and only until they are pruned.
+/// Blobs are therefore only used with `BlobConfig::allow_pruning`, and never for index
+/// checkpoints, revert records or chunk manifests.
+pub struct BlobWvmDataSettler<S, P> {
+    settler: S,
+    prices: P,
+    config: BlobConfig,
+    last_mode: Option<SettlementMode>,
+}
+
+impl<S, P> BlobWvmDataSettler<S, P>
+where
+    S: WvmDataSettler + BlobSubmitter + Send + Sync,
+    P: GasPriceProvider + Send + Sync,
+{
+    pub fn new(settler: S, prices: P, config: BlobConfig) -> Self {
+        Self {
+            settler,
+            prices,
+            config,
+            last_mode: None,
+        }
+    }
+
+    pub fn settler(&self) -> &S {
+        &self.settler
+    }
+
+    /// Mode of the latest settlement
+    pub fn last_mode(&self) -> Option<SettlementMode> {
+        self.last_mode
+    }
+
+    /// Picks the mode of `block_data` at the current fees, returning the max fee per blob
+    /// gas when it goes in blobs
+    async fn blob_fee_for(
+        &mut self,
+        block_data: &[u8],
+    ) -> Result<Option<u128>, WvmDataSettlerError> {
+        let fees = FeeMarket::fetch(&self.prices).await?;
+        let mode = self.config.mode_for(block_data, &fees);
+        self.last_mode = Some(mode);
+
+        Ok(match (mode, fees.blob_base_fee) {
+            (SettlementMode::Blob, Some(blob_base_fee)) => {
+                Some((blob_base_fee * self.config.blob_fee_percent as u128 / 100).max(1))
+            }
+            _ => None,
+        })
+    }
+
+    async fn send_in_blobs(
+        &mut self,
+        block_data: Vec<u8>,
+        max_fee_per_blob_gas: u128,
+    ) -> Result<String, WvmDataSettlerError> {
+        let sidecar = tokio::task::spawn_blocking(move || build_sidecar(&block_data))
+            .await
+            .map_err(|e| WvmDataSettlerError::Blob(e.into()))??;
+        self.settler.send_blobs(sidecar, max_fee_per_blob_gas).await
+    }
+}
+
+#[async_trait]
+impl<S, P> WvmDataSettler for BlobWvmDataSettler<S, P>
+where
+    S: WvmDataSettler + BlobSubmitter + Send + Sync,
+    P: GasPriceProvider + Send + Sync,
+{
+    fn codec(&self) -> &dyn Codec {
+        self.settler.codec()
+    }
+
+    fn encryption(&self) -> Option<&Encryption> {
+        self.settler.encryption()
+    }
+
+    fn bump_gas(&mut self, percent: u64) {
+        self.settler.bump_gas(percent)
+    }
+
+    fn forget_tx(&mut self, tx_hash: &str) -> Result<(), WvmDataSettlerError> {
+        self.settler.forget_tx(tx_hash)
+    }
+
+    async fn send_wvm_calldata(
+        &mut self,
+        block_data: Vec<u8>,
+    ) -> Result<String, WvmDataSettlerError> {
+        match self.blob_fee_for(&block_data).await? {
+            Some(max_fee_per_blob_gas) => {
+                self.send_in_blobs(block_data, max_fee_per_blob_gas).await
+            }
+            None => self.settler.send_wvm_calldata(block_data).await,
+        }
+    }
+
+    /// Calldata is left to the wrapped settler, blob transactions are settled right away
+    async fn submit_wvm_calldata(&mut self, block_data: Vec<u8>) -> PendingSettlement {
+        match self.blob_fee_for(&block_data).await {
+            Ok(Some(max_fee_per_blob_gas)) => {
+                PendingSettlement::Done(self.send_in_blobs(block_data, max_fee_per_blob_gas).await)
+            }
+            Ok(None) => self.settler.submit_wvm_calldata(block_data).await,
+            Err(error) => PendingSettlement::Done(Err(error)),
+        }
+    }
+
+    async fn await_wvm_calldata(
+        &mut self,
+        pending: PendingSettlement,
+    ) -> Result<String, WvmDataSettlerError> {
+        self.settler.await_wvm_calldata(pending).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::{
+        blob_count, build_sidecar, decode_sidecar, BlobConfig, BlobWvmDataSettler, SettlementMode,
+    };
+    use crate::cost::{BudgetedWvmDataSettler, CostEstimate};
+    use crate::index::INDEX_MAGIC;
+    use crate::mock::{MockWvmSink, MOCK_GAS_PRICE};
+    use crate::WvmDataSettler;
+
+    #[tokio::test]
+    pub async fn test_blob_settlement() {
+        assert_eq!(blob_count(31 * 4095), 1);
+        assert_eq!(blob_count(31 * 4095 + 1), 2);
+
+        let data = vec![0xabu8; 1000];
+        let sidecar = build_sidecar(&data).unwrap();
+        assert_eq!(sidecar.versioned_hashes().count(), 1);
+        assert_eq!(decode_sidecar(&sidecar).unwrap(), data);
+
+        let sink = MockWvmSink::new();
+        sink.set_blob_base_fee(Some(MOCK_GAS_PRICE));
+        let config = BlobConfig {
+            allow_pruning: true,
+            ..Default::default()
+        };
+        let mut settler = BlobWvmDataSettler::new(sink.clone(), sink.clone(), config.clone());
+
+        // A blob costs as much as 8192 non-zero calldata bytes
+        let small = vec![1u8; 4096];
+        settler.send_wvm_calldata(small).await.unwrap();
+        assert_eq!(settler.last_mode(), Some(SettlementMode::Calldata));
+
+        let large = vec![1u8; 64 * 1024];
+        let tx_hash = settler.send_wvm_calldata(large.clone()).await.unwrap();
+        assert_eq!(settler.last_mode(), Some(SettlementMode::Blob));
+        assert_eq!(sink.payload(&tx_hash).unwrap(), large);
+
+        // Records read back by consumers always go in calldata
+        let mut checkpoint = INDEX_MAGIC.to_vec();
+        checkpoint.resize(64 * 1024, 1);
+        settler.send_wvm_calldata(checkpoint).await.unwrap();
+        assert_eq!(settler.last_mode(), Some(SettlementMode::Calldata));
+
+        // Priced as blobs by a budget in front of the settler
+        let budgeted =
+            BudgetedWvmDataSettler::new(sink.clone(), sink.clone(), vec![]).with_blobs(config);
+        let estimate = budgeted.estimate(&large).await.unwrap();
+        assert!(estimate.blob_gas > 0);
+        assert!(estimate.wei() < CostEstimate::calldata(&large, MOCK_GAS_PRICE).wei());
+
+        sink.set_blob_base_fee(None);
+        settler.send_wvm_calldata(large.clone()).await.unwrap();
+        assert_eq!(settler.last_mode(), Some(SettlementMode::Calldata));
+        assert_eq!(sink.submissions().len(), 4);
+
+        // Without opting in to pruning, blobs are never used
+        sink.set_blob_base_fee(Some(MOCK_GAS_PRICE));
+        let mut settler =
+            BlobWvmDataSettler::new(sink.clone(), sink.clone(), BlobConfig::default());
+        settler.send_wvm_calldata(large).await.unwrap();
+        assert_eq!(settler.last_mode(), Some(SettlementMode::Calldata));
+    }
+}
@@ -0,0 +1,424 @@
+use crate::codec::Codec;
+use crate::crypto::Encryption;
+use crate::envelope::payload_checksum;
+use crate::provider::CalldataProvider;
+use crate::{PendingSettlement, WvmDataSettler, WvmDataSettlerError};
+use async_trait::async_trait;
+use borsh::{BorshDeserialize, BorshSerialize};
+use thiserror::Error;
+
+/// Leading bytes of a single chunk of an oversized payload.
+pub const CHUNK_MAGIC: [u8; 4] = *b"WVMC";
+/// Leading bytes of the manifest tying the chunks of a payload together.
+pub const MANIFEST_MAGIC: [u8; 4] = *b"WVMM";
+pub const CHUNK_VERSION: u8 = 1;
+
+/// magic | version | payload_hash | index | total | checksum
+const CHUNK_HEADER_LEN: usize = 4 + 1 + 32 + 4 + 4 + 32;
+
+/// Length of a `0x`-prefixed hex tx hash
+const TX_HASH_LEN: usize = 2 + 64;
+
+#[derive(Debug, Error)]
+pub enum ChunkError {
+    #[error("Invalid chunk magic bytes")]
+    InvalidMagic,
+
+    #[error("Unsupported chunk version: {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Chunk truncated: expected at least {expected} bytes, got {actual}")]
+    Truncated { expected: usize, actual: usize },
+
+    #[error("Checksum mismatch for chunk {0}")]
+    ChecksumMismatch(u32),
+
+    #[error("Missing chunk {index} of {total}")]
+    MissingChunk { index: u32, total: u32 },
+
+    #[error("Chunk belongs to a different payload")]
+    ForeignChunk,
+
+    #[error("Reassembled payload does not match its hash")]
+    PayloadHashMismatch,
+
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(#[source] std::io::Error),
+}
+
+/// Transaction size limits of the target chain.
+#[derive(Debug, Clone, Copy)]
+pub struct ChainLimits {
+    pub max_calldata_bytes: usize,
+}
+
+impl Default for ChainLimits {
+    /// geth's default pool limit of 128 KiB covers the whole signed transaction, leave
+    /// room for its other fields and the signature
+    fn default() -> Self {
+        Self {
+            max_calldata_bytes: 120 * 1024,
+        }
+    }
+}
+
+impl ChainLimits {
+    pub fn check(&self, size: usize) -> Result<(), WvmDataSettlerError> {
+        if size > self.max_calldata_bytes {
+            return Err(WvmDataSettlerError::PayloadTooLarge {
+                size,
+                max: self.max_calldata_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    fn max_chunk_data(&self) -> usize {
+        self.max_calldata_bytes
+            .saturating_sub(CHUNK_HEADER_LEN)
+            .max(1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// Hash of the full payload, shared by all of its chunks
+    pub payload_hash: [u8; 32],
+    pub index: u32,
+    pub total: u32,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buff = Vec::with_capacity(CHUNK_HEADER_LEN + self.data.len());
+        buff.extend_from_slice(&CHUNK_MAGIC);
+        buff.push(CHUNK_VERSION);
+        buff.extend_from_slice(&self.payload_hash);
+        buff.extend_from_slice(&self.index.to_be_bytes());
+        buff.extend_from_slice(&self.total.to_be_bytes());
+        buff.extend_from_slice(&payload_checksum(&self.data));
+        buff.extend_from_slice(&self.data);
+        buff
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ChunkError> {
+        if data.len() < CHUNK_HEADER_LEN {
+            return Err(ChunkError::Truncated {
+                expected: CHUNK_HEADER_LEN,
+                actual: data.len(),
+            });
+        }
+        if data[..4] != CHUNK_MAGIC {
+            return Err(ChunkError::InvalidMagic);
+        }
+        if data[4] != CHUNK_VERSION {
+            return Err(ChunkError::UnsupportedVersion(data[4]));
+        }
+
+        let index = u32::from_be_bytes(data[37..41].try_into().unwrap());
+        let chunk_data = &data[CHUNK_HEADER_LEN..];
+        if payload_checksum(chunk_data)[..] != data[45..77] {
+            return Err(ChunkError::ChecksumMismatch(index));
+        }
+
+        Ok(Self {
+            payload_hash: data[5..37].try_into().unwrap(),
+            index,
+            total: u32::from_be_bytes(data[41..45].try_into().unwrap()),
+            data: chunk_data.to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ChunkRef {
+    pub index: u32,
+    pub len: u32,
+    pub checksum: [u8; 32],
+    pub tx_hash: String,
+}
+
+/// Settled after all chunks; its tx hash is the one reported for the payload.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ChunkManifest {
+    pub payload_hash: [u8; 32],
+    pub total_len: u64,
+    pub chunks: Vec<ChunkRef>,
+}
+
+impl ChunkManifest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buff = MANIFEST_MAGIC.to_vec();
+        buff.push(CHUNK_VERSION);
+        // Writing into a Vec cannot fail
+        borsh::to_writer(&mut buff, self).expect("chunk manifest serializes");
+        buff
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ChunkError> {
+        if data.len() < 5 || data[..4] != MANIFEST_MAGIC {
+            return Err(ChunkError::InvalidMagic);
+        }
+        if data[4] != CHUNK_VERSION {
+            return Err(ChunkError::UnsupportedVersion(data[4]));
+        }
+        borsh::from_slice(&data[5..]).map_err(ChunkError::InvalidManifest)
+    }
+}
+
+/// Splits `payload` into ordered chunks whose encoding fits in `limits`.
+pub fn split_payload(payload: &[u8], limits: &ChainLimits) -> Vec<Chunk> {
+    let payload_hash = payload_checksum(payload);
+    let parts: Vec<&[u8]> = payload.chunks(limits.max_chunk_data()).collect();
+    let total = parts.len() as u32;
+
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| Chunk {
+            payload_hash,
+            index: index as u32,
+            total,
+            data: data.to_vec(),
+        })
+        .collect()
+}
+
+/// Puts chunks back together, in any input order, and verifies the result.
+pub fn reassemble(mut chunks: Vec<Chunk>) -> Result<Vec<u8>, ChunkError> {
+    chunks.sort_by_key(|chunk| chunk.index);
+    let Some(first) = chunks.first() else {
+        return Err(ChunkError::MissingChunk { index: 0, total: 0 });
+    };
+    let (payload_hash, total) = (first.payload_hash, first.total);
+
+    let mut payload = vec![];
+    for index in 0..total {
+        let chunk = chunks
+            .get(index as usize)
+            .filter(|chunk| chunk.index == index)
+            .ok_or(ChunkError::MissingChunk { index, total })?;
+        if chunk.payload_hash != payload_hash || chunk.total != total {
+            return Err(ChunkError::ForeignChunk);
+        }
+        payload.extend_from_slice(&chunk.data);
+    }
+
+    if payload_checksum(&payload) != payload_hash {
+        return Err(ChunkError::PayloadHashMismatch);
+    }
+    Ok(payload)
+}
+
+///
+/// Reads a settled payload, following the chunk manifest if the payload was chunked
+///
+/// # Arguments
+///
+/// * `provider` - source of settlement calldata
+/// * `tx_hash` - tx hash returned by `send_wvm_calldata`
+pub async fn fetch_payload<P>(provider: &P, tx_hash: &str) -> eyre::Result<Vec<u8>>
+where
+    P: CalldataProvider + ?Sized + Sync,
+{
+    let calldata = provider.get_calldata(tx_hash).await?;
+    if !calldata.starts_with(&MANIFEST_MAGIC) {
+        return Ok(calldata);
+    }
+
+    let manifest = ChunkManifest::decode(&calldata)?;
+    let mut chunks = Vec::with_capacity(manifest.chunks.len());
+    for chunk_ref in &manifest.chunks {
+        let chunk = Chunk::decode(&provider.get_calldata(&chunk_ref.tx_hash).await?)?;
+        if chunk.index != chunk_ref.index || payload_checksum(&chunk.data) != chunk_ref.checksum {
+            return Err(ChunkError::ChecksumMismatch(chunk_ref.index).into());
+        }
+        chunks.push(chunk);
+    }
+
+    let payload = reassemble(chunks)?;
+    if payload.len() as u64 != manifest.total_len
+        || payload_checksum(&payload) != manifest.payload_hash
+    {
+        return Err(ChunkError::PayloadHashMismatch.into());
+    }
+    Ok(payload)
+}
+
+/// Splits payloads larger than `ChainLimits::max_calldata_bytes` across several
+/// transactions and returns the tx hash of their manifest.
+pub struct ChunkingWvmDataSettler<S> {
+    settler: S,
+    limits: ChainLimits,
+}
+
+impl<S> ChunkingWvmDataSettler<S> {
+    pub fn new(settler: S, limits: ChainLimits) -> Self {
+        Self { settler, limits }
+    }
+
+    pub fn settler(&self) -> &S {
+        &self.settler
+    }
+}
+
+#[async_trait]
+impl<S> WvmDataSettler for ChunkingWvmDataSettler<S>
+where
+    S: WvmDataSettler + Send + Sync,
+{
+    fn codec(&self) -> &dyn Codec {
+        self.settler.codec()
+    }
+
+    fn encryption(&self) -> Option<&Encryption> {
+        self.settler.encryption()
+    }
+
+    fn bump_gas(&mut self, percent: u64) {
+        self.settler.bump_gas(percent)
+    }
+
+    fn forget_tx(&mut self, tx_hash: &str) -> Result<(), WvmDataSettlerError> {
+        self.settler.forget_tx(tx_hash)
+    }
+
+    async fn send_wvm_calldata(
+        &mut self,
+        block_data: Vec<u8>,
+    ) -> Result<String, WvmDataSettlerError> {
+        if block_data.len() <= self.limits.max_calldata_bytes {
+            return self.settler.send_wvm_calldata(block_data).await;
+        }
+
+        let chunks = split_payload(&block_data, &self.limits);
+        let mut manifest = ChunkManifest {
+            payload_hash: payload_checksum(&block_data),
+            total_len: block_data.len() as u64,
+            chunks: vec![],
+        };
+
+        // Check the manifest before paying for any chunk
+        let expected = ChunkManifest {
+            chunks: chunks
+                .iter()
+                .map(|chunk| ChunkRef {
+                    index: chunk.index,
+                    len: chunk.data.len() as u32,
+                    checksum: [0; 32],
+                    tx_hash: "0".repeat(TX_HASH_LEN),
+                })
+                .collect(),
+            ..manifest.clone()
+        };
+        self.limits.check(expected.encode().len())?;
+
+        for chunk in chunks {
+            let checksum = payload_checksum(&chunk.data);
+            let len = chunk.data.len() as u32;
+            let tx_hash = self.settler.send_wvm_calldata(chunk.encode()).await?;
+            manifest.chunks.push(ChunkRef {
+                index: chunk.index,
+                len,
+                checksum,
+                tx_hash,
+            });
+        }
+
+        let manifest = manifest.encode();
+        self.limits.check(manifest.len())?;
+        self.settler.send_wvm_calldata(manifest).await
+    }
+
+    /// Payloads that fit in one transaction are left to the wrapped settler, chunked ones
+    /// are settled right away.
+    async fn submit_wvm_calldata(&mut self, block_data: Vec<u8>) -> PendingSettlement {
+        if block_data.len() <= self.limits.max_calldata_bytes {
+            return self.settler.submit_wvm_calldata(block_data).await;
+        }
+        PendingSettlement::Done(self.send_wvm_calldata(block_data).await)
+    }
+
+    async fn await_wvm_calldata(
+        &mut self,
+        pending: PendingSettlement,
+    ) -> Result<String, WvmDataSettlerError> {
+        self.settler.await_wvm_calldata(pending).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{
+        fetch_payload, reassemble, split_payload, ChainLimits, ChunkingWvmDataSettler,
+    };
+    use crate::{CalldataProvider, WvmDataSettler, WvmDataSettlerError};
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct TestWvmDa {
+        sent: HashMap<String, Vec<u8>>,
+    }
+
+    #[async_trait]
+    impl WvmDataSettler for TestWvmDa {
+        async fn send_wvm_calldata(
+            &mut self,
+            block_data: Vec<u8>,
+        ) -> Result<String, WvmDataSettlerError> {
+            let tx_hash = format!("0x{:02x}", self.sent.len());
+            self.sent.insert(tx_hash.clone(), block_data);
+            Ok(tx_hash)
+        }
+    }
+
+    #[async_trait]
+    impl CalldataProvider for TestWvmDa {
+        async fn get_calldata(&self, tx_hash: &str) -> eyre::Result<Vec<u8>> {
+            self.sent
+                .get(tx_hash)
+                .cloned()
+                .ok_or_else(|| eyre::eyre!("unknown tx {}", tx_hash))
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_chunked_round_trip() {
+        let limits = ChainLimits {
+            max_calldata_bytes: 512,
+        };
+        let payload: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+
+        let mut chunks = split_payload(&payload, &limits);
+        assert_eq!(chunks.len(), 3);
+        chunks.reverse();
+        assert_eq!(reassemble(chunks.clone()).unwrap(), payload);
+        chunks.remove(1);
+        assert!(reassemble(chunks).is_err());
+
+        let mut settler = ChunkingWvmDataSettler::new(TestWvmDa::default(), limits);
+        let small_tx = settler.send_wvm_calldata(vec![1; 16]).await.unwrap();
+        let tx_hash = settler.send_wvm_calldata(payload.clone()).await.unwrap();
+
+        let sink = settler.settler();
+        assert_eq!(sink.sent.len(), 5);
+        assert_eq!(fetch_payload(sink, &small_tx).await.unwrap(), vec![1; 16]);
+        assert_eq!(fetch_payload(sink, &tx_hash).await.unwrap(), payload);
+
+        // Too many chunks for one manifest, nothing is sent
+        let mut settler = ChunkingWvmDataSettler::new(
+            TestWvmDa::default(),
+            ChainLimits {
+                max_calldata_bytes: 200,
+            },
+        );
+        let error = settler
+            .send_wvm_calldata(vec![7; 10_000])
+            .await
+            .unwrap_err();
+        assert!(matches!(error, WvmDataSettlerError::PayloadTooLarge { .. }));
+        assert!(settler.settler().sent.is_empty());
+    }
+}
@@ -0,0 +1,368 @@
+use crate::envelope::EnvelopeError;
+use brotlic::{BrotliEncoderOptions, CompressorWriter, DecompressorReader, Quality, WindowSize};
+use std::io::{Read, Write};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("Invalid codec parameters: {0}")]
+    InvalidParameters(String),
+
+    #[error("Compression failed: {0}")]
+    Compress(#[source] std::io::Error),
+
+    #[error("Decompression failed: {0}")]
+    Decompress(#[source] std::io::Error),
+
+    #[error("Failed to produce the data to compress: {0}")]
+    Input(#[source] std::io::Error),
+
+    #[error("Decompressed payload exceeds {max} bytes")]
+    TooLarge { max: u64 },
+}
+
+/// Upper bound of a decompressed payload. Larger outputs are rejected, so that a small
+/// malicious payload can't expand into gigabytes.
+pub const MAX_DECOMPRESSED_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Writes the data to compress into the given writer, e.g. `BorshSerialize::serialize`.
+pub type WriteFn<'a> = dyn FnMut(&mut dyn Write) -> std::io::Result<()> + 'a;
+
+/// Passes writes through to the compressor, remembering whether it failed.
+struct TrackedWriter<'a> {
+    inner: &'a mut dyn Write,
+    failed: bool,
+}
+
+impl Write for TrackedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf).inspect_err(|_| self.failed = true)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().inspect_err(|_| self.failed = true)
+    }
+}
+
+/// Runs `write` against a compressor, telling its failures apart from the input's
+fn write_into(compressor: &mut dyn Write, write: &mut WriteFn) -> Result<(), CodecError> {
+    let mut writer = TrackedWriter {
+        inner: compressor,
+        failed: false,
+    };
+    match write(&mut writer) {
+        Ok(()) => Ok(()),
+        Err(e) if writer.failed => Err(CodecError::Compress(e)),
+        Err(e) => Err(CodecError::Input(e)),
+    }
+}
+
+/// Reads a decompressor to the end, up to `max` bytes
+fn read_bounded(decompressor: impl Read, max: u64) -> Result<Vec<u8>, CodecError> {
+    let mut buff = vec![];
+    decompressor
+        .take(max + 1)
+        .read_to_end(&mut buff)
+        .map_err(CodecError::Decompress)?;
+    if buff.len() as u64 > max {
+        return Err(CodecError::TooLarge { max });
+    }
+    Ok(buff)
+}
+
+/// Codec identifier recorded in every envelope header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CodecId {
+    Identity = 0,
+    Brotli = 1,
+    Zstd = 2,
+    Lz4 = 3,
+}
+
+impl TryFrom<u8> for CodecId {
+    type Error = EnvelopeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CodecId::Identity),
+            1 => Ok(CodecId::Brotli),
+            2 => Ok(CodecId::Zstd),
+            3 => Ok(CodecId::Lz4),
+            other => Err(EnvelopeError::UnknownCodec(other)),
+        }
+    }
+}
+
+/// Compression stage applied to Borsh-serialized blocks before settlement.
+pub trait Codec: Send + Sync {
+    fn id(&self) -> CodecId;
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CodecError>;
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, CodecError>;
+
+    /// Compresses the data produced by `write` as it is written. Codecs without a streaming
+    /// encoder buffer the whole input first.
+    fn compress_from(&self, write: &mut WriteFn) -> Result<Vec<u8>, CodecError> {
+        let mut data = vec![];
+        write(&mut data).map_err(CodecError::Input)?;
+        self.compress(&data)
+    }
+
+    /// Owned copy of the codec, to compress off the async runtime
+    fn boxed_clone(&self) -> Box<dyn Codec>;
+}
+
+///
+/// Returns a codec able to decode payloads tagged with `id`, using default parameters.
+/// Payloads compressed with a zstd dictionary need the matching `ZstdCodec` instead.
+pub fn default_codec(id: CodecId) -> Box<dyn Codec> {
+    match id {
+        CodecId::Identity => Box::new(IdentityCodec),
+        CodecId::Brotli => Box::new(BrotliCodec::DEFAULT),
+        CodecId::Zstd => Box::new(ZstdCodec::default()),
+        CodecId::Lz4 => Box::new(Lz4Codec),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityCodec;
+
+impl Codec for IdentityCodec {
+    fn id(&self) -> CodecId {
+        CodecId::Identity
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        Ok(data.to_vec())
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        Ok(data.to_vec())
+    }
+
+    fn compress_from(&self, write: &mut WriteFn) -> Result<Vec<u8>, CodecError> {
+        let mut data = vec![];
+        write(&mut data).map_err(CodecError::Input)?;
+        Ok(data)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Codec> {
+        Box::new(*self)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BrotliCodec {
+    /// 0 (fastest) to 11 (smallest)
+    quality: u8,
+    /// Base 2 logarithm of the sliding window size, 10 to 24
+    window: u8,
+}
+
+impl BrotliCodec {
+    pub const DEFAULT: BrotliCodec = BrotliCodec {
+        quality: 11,
+        window: 22,
+    };
+
+    pub fn new(quality: u8, window: u8) -> Result<Self, CodecError> {
+        let codec = Self { quality, window };
+        codec.encoder_options()?;
+        Ok(codec)
+    }
+
+    fn encoder_options(&self) -> Result<BrotliEncoderOptions, CodecError> {
+        let quality = Quality::new(self.quality)
+            .map_err(|e| CodecError::InvalidParameters(format!("brotli quality: {}", e)))?;
+        let window = WindowSize::new(self.window)
+            .map_err(|e| CodecError::InvalidParameters(format!("brotli window: {}", e)))?;
+
+        let mut options = BrotliEncoderOptions::new();
+        options.quality(quality).window_size(window);
+        Ok(options)
+    }
+}
+
+impl Default for BrotliCodec {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Codec for BrotliCodec {
+    fn id(&self) -> CodecId {
+        CodecId::Brotli
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        self.compress_from(&mut |writer| writer.write_all(data))
+    }
+
+    fn compress_from(&self, write: &mut WriteFn) -> Result<Vec<u8>, CodecError> {
+        let encoder = self
+            .encoder_options()?
+            .build()
+            .map_err(|e| CodecError::InvalidParameters(e.to_string()))?;
+        let mut compressor = CompressorWriter::with_encoder(encoder, vec![]);
+        write_into(&mut compressor, write)?;
+        compressor
+            .into_inner()
+            .map_err(|e| CodecError::Compress(std::io::Error::other(e.to_string())))
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        read_bounded(DecompressorReader::new(data), MAX_DECOMPRESSED_BYTES)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Codec> {
+        Box::new(*self)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ZstdCodec {
+    level: i32,
+    dictionary: Option<Vec<u8>>,
+}
+
+impl ZstdCodec {
+    pub fn new(level: i32) -> Self {
+        Self {
+            level,
+            dictionary: None,
+        }
+    }
+
+    /// The same dictionary must be supplied to decode the produced payloads.
+    pub fn with_dictionary(level: i32, dictionary: Vec<u8>) -> Self {
+        Self {
+            level,
+            dictionary: Some(dictionary),
+        }
+    }
+
+    ///
+    /// Trains a zstd dictionary from sample payloads, e.g. previously serialized blocks
+    ///
+    /// # Arguments
+    ///
+    /// * `samples` - Borsh-serialized blocks representative of the chain
+    /// * `max_size` - maximum dictionary size in bytes
+    pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>, CodecError> {
+        zstd::dict::from_samples(samples, max_size)
+            .map_err(|e| CodecError::InvalidParameters(format!("zstd dictionary: {}", e)))
+    }
+}
+
+impl Default for ZstdCodec {
+    fn default() -> Self {
+        Self::new(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+impl Codec for ZstdCodec {
+    fn id(&self) -> CodecId {
+        CodecId::Zstd
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        self.compress_from(&mut |writer| writer.write_all(data))
+    }
+
+    fn compress_from(&self, write: &mut WriteFn) -> Result<Vec<u8>, CodecError> {
+        let mut encoder = match &self.dictionary {
+            Some(dictionary) => zstd::Encoder::with_dictionary(vec![], self.level, dictionary),
+            None => zstd::Encoder::new(vec![], self.level),
+        }
+        .map_err(CodecError::Compress)?;
+        write_into(&mut encoder, write)?;
+        encoder.finish().map_err(CodecError::Compress)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        let decoder = match &self.dictionary {
+            Some(dictionary) => zstd::Decoder::with_dictionary(data, dictionary),
+            None => zstd::Decoder::with_buffer(data),
+        }
+        .map_err(CodecError::Decompress)?;
+        read_bounded(decoder, MAX_DECOMPRESSED_BYTES)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Codec> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4Codec;
+
+impl Codec for Lz4Codec {
+    fn id(&self) -> CodecId {
+        CodecId::Lz4
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        self.compress_from(&mut |writer| writer.write_all(data))
+    }
+
+    fn compress_from(&self, write: &mut WriteFn) -> Result<Vec<u8>, CodecError> {
+        let mut encoder = lz4_flex::frame::FrameEncoder::new(vec![]);
+        write_into(&mut encoder, write)?;
+        encoder.finish().map_err(|e| CodecError::Compress(e.into()))
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        read_bounded(
+            lz4_flex::frame::FrameDecoder::new(data),
+            MAX_DECOMPRESSED_BYTES,
+        )
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Codec> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::{
+        default_codec, read_bounded, BrotliCodec, Codec, CodecError, CodecId, ZstdCodec,
+    };
+
+    #[test]
+    pub fn test_codecs_round_trip() {
+        let data: Vec<u8> = (0..4096u32).flat_map(|i| (i % 97).to_be_bytes()).collect();
+
+        for id in [
+            CodecId::Identity,
+            CodecId::Brotli,
+            CodecId::Zstd,
+            CodecId::Lz4,
+        ] {
+            let codec = default_codec(id);
+            assert_eq!(codec.id(), id);
+            let compressed = codec.compress(&data).unwrap();
+            assert_eq!(codec.decompress(&compressed).unwrap(), data);
+        }
+
+        let dictionary = data[..1024].to_vec();
+        let zstd = ZstdCodec::with_dictionary(3, dictionary);
+        let compressed = zstd.compress(&data).unwrap();
+        assert_eq!(zstd.decompress(&compressed).unwrap(), data);
+
+        assert!(BrotliCodec::new(12, 22).is_err());
+
+        let zeros = default_codec(CodecId::Zstd).compress(&[0; 4096]).unwrap();
+        let decoder = zstd::Decoder::new(&zeros[..]).unwrap();
+        assert!(matches!(
+            read_bounded(decoder, 1024),
+            Err(CodecError::TooLarge { max: 1024 })
+        ));
+
+        let result = default_codec(CodecId::Zstd)
+            .compress_from(&mut |_| Err(std::io::Error::other("unserializable block")));
+        assert!(matches!(result, Err(CodecError::Input(_))));
+    }
+}
@@ -0,0 +1,102 @@
+use serde::Deserialize;
+use std::fmt;
+
+/// Gas pricing of settlement transactions.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct GasPolicy {
+    /// Fixed gas limit, estimated by the node when unset
+    #[serde(rename = "gasLimit", default)]
+    pub gas_limit: Option<u64>,
+    /// Percentage applied to the node's gas price, 100 uses it as is
+    #[serde(rename = "gasPricePercent", default = "default_gas_price_percent")]
+    pub gas_price_percent: u64,
+    /// Upper bound in wei, bumps never go above it
+    #[serde(rename = "maxGasPrice", default)]
+    pub max_gas_price: Option<u64>,
+}
+
+fn default_gas_price_percent() -> u64 {
+    100
+}
+
+impl Default for GasPolicy {
+    fn default() -> Self {
+        Self {
+            gas_limit: None,
+            gas_price_percent: default_gas_price_percent(),
+            max_gas_price: None,
+        }
+    }
+}
+
+/// Where settlement transaction nonces come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NonceMode {
+    /// Ask the node for the pending transaction count before every submission
+    #[default]
+    Node,
+    /// Fetch the nonce once per signer and count locally, resyncing after a nonce error.
+    /// Required to have several transactions of one signer in flight.
+    Local,
+}
+
+/// Explicit settings for `DefaultWvmDataSettler::from_config`, replacing the environment
+/// variables read by `wvm_archiver`.
+#[derive(Clone, Deserialize)]
+pub struct WvmSettlerConfig {
+    #[serde(rename = "rpcUrl")]
+    pub rpc_url: String,
+    #[serde(rename = "chainId")]
+    pub chain_id: u64,
+    /// Hex encoded private keys, used round-robin
+    #[serde(rename = "signerKeys")]
+    pub signer_keys: Vec<String>,
+    /// Address receiving the settlement transactions
+    #[serde(rename = "targetAddress")]
+    pub target_address: String,
+    #[serde(default)]
+    pub gas: GasPolicy,
+    #[serde(default)]
+    pub nonce: NonceMode,
+}
+
+impl fmt::Debug for WvmSettlerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WvmSettlerConfig")
+            .field("rpc_url", &self.rpc_url)
+            .field("chain_id", &self.chain_id)
+            .field(
+                "signer_keys",
+                &format!("<{} redacted>", self.signer_keys.len()),
+            )
+            .field("target_address", &self.target_address)
+            .field("gas", &self.gas)
+            .field("nonce", &self.nonce)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{GasPolicy, NonceMode, WvmSettlerConfig};
+
+    #[test]
+    pub fn test_config_from_json() {
+        let json = r#"{
+            "rpcUrl": "https://testnet-rpc.wvm.dev",
+            "chainId": 9496,
+            "signerKeys": [
+                "9234bd23a4180e3a37a565150b058e20987dceb6ac63d98a571ec8197222242c"
+            ],
+            "targetAddress": "0xa2A0D977847805fE224B789D8C4d3D711ab251e7",
+            "nonce": "local"
+        }"#;
+
+        let config: WvmSettlerConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.chain_id, 9496);
+        assert_eq!(config.gas, GasPolicy::default());
+        assert_eq!(config.nonce, NonceMode::Local);
+        assert!(!format!("{:?}", config).contains("9234bd"));
+    }
+}
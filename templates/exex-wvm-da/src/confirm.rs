@@ -0,0 +1,353 @@
+use crate::envelope::BlockMeta;
+use crate::metrics;
+use crate::provider::{ReceiptProvider, TxStatus};
+use crate::WvmDataSettler;
+use eyre::Error;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Lifecycle of a block settlement, published by `ConfirmationTracker`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettlementEvent {
+    /// Broadcast, also emitted for every resubmission
+    Submitted { block: BlockMeta, tx_hash: String },
+    /// Mined in WeaveVM block `included_in`
+    Included {
+        block: BlockMeta,
+        tx_hash: String,
+        included_in: u64,
+    },
+    Finalized {
+        block: BlockMeta,
+        tx_hash: String,
+        confirmations: u64,
+    },
+    Failed {
+        block: BlockMeta,
+        tx_hash: String,
+        reason: String,
+    },
+}
+
+impl SettlementEvent {
+    pub fn block(&self) -> &BlockMeta {
+        match self {
+            SettlementEvent::Submitted { block, .. }
+            | SettlementEvent::Included { block, .. }
+            | SettlementEvent::Finalized { block, .. }
+            | SettlementEvent::Failed { block, .. } => block,
+        }
+    }
+
+    pub fn tx_hash(&self) -> &str {
+        match self {
+            SettlementEvent::Submitted { tx_hash, .. }
+            | SettlementEvent::Included { tx_hash, .. }
+            | SettlementEvent::Finalized { tx_hash, .. }
+            | SettlementEvent::Failed { tx_hash, .. } => tx_hash,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfirmationConfig {
+    /// Confirmations after which a settlement is final, counting the block it is mined in
+    pub confirmations: u64,
+    pub poll_interval: Duration,
+    /// Consecutive polls a transaction may be unknown to the node before it is resubmitted
+    pub unknown_polls: u32,
+    pub max_resubmits: u32,
+    /// Gas bump requested from the settler before every resubmission
+    pub gas_bump_percent: u64,
+    /// Events buffered per subscriber before slow subscribers start lagging
+    pub event_capacity: usize,
+}
+
+impl Default for ConfirmationConfig {
+    fn default() -> Self {
+        Self {
+            confirmations: 6,
+            poll_interval: Duration::from_secs(5),
+            unknown_polls: 3,
+            max_resubmits: 3,
+            gas_bump_percent: 15,
+            event_capacity: 1024,
+        }
+    }
+}
+
+/// Outcome of one `ConfirmationTracker::poll`.
+#[derive(Debug, Default)]
+pub struct PollOutcome {
+    /// Events published by the poll
+    pub events: Vec<SettlementEvent>,
+    /// Transactions whose status could not be fetched, checked again by the next poll
+    pub errors: Vec<(String, Error)>,
+}
+
+struct Tracked {
+    block: BlockMeta,
+    data: Vec<u8>,
+    included_in: Option<u64>,
+    unknown_polls: u32,
+    resubmits: u32,
+    submitted_at: Instant,
+}
+
+/// Follows settlement transactions until they reach the configured number of
+/// confirmations, resubmitting the ones the node no longer knows about.
+pub struct ConfirmationTracker {
+    provider: Box<dyn ReceiptProvider + Send + Sync>,
+    config: ConfirmationConfig,
+    /// Tracked settlements by tx hash
+    tracked: BTreeMap<String, Tracked>,
+    events: broadcast::Sender<SettlementEvent>,
+}
+
+impl ConfirmationTracker {
+    pub fn new(
+        provider: impl ReceiptProvider + Send + Sync + 'static,
+        config: ConfirmationConfig,
+    ) -> Self {
+        let (events, _) = broadcast::channel(config.event_capacity);
+        Self {
+            provider: Box::new(provider),
+            config,
+            tracked: BTreeMap::new(),
+            events,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SettlementEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        self.config.poll_interval
+    }
+
+    /// Number of settlements not yet finalized or failed
+    pub fn pending(&self) -> usize {
+        self.tracked.len()
+    }
+
+    ///
+    /// Starts following a submitted settlement transaction
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - settled block
+    /// * `tx_hash` - settlement transaction
+    /// * `data` - calldata of the transaction, resubmitted if it gets dropped
+    pub fn track(&mut self, block: BlockMeta, tx_hash: String, data: Vec<u8>) {
+        self.publish(SettlementEvent::Submitted {
+            block,
+            tx_hash: tx_hash.clone(),
+        });
+        self.tracked.insert(
+            tx_hash,
+            Tracked {
+                block,
+                data,
+                included_in: None,
+                unknown_polls: 0,
+                resubmits: 0,
+                submitted_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Checks every tracked transaction once, resubmitting dropped ones through `settler`.
+    /// Fails only if the chain head can't be fetched.
+    pub async fn poll<S>(&mut self, settler: &mut S) -> Result<PollOutcome, Error>
+    where
+        S: WvmDataSettler + Send,
+    {
+        let head = self.provider.block_number().await?;
+        let mut events = vec![];
+        let mut errors = vec![];
+
+        let tx_hashes: Vec<String> = self.tracked.keys().cloned().collect();
+        for tx_hash in tx_hashes {
+            let status = match self.provider.tx_status(&tx_hash).await {
+                Ok(status) => status,
+                Err(error) => {
+                    errors.push((tx_hash, error));
+                    continue;
+                }
+            };
+            let Some(tracked) = self.tracked.get_mut(&tx_hash) else {
+                continue;
+            };
+            let block = tracked.block;
+
+            match status {
+                TxStatus::Mined { success: false, .. } => {
+                    self.tracked.remove(&tx_hash);
+                    if let Err(error) = settler.forget_tx(&tx_hash) {
+                        errors.push((tx_hash.clone(), error.into()));
+                    }
+                    events.push(SettlementEvent::Failed {
+                        block,
+                        tx_hash,
+                        reason: "settlement transaction reverted".to_string(),
+                    });
+                }
+                TxStatus::Mined {
+                    block_number, fee, ..
+                } => {
+                    tracked.unknown_polls = 0;
+                    if tracked.included_in != Some(block_number) {
+                        if tracked.included_in.is_none() {
+                            metrics::record_included(tracked.submitted_at.elapsed(), fee);
+                        }
+                        tracked.included_in = Some(block_number);
+                        events.push(SettlementEvent::Included {
+                            block,
+                            tx_hash: tx_hash.clone(),
+                            included_in: block_number,
+                        });
+                    }
+                    let confirmations = head.saturating_sub(block_number) + 1;
+                    if confirmations >= self.config.confirmations {
+                        metrics::record_finalized(tracked.submitted_at.elapsed());
+                        self.tracked.remove(&tx_hash);
+                        events.push(SettlementEvent::Finalized {
+                            block,
+                            tx_hash,
+                            confirmations,
+                        });
+                    }
+                }
+                TxStatus::Pending => {
+                    // Mined before but back in the mempool: reorged out on WeaveVM
+                    tracked.included_in = None;
+                    tracked.unknown_polls = 0;
+                }
+                TxStatus::Unknown => {
+                    tracked.unknown_polls += 1;
+                    if tracked.unknown_polls < self.config.unknown_polls {
+                        continue;
+                    }
+
+                    let tracked = self.tracked.remove(&tx_hash).unwrap();
+                    // Settlers caching the dead hash would hand it back for the resubmission
+                    if let Err(error) = settler.forget_tx(&tx_hash) {
+                        errors.push((tx_hash.clone(), error.into()));
+                    }
+                    if tracked.resubmits >= self.config.max_resubmits {
+                        events.push(SettlementEvent::Failed {
+                            block,
+                            tx_hash,
+                            reason: format!("dropped after {} resubmissions", tracked.resubmits),
+                        });
+                        continue;
+                    }
+
+                    metrics::record_retry("dropped");
+                    settler.bump_gas(self.config.gas_bump_percent);
+                    match settler.send_wvm_calldata(tracked.data.clone()).await {
+                        Ok(new_tx_hash) => {
+                            events.push(SettlementEvent::Submitted {
+                                block,
+                                tx_hash: new_tx_hash.clone(),
+                            });
+                            self.tracked.insert(
+                                new_tx_hash,
+                                Tracked {
+                                    included_in: None,
+                                    unknown_polls: 0,
+                                    resubmits: tracked.resubmits + 1,
+                                    submitted_at: Instant::now(),
+                                    ..tracked
+                                },
+                            );
+                        }
+                        Err(error) => events.push(SettlementEvent::Failed {
+                            block,
+                            tx_hash,
+                            reason: error.to_string(),
+                        }),
+                    }
+                }
+            }
+        }
+
+        for event in &events {
+            self.publish(event.clone());
+        }
+        Ok(PollOutcome { events, errors })
+    }
+
+    fn publish(&self, event: SettlementEvent) {
+        // Sending only fails without subscribers
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::confirm::{ConfirmationConfig, ConfirmationTracker, SettlementEvent};
+    use crate::envelope::BlockMeta;
+    use crate::mock::MockWvmSink;
+    use crate::WvmDataSettler;
+
+    #[tokio::test]
+    pub async fn test_confirmation_tracking() {
+        let mut sink = MockWvmSink::new();
+        let config = ConfirmationConfig {
+            confirmations: 3,
+            unknown_polls: 2,
+            ..Default::default()
+        };
+        let mut tracker = ConfirmationTracker::new(sink.clone(), config);
+        let mut events = tracker.subscribe();
+
+        let block = |block_number: u64| BlockMeta {
+            chain_id: 9496,
+            block_number,
+            block_hash: [block_number as u8; 32],
+        };
+
+        let first = sink.send_wvm_calldata(vec![1]).await.unwrap();
+        tracker.track(block(1), first.clone(), vec![1]);
+        let second = sink.send_wvm_calldata(vec![2]).await.unwrap();
+        tracker.track(block(2), second.clone(), vec![2]);
+        sink.drop_tx(&second);
+
+        assert!(tracker.poll(&mut sink).await.unwrap().events.is_empty());
+
+        sink.mine_blocks(1);
+        let polled = tracker.poll(&mut sink).await.unwrap().events;
+        assert_eq!(polled.len(), 2);
+        assert!(polled.contains(&SettlementEvent::Included {
+            block: block(1),
+            tx_hash: first.clone(),
+            included_in: 1,
+        }));
+        // Unknown for two polls, resubmitted with a new hash
+        let resubmitted = polled
+            .iter()
+            .find(|event| matches!(event, SettlementEvent::Submitted { .. }))
+            .unwrap();
+        assert_eq!(resubmitted.block().block_number, 2);
+        assert_ne!(resubmitted.tx_hash(), second);
+
+        sink.mine_blocks(2);
+        let polled = tracker.poll(&mut sink).await.unwrap().events;
+        assert!(polled.contains(&SettlementEvent::Finalized {
+            block: block(1),
+            tx_hash: first,
+            confirmations: 3,
+        }));
+        assert_eq!(tracker.pending(), 1);
+
+        let mut received = vec![];
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(received.len(), 6);
+        assert!(matches!(received[0], SettlementEvent::Submitted { .. }));
+    }
+}
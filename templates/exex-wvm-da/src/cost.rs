@@ -0,0 +1,579 @@
+use crate::blob::{estimate_blob_gas, BlobConfig, FeeMarket, SettlementMode};
+use crate::codec::Codec;
+use crate::crypto::Encryption;
+use crate::provider::GasPriceProvider;
+use crate::{PendingSettlement, WvmDataSettler, WvmDataSettlerError};
+use async_trait::async_trait;
+use borsh::BorshDeserialize;
+use eyre::Error;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+pub(crate) const TX_BASE_GAS: u64 = 21_000;
+const ZERO_BYTE_GAS: u64 = 4;
+const NONZERO_BYTE_GAS: u64 = 16;
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Intrinsic gas of a settlement transaction carrying `data` as calldata.
+pub fn estimate_calldata_gas(data: &[u8]) -> u64 {
+    let zeros = data.iter().filter(|byte| **byte == 0).count() as u64;
+    let nonzeros = data.len() as u64 - zeros;
+    TX_BASE_GAS + zeros * ZERO_BYTE_GAS + nonzeros * NONZERO_BYTE_GAS
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CostEstimate {
+    pub gas: u64,
+    /// Gas price in wei
+    pub gas_price: u128,
+    /// Blob gas, 0 for calldata settlements
+    pub blob_gas: u64,
+    /// Blob base fee in wei
+    pub blob_gas_price: u128,
+}
+
+impl CostEstimate {
+    /// Settling `data` as calldata at `gas_price`
+    pub fn calldata(data: &[u8], gas_price: u128) -> Self {
+        Self {
+            gas: estimate_calldata_gas(data),
+            gas_price,
+            ..Default::default()
+        }
+    }
+
+    pub fn wei(&self) -> u128 {
+        self.gas as u128 * self.gas_price + self.blob_gas as u128 * self.blob_gas_price
+    }
+}
+
+/// Estimated spend of settled payloads over a sliding window, kept for a day unless
+/// created `with_retention`.
+#[derive(Debug)]
+pub struct SpendTracker {
+    spent: VecDeque<(Instant, u128)>,
+    total: u128,
+    retention: Duration,
+}
+
+impl Default for SpendTracker {
+    fn default() -> Self {
+        Self::with_retention(DAY)
+    }
+}
+
+impl SpendTracker {
+    pub fn with_retention(retention: Duration) -> Self {
+        Self {
+            spent: VecDeque::new(),
+            total: 0,
+            retention,
+        }
+    }
+
+    pub fn record(&mut self, wei: u128) {
+        self.prune();
+        self.spent.push_back((Instant::now(), wei));
+        self.total += wei;
+    }
+
+    /// Spend over the last `window`, at most the retention
+    pub fn spent_within(&self, window: Duration) -> u128 {
+        let now = Instant::now();
+        self.spent
+            .iter()
+            .rev()
+            .take_while(|(at, _)| now.duration_since(*at) < window)
+            .map(|(_, wei)| wei)
+            .sum()
+    }
+
+    pub fn last_hour(&self) -> u128 {
+        self.spent_within(HOUR)
+    }
+
+    pub fn last_day(&self) -> u128 {
+        self.spent_within(DAY)
+    }
+
+    /// Spend since the tracker was created
+    pub fn total(&self) -> u128 {
+        self.total
+    }
+
+    /// When the spend within `window` drops to `allowance` or below, as older spends expire
+    fn fits_at(&self, window: Duration, allowance: u128) -> Instant {
+        let now = Instant::now();
+        let mut spent = self.spent_within(window);
+        for (at, wei) in &self.spent {
+            if spent <= allowance {
+                break;
+            }
+            if now.duration_since(*at) < window {
+                spent -= wei;
+                if spent <= allowance {
+                    return *at + window;
+                }
+            }
+        }
+        now
+    }
+
+    fn prune(&mut self) {
+        let now = Instant::now();
+        while self
+            .spent
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= self.retention)
+        {
+            self.spent.pop_front();
+        }
+    }
+}
+
+/// What `BudgetedWvmDataSettler` does while a budget is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetAction {
+    /// Holds back settlements until the window has room for them again
+    Pause,
+    /// Raises `BudgetPressure`, which makes a `BatchingWvmDataSettler` batch more blocks.
+    /// The batching settler is driven through `push` and `flush`, it is not a
+    /// `WvmDataSettler`, so this does nothing for settlers that don't batch, such as the
+    /// one of `WvmDaExEx`.
+    IncreaseBatching,
+    /// Compresses blocks with the codec passed to `with_compression` while a settlement the
+    /// size of the previous one would exceed the budget
+    IncreaseCompression,
+    /// Publishes a `BudgetAlert` and keeps settling
+    Alert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+    /// Maximum estimated spend within `window`, in wei
+    pub limit: u128,
+    pub window: Duration,
+    pub action: BudgetAction,
+}
+
+impl Budget {
+    pub fn hourly(limit: u128, action: BudgetAction) -> Self {
+        Self {
+            limit,
+            window: HOUR,
+            action,
+        }
+    }
+
+    pub fn daily(limit: u128, action: BudgetAction) -> Self {
+        Self {
+            limit,
+            window: DAY,
+            action,
+        }
+    }
+}
+
+/// Published for every settlement that exceeds an `Alert` budget.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetAlert {
+    pub budget: Budget,
+    /// Estimated spend within the budget window, excluding this settlement
+    pub spent: u128,
+    pub estimate: CostEstimate,
+}
+
+/// Shared flag raised while an `IncreaseBatching` budget is exceeded.
+#[derive(Debug, Clone, Default)]
+pub struct BudgetPressure(Arc<AtomicBool>);
+
+impl BudgetPressure {
+    pub fn is_raised(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, raised: bool) {
+        self.0.store(raised, Ordering::Relaxed);
+    }
+}
+
+/// Estimates the cost of every payload before sending it and enforces spend budgets.
+///
+/// Costs are estimated from the intrinsic calldata gas and the current gas price, so they
+/// ignore execution gas on the target and any gas bumps. In front of a
+/// `BlobWvmDataSettler`, pass its `BlobConfig` to `with_blobs` so blob settlements are
+/// priced at the blob base fee.
+pub struct BudgetedWvmDataSettler<S, P> {
+    settler: S,
+    prices: P,
+    budgets: Vec<Budget>,
+    spend: SpendTracker,
+    compression: Option<Box<dyn Codec>>,
+    blobs: Option<BlobConfig>,
+    /// Estimate of the previous settlement, standing in for the next one until it is known
+    last_estimate: u128,
+    /// Estimates of the settlements queued in the wrapped settler, spent once they settle
+    queued: HashMap<u64, CostEstimate>,
+    pressure: BudgetPressure,
+    alerts: broadcast::Sender<BudgetAlert>,
+}
+
+impl<S, P> BudgetedWvmDataSettler<S, P>
+where
+    S: WvmDataSettler + Send + Sync,
+    P: GasPriceProvider + Send + Sync,
+{
+    pub fn new(settler: S, prices: P, budgets: Vec<Budget>) -> Self {
+        let (alerts, _) = broadcast::channel(64);
+        let retention = budgets
+            .iter()
+            .map(|budget| budget.window)
+            .fold(DAY, Duration::max);
+        Self {
+            settler,
+            prices,
+            budgets,
+            spend: SpendTracker::with_retention(retention),
+            compression: None,
+            blobs: None,
+            last_estimate: 0,
+            queued: HashMap::new(),
+            pressure: BudgetPressure::default(),
+            alerts,
+        }
+    }
+
+    /// Codec used instead of the settler's while an `IncreaseCompression` budget is exceeded.
+    /// Enveloped payloads record their codec, raw ones are decoded with either codec.
+    pub fn with_compression(mut self, codec: impl Codec + 'static) -> Self {
+        self.compression = Some(Box::new(codec));
+        self
+    }
+
+    /// Prices payloads the way a `BlobWvmDataSettler` with `config` settles them
+    pub fn with_blobs(mut self, config: BlobConfig) -> Self {
+        self.blobs = Some(config);
+        self
+    }
+
+    pub fn settler(&self) -> &S {
+        &self.settler
+    }
+
+    pub fn spend(&self) -> &SpendTracker {
+        &self.spend
+    }
+
+    /// Pass to `BatchingWvmDataSettler::with_budget_pressure`
+    pub fn pressure(&self) -> BudgetPressure {
+        self.pressure.clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<BudgetAlert> {
+        self.alerts.subscribe()
+    }
+
+    pub async fn estimate(&self, data: &[u8]) -> Result<CostEstimate, WvmDataSettlerError> {
+        let Some(config) = &self.blobs else {
+            let gas_price = self
+                .prices
+                .gas_price()
+                .await
+                .map_err(|e| WvmDataSettlerError::Gas(e.into()))?;
+            return Ok(CostEstimate::calldata(data, gas_price));
+        };
+
+        let fees = FeeMarket::fetch(&self.prices).await?;
+        Ok(match (config.mode_for(data, &fees), fees.blob_base_fee) {
+            (SettlementMode::Blob, Some(blob_base_fee)) => CostEstimate {
+                gas: TX_BASE_GAS,
+                gas_price: fees.gas_price,
+                blob_gas: estimate_blob_gas(data.len()),
+                blob_gas_price: blob_base_fee,
+            },
+            _ => CostEstimate::calldata(data, fees.gas_price),
+        })
+    }
+
+    /// Whether an `IncreaseCompression` budget would be exceeded by a settlement the size
+    /// of the previous one, evaluated when the next block is compressed
+    fn compress_harder(&self) -> bool {
+        self.budgets.iter().any(|budget| {
+            budget.action == BudgetAction::IncreaseCompression
+                && self.spend.spent_within(budget.window) + self.last_estimate > budget.limit
+        })
+    }
+
+    async fn enforce(&mut self, estimate: CostEstimate) -> Result<(), WvmDataSettlerError> {
+        // Evaluated again after every pause, the spend of the other windows moved meanwhile
+        let (raise_pressure, alerts) = 'evaluate: loop {
+            let mut raise_pressure = false;
+            let mut alerts = vec![];
+
+            for budget in &self.budgets {
+                let spent = self.spend.spent_within(budget.window);
+                if spent + estimate.wei() <= budget.limit {
+                    continue;
+                }
+
+                match budget.action {
+                    BudgetAction::Pause => {
+                        let allowance = budget.limit.checked_sub(estimate.wei()).ok_or(
+                            WvmDataSettlerError::BudgetExceeded {
+                                estimate: estimate.wei(),
+                                limit: budget.limit,
+                            },
+                        )?;
+                        tokio::time::sleep_until(self.spend.fits_at(budget.window, allowance))
+                            .await;
+                        continue 'evaluate;
+                    }
+                    BudgetAction::IncreaseBatching => raise_pressure = true,
+                    BudgetAction::IncreaseCompression => {}
+                    BudgetAction::Alert => alerts.push(BudgetAlert {
+                        budget: *budget,
+                        spent,
+                        estimate,
+                    }),
+                }
+            }
+            break (raise_pressure, alerts);
+        };
+
+        for alert in alerts {
+            // Sending only fails without subscribers
+            let _ = self.alerts.send(alert);
+        }
+        self.pressure.set(raise_pressure);
+        Ok(())
+    }
+
+    fn record_spend(&mut self, estimate: CostEstimate) {
+        self.spend.record(estimate.wei());
+        self.last_estimate = estimate.wei();
+    }
+}
+
+#[async_trait]
+impl<S, P> WvmDataSettler for BudgetedWvmDataSettler<S, P>
+where
+    S: WvmDataSettler + Send + Sync,
+    P: GasPriceProvider + Send + Sync,
+{
+    fn codec(&self) -> &dyn Codec {
+        match &self.compression {
+            Some(codec) if self.compress_harder() => codec.as_ref(),
+            _ => self.settler.codec(),
+        }
+    }
+
+    fn decode_block<T: BorshDeserialize>(&self, block_data: &[u8]) -> Result<T, Error> {
+        let base = self.settler.codec();
+        let borsh_data = match (base.decompress(block_data), &self.compression) {
+            (Ok(borsh_data), _) => borsh_data,
+            (Err(_), Some(codec)) => codec.decompress(block_data)?,
+            (Err(error), None) => return Err(error.into()),
+        };
+        Ok(borsh::from_slice(&borsh_data)?)
+    }
+
+    fn encryption(&self) -> Option<&Encryption> {
+        self.settler.encryption()
+    }
+
+    fn bump_gas(&mut self, percent: u64) {
+        self.settler.bump_gas(percent)
+    }
+
+    fn forget_tx(&mut self, tx_hash: &str) -> Result<(), WvmDataSettlerError> {
+        self.settler.forget_tx(tx_hash)
+    }
+
+    async fn send_wvm_calldata(
+        &mut self,
+        block_data: Vec<u8>,
+    ) -> Result<String, WvmDataSettlerError> {
+        let estimate = self.estimate(&block_data).await?;
+        self.enforce(estimate).await?;
+
+        let tx_hash = self.settler.send_wvm_calldata(block_data).await?;
+        self.record_spend(estimate);
+        Ok(tx_hash)
+    }
+
+    /// Budgets are enforced on submission, settlements still queued in the wrapped settler
+    /// count toward them once they are awaited.
+    async fn submit_wvm_calldata(&mut self, block_data: Vec<u8>) -> PendingSettlement {
+        let estimate = match self.estimate(&block_data).await {
+            Ok(estimate) => estimate,
+            Err(error) => return PendingSettlement::Done(Err(error)),
+        };
+        if let Err(error) = self.enforce(estimate).await {
+            return PendingSettlement::Done(Err(error));
+        }
+
+        match self.settler.submit_wvm_calldata(block_data).await {
+            PendingSettlement::Queued(sequence) => {
+                self.queued.insert(sequence, estimate);
+                PendingSettlement::Queued(sequence)
+            }
+            PendingSettlement::Done(result) => {
+                if result.is_ok() {
+                    self.record_spend(estimate);
+                }
+                PendingSettlement::Done(result)
+            }
+        }
+    }
+
+    async fn await_wvm_calldata(
+        &mut self,
+        pending: PendingSettlement,
+    ) -> Result<String, WvmDataSettlerError> {
+        let estimate = match &pending {
+            PendingSettlement::Queued(sequence) => self.queued.remove(sequence),
+            PendingSettlement::Done(_) => None,
+        };
+        let tx_hash = self.settler.await_wvm_calldata(pending).await?;
+        if let Some(estimate) = estimate {
+            self.record_spend(estimate);
+        }
+        Ok(tx_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::{CodecId, ZstdCodec};
+    use crate::cost::{
+        estimate_calldata_gas, Budget, BudgetAction, BudgetedWvmDataSettler, CostEstimate,
+    };
+    use crate::mock::{MockWvmSink, MOCK_GAS_PRICE};
+    use crate::{WvmDataSettler, WvmDataSettlerError};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[test]
+    pub fn test_calldata_gas_estimate() {
+        assert_eq!(
+            estimate_calldata_gas(&[0, 1, 0, 2]),
+            21_000 + 4 + 16 + 4 + 16
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_budget_alert() {
+        let sink = MockWvmSink::new();
+        let payload = vec![1u8; 100];
+        let cost = CostEstimate::calldata(&payload, MOCK_GAS_PRICE).wei();
+
+        let budgets = vec![Budget::hourly(cost * 2, BudgetAction::Alert)];
+        let mut settler = BudgetedWvmDataSettler::new(sink.clone(), sink.clone(), budgets);
+        let mut alerts = settler.subscribe();
+
+        for _ in 0..2 {
+            settler.send_wvm_calldata(payload.clone()).await.unwrap();
+        }
+        assert!(alerts.try_recv().is_err());
+
+        // The third settlement is still sent, past the budget
+        settler.send_wvm_calldata(payload).await.unwrap();
+        assert_eq!(alerts.try_recv().unwrap().spent, cost * 2);
+        assert_eq!(settler.spend().last_hour(), cost * 3);
+        assert_eq!(sink.submissions().len(), 3);
+    }
+
+    #[tokio::test]
+    pub async fn test_budget_compression() {
+        let sink = MockWvmSink::new();
+        let payload = vec![1u8; 100];
+        let cost = CostEstimate::calldata(&payload, MOCK_GAS_PRICE).wei();
+
+        let budgets = vec![Budget::daily(cost * 3, BudgetAction::IncreaseCompression)];
+        let mut settler = BudgetedWvmDataSettler::new(sink.clone(), sink, budgets)
+            .with_compression(ZstdCodec::new(19));
+
+        for _ in 0..2 {
+            settler.send_wvm_calldata(payload.clone()).await.unwrap();
+        }
+        assert_eq!(settler.codec().id(), CodecId::Brotli);
+
+        // After the third settlement, another one would exceed the daily budget
+        settler.send_wvm_calldata(payload).await.unwrap();
+        assert_eq!(settler.codec().id(), CodecId::Zstd);
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn test_budget_pause() {
+        let sink = MockWvmSink::new();
+        let payload = vec![1u8; 100];
+        let cost = CostEstimate::calldata(&payload, MOCK_GAS_PRICE).wei();
+
+        let budgets = vec![Budget {
+            limit: cost * 4,
+            window: Duration::from_millis(200),
+            action: BudgetAction::Pause,
+        }];
+        let mut settler = BudgetedWvmDataSettler::new(sink.clone(), sink.clone(), budgets);
+
+        for _ in 0..4 {
+            settler.send_wvm_calldata(payload.clone()).await.unwrap();
+        }
+
+        // The fifth settlement waits for the 200ms window to free up
+        let started = Instant::now();
+        settler.send_wvm_calldata(payload).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(sink.submissions().len(), 5);
+        assert_eq!(settler.spend().total(), cost * 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn test_budget_long_window() {
+        let sink = MockWvmSink::new();
+        let payload = vec![1u8; 100];
+        let cost = CostEstimate::calldata(&payload, MOCK_GAS_PRICE).wei();
+
+        // Spend is kept for windows longer than a day
+        let two_days = Duration::from_secs(2 * 24 * 60 * 60);
+        let mut settler = BudgetedWvmDataSettler::new(
+            sink.clone(),
+            sink,
+            vec![Budget {
+                limit: cost * 10,
+                window: two_days,
+                action: BudgetAction::Alert,
+            }],
+        );
+        settler.send_wvm_calldata(payload.clone()).await.unwrap();
+        tokio::time::advance(Duration::from_secs(25 * 60 * 60)).await;
+        settler.send_wvm_calldata(payload).await.unwrap();
+        assert_eq!(settler.spend().spent_within(two_days), cost * 2);
+        assert_eq!(settler.spend().last_day(), cost);
+    }
+
+    #[tokio::test]
+    pub async fn test_budget_strict_limit() {
+        let sink = MockWvmSink::new();
+        let payload = vec![1u8; 100];
+        let cost = CostEstimate::calldata(&payload, MOCK_GAS_PRICE).wei();
+
+        // A settlement that alone exceeds a pausing budget can never fit
+        let mut settler = BudgetedWvmDataSettler::new(
+            sink.clone(),
+            sink.clone(),
+            vec![Budget::hourly(cost - 1, BudgetAction::Pause)],
+        );
+        assert!(matches!(
+            settler.send_wvm_calldata(payload).await,
+            Err(WvmDataSettlerError::BudgetExceeded { .. })
+        ));
+        assert!(sink.submissions().is_empty());
+    }
+}
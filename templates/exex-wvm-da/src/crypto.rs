@@ -0,0 +1,343 @@
+use crate::envelope::{BlockMeta, EnvelopeError};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::Path;
+use thiserror::Error;
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Error)]
+pub enum CryptoError {
+    #[error("Payload is encrypted but the settler has no encryption configured")]
+    NotConfigured,
+
+    #[error("Unknown encryption key id: {0}")]
+    UnknownKey(u32),
+
+    #[error("Invalid encryption key: {0}")]
+    InvalidKey(String),
+
+    #[error("Cipher {0:?} does not encrypt payloads")]
+    InvalidCipher(CipherId),
+
+    #[error("Failed to encrypt payload")]
+    Encrypt,
+
+    #[error("Failed to decrypt payload, wrong key or tampered data")]
+    Decrypt,
+
+    #[error("Failed to read encryption keys: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Identifies the cipher of an encrypted payload in the envelope header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CipherId {
+    None = 0,
+    Aes256Gcm = 1,
+    ChaCha20Poly1305 = 2,
+}
+
+impl TryFrom<u8> for CipherId {
+    type Error = EnvelopeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CipherId::None),
+            1 => Ok(CipherId::Aes256Gcm),
+            2 => Ok(CipherId::ChaCha20Poly1305),
+            other => Err(EnvelopeError::UnknownCipher(other)),
+        }
+    }
+}
+
+/// Source of the 256-bit keys used to encrypt payloads, looked up by key id.
+pub trait KeyProvider: Send + Sync {
+    /// Id and key used for new payloads
+    fn current_key(&self) -> Result<(u32, [u8; 32]), CryptoError>;
+
+    fn key(&self, key_id: u32) -> Result<[u8; 32], CryptoError>;
+}
+
+pub struct StaticKeyProvider {
+    key_id: u32,
+    key: [u8; 32],
+}
+
+impl StaticKeyProvider {
+    pub fn new(key_id: u32, key: [u8; 32]) -> Self {
+        Self { key_id, key }
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn current_key(&self) -> Result<(u32, [u8; 32]), CryptoError> {
+        Ok((self.key_id, self.key))
+    }
+
+    fn key(&self, key_id: u32) -> Result<[u8; 32], CryptoError> {
+        if key_id != self.key_id {
+            return Err(CryptoError::UnknownKey(key_id));
+        }
+        Ok(self.key)
+    }
+}
+
+/// File of `key_id hex_key` lines. The last key is the current one, earlier keys remain
+/// available to decrypt older payloads.
+pub struct KeyFileProvider {
+    keys: BTreeMap<u32, [u8; 32]>,
+    current: u32,
+}
+
+impl KeyFileProvider {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CryptoError> {
+        let mut keys = BTreeMap::new();
+        let mut current = None;
+
+        for line in read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key_id, key) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| CryptoError::InvalidKey(format!("malformed line: {}", line)))?;
+            let key_id: u32 = key_id
+                .parse()
+                .map_err(|_| CryptoError::InvalidKey(format!("invalid key id: {}", key_id)))?;
+            keys.insert(key_id, parse_key(key.trim())?);
+            current = Some(key_id);
+        }
+
+        let current = current.ok_or_else(|| CryptoError::InvalidKey("empty key file".into()))?;
+        Ok(Self { keys, current })
+    }
+}
+
+impl KeyProvider for KeyFileProvider {
+    fn current_key(&self) -> Result<(u32, [u8; 32]), CryptoError> {
+        Ok((self.current, self.key(self.current)?))
+    }
+
+    fn key(&self, key_id: u32) -> Result<[u8; 32], CryptoError> {
+        self.keys
+            .get(&key_id)
+            .copied()
+            .ok_or(CryptoError::UnknownKey(key_id))
+    }
+}
+
+/// Reads the current key id from `<prefix>_KEY_ID` and each key from `<prefix>_KEY_<id>`,
+/// hex encoded. Variables are read on every lookup, so keys can be rotated in place.
+pub struct EnvKeyProvider {
+    prefix: String,
+}
+
+impl EnvKeyProvider {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl Default for EnvKeyProvider {
+    fn default() -> Self {
+        Self::new("WVM_DA_ENCRYPTION")
+    }
+}
+
+impl KeyProvider for EnvKeyProvider {
+    fn current_key(&self) -> Result<(u32, [u8; 32]), CryptoError> {
+        let var = format!("{}_KEY_ID", self.prefix);
+        let key_id = std::env::var(&var)
+            .map_err(|_| CryptoError::InvalidKey(format!("{} is not set", var)))?
+            .parse()
+            .map_err(|_| CryptoError::InvalidKey(format!("{} is not a key id", var)))?;
+        Ok((key_id, self.key(key_id)?))
+    }
+
+    fn key(&self, key_id: u32) -> Result<[u8; 32], CryptoError> {
+        let key = std::env::var(format!("{}_KEY_{}", self.prefix, key_id))
+            .map_err(|_| CryptoError::UnknownKey(key_id))?;
+        parse_key(&key)
+    }
+}
+
+fn parse_key(key: &str) -> Result<[u8; 32], CryptoError> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(key.trim_start_matches("0x"), &mut bytes)
+        .map_err(|e| CryptoError::InvalidKey(e.to_string()))?;
+    Ok(bytes)
+}
+
+/// Binds a ciphertext to the block it was produced for.
+fn associated_data(meta: &BlockMeta) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(48);
+    aad.extend_from_slice(&meta.chain_id.to_be_bytes());
+    aad.extend_from_slice(&meta.block_number.to_be_bytes());
+    aad.extend_from_slice(&meta.block_hash);
+    aad
+}
+
+/// Encryption stage applied after compression. The ciphertext is the random nonce
+/// followed by the AEAD output, so sealing the same payload twice gives different
+/// ciphertexts; `DedupWvmDataSettler` identifies encrypted envelopes by their plaintext.
+pub struct Encryption {
+    cipher: CipherId,
+    keys: Box<dyn KeyProvider>,
+}
+
+impl Encryption {
+    /// Fails for `CipherId::None`, leave encryption unconfigured for plaintext payloads
+    pub fn new(cipher: CipherId, keys: impl KeyProvider + 'static) -> Result<Self, CryptoError> {
+        if cipher == CipherId::None {
+            return Err(CryptoError::InvalidCipher(cipher));
+        }
+        Ok(Self {
+            cipher,
+            keys: Box::new(keys),
+        })
+    }
+
+    pub fn cipher(&self) -> CipherId {
+        self.cipher
+    }
+
+    ///
+    /// Encrypts `payload` with the current key, returning the key id and ciphertext
+    ///
+    /// # Arguments
+    ///
+    /// * `meta` - block the payload belongs to, authenticated along with it
+    /// * `payload` - compressed block payload
+    pub fn seal(&self, meta: &BlockMeta, payload: &[u8]) -> Result<(u32, Vec<u8>), CryptoError> {
+        let (key_id, key) = self.keys.current_key()?;
+        let aad = associated_data(meta);
+        let payload = Payload {
+            msg: payload,
+            aad: &aad,
+        };
+
+        let (nonce, ciphertext) = match self.cipher {
+            CipherId::None => return Err(CryptoError::InvalidCipher(self.cipher)),
+            CipherId::Aes256Gcm => {
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                let cipher = Aes256Gcm::new(&key.into());
+                (nonce, cipher.encrypt(&nonce, payload))
+            }
+            CipherId::ChaCha20Poly1305 => {
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let cipher = ChaCha20Poly1305::new(&key.into());
+                (nonce, cipher.encrypt(&nonce, payload))
+            }
+        };
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext.map_err(|_| CryptoError::Encrypt)?);
+        Ok((key_id, sealed))
+    }
+
+    ///
+    /// Decrypts a payload produced by `seal`
+    ///
+    /// # Arguments
+    ///
+    /// * `meta` - block recorded in the envelope header
+    /// * `cipher` - cipher recorded in the envelope header, which may differ from the
+    ///   configured one for older payloads
+    /// * `key_id` - key id recorded in the envelope header
+    /// * `sealed` - envelope payload
+    pub fn open(
+        &self,
+        meta: &BlockMeta,
+        cipher: CipherId,
+        key_id: u32,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        if cipher == CipherId::None {
+            return Ok(sealed.to_vec());
+        }
+        if sealed.len() < NONCE_LEN {
+            return Err(CryptoError::Decrypt);
+        }
+
+        let key = self.keys.key(key_id)?;
+        let aad = associated_data(meta);
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+
+        let plaintext = match cipher {
+            CipherId::Aes256Gcm => Aes256Gcm::new(&key.into()).decrypt(nonce.into(), payload),
+            _ => ChaCha20Poly1305::new(&key.into()).decrypt(nonce.into(), payload),
+        };
+        plaintext.map_err(|_| CryptoError::Decrypt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::{CipherId, CryptoError, Encryption, KeyFileProvider, StaticKeyProvider};
+    use crate::envelope::BlockMeta;
+
+    #[test]
+    pub fn test_encryption_round_trip() {
+        let meta = BlockMeta {
+            chain_id: 9496,
+            block_number: 7,
+            block_hash: [7; 32],
+        };
+
+        for cipher in [CipherId::Aes256Gcm, CipherId::ChaCha20Poly1305] {
+            let encryption = Encryption::new(cipher, StaticKeyProvider::new(3, [9; 32])).unwrap();
+            let (key_id, sealed) = encryption.seal(&meta, b"block payload").unwrap();
+            assert_eq!(key_id, 3);
+            assert_ne!(&sealed[12..], b"block payload");
+
+            let opened = encryption.open(&meta, cipher, key_id, &sealed).unwrap();
+            assert_eq!(opened, b"block payload");
+
+            let other_block = BlockMeta {
+                block_number: 8,
+                ..meta
+            };
+            assert!(matches!(
+                encryption.open(&other_block, cipher, key_id, &sealed),
+                Err(CryptoError::Decrypt)
+            ));
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        std::fs::write(
+            &path,
+            format!(
+                "# rotated\n1 {}\n2 {}\n",
+                hex::encode([1; 32]),
+                hex::encode([2; 32])
+            ),
+        )
+        .unwrap();
+        let encryption =
+            Encryption::new(CipherId::Aes256Gcm, KeyFileProvider::open(&path).unwrap()).unwrap();
+        let (key_id, sealed) = encryption.seal(&meta, b"rotated").unwrap();
+        assert_eq!(key_id, 2);
+        assert!(matches!(
+            encryption.open(&meta, CipherId::Aes256Gcm, 4, &sealed),
+            Err(CryptoError::UnknownKey(4))
+        ));
+
+        assert!(matches!(
+            Encryption::new(CipherId::None, StaticKeyProvider::new(1, [1; 32])),
+            Err(CryptoError::InvalidCipher(CipherId::None))
+        ));
+    }
+}
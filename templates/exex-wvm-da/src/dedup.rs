@@ -0,0 +1,422 @@
+use crate::codec::Codec;
+use crate::crypto::{CipherId, Encryption};
+use crate::envelope::{payload_checksum, Envelope};
+use crate::revert::RevertRecord;
+use crate::{PendingSettlement, WvmDataSettler, WvmDataSettlerError};
+use async_trait::async_trait;
+use eyre::Error;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Maps the content hash of every settled payload to its settlement transaction.
+pub trait ContentIndex: Send {
+    fn lookup(&self, content_hash: &[u8; 32]) -> Option<String>;
+
+    fn insert(&mut self, content_hash: [u8; 32], tx_hash: &str) -> std::io::Result<()>;
+
+    /// Forgets every payload settled by `tx_hash`
+    fn remove_tx(&mut self, tx_hash: &str) -> std::io::Result<()>;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryContentIndex {
+    settled: HashMap<[u8; 32], String>,
+}
+
+impl ContentIndex for MemoryContentIndex {
+    fn lookup(&self, content_hash: &[u8; 32]) -> Option<String> {
+        self.settled.get(content_hash).cloned()
+    }
+
+    fn insert(&mut self, content_hash: [u8; 32], tx_hash: &str) -> std::io::Result<()> {
+        self.settled.insert(content_hash, tx_hash.to_string());
+        Ok(())
+    }
+
+    fn remove_tx(&mut self, tx_hash: &str) -> std::io::Result<()> {
+        self.settled.retain(|_, settled_tx| settled_tx != tx_hash);
+        Ok(())
+    }
+}
+
+/// Append-only file of `content_hash tx_hash` lines, a `-` tx hash removes the entry.
+pub struct FileContentIndex {
+    path: PathBuf,
+    settled: HashMap<[u8; 32], String>,
+}
+
+impl FileContentIndex {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut settled = HashMap::new();
+
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                let Some((content_hash, tx_hash)) = line.split_once(' ') else {
+                    continue;
+                };
+                let mut hash = [0u8; 32];
+                hex::decode_to_slice(content_hash, &mut hash).map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+                })?;
+                match tx_hash {
+                    "-" => settled.remove(&hash),
+                    tx_hash => settled.insert(hash, tx_hash.to_string()),
+                };
+            }
+        }
+
+        Ok(Self { path, settled })
+    }
+
+    fn append(&self, content_hash: &[u8; 32], tx_hash: &str) -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{} {}", hex::encode(content_hash), tx_hash)?;
+        file.sync_data()
+    }
+}
+
+impl ContentIndex for FileContentIndex {
+    fn lookup(&self, content_hash: &[u8; 32]) -> Option<String> {
+        self.settled.get(content_hash).cloned()
+    }
+
+    fn insert(&mut self, content_hash: [u8; 32], tx_hash: &str) -> std::io::Result<()> {
+        self.append(&content_hash, tx_hash)?;
+        self.settled.insert(content_hash, tx_hash.to_string());
+        Ok(())
+    }
+
+    fn remove_tx(&mut self, tx_hash: &str) -> std::io::Result<()> {
+        let removed: Vec<[u8; 32]> = self
+            .settled
+            .iter()
+            .filter(|(_, settled_tx)| *settled_tx == tx_hash)
+            .map(|(content_hash, _)| *content_hash)
+            .collect();
+        for content_hash in removed {
+            self.append(&content_hash, "-")?;
+            self.settled.remove(&content_hash);
+        }
+        Ok(())
+    }
+}
+
+/// Finds a payload that was already settled on WeaveVM, e.g. through an indexer.
+/// The content hash of encrypted envelopes is the one of their plaintext envelope, which
+/// an indexer can't derive from the calldata.
+#[async_trait]
+pub trait OnChainLookup {
+    async fn find(&self, content_hash: &[u8; 32]) -> Result<Option<String>, Error>;
+}
+
+/// Skips payloads that were already settled and returns their existing tx hash instead.
+///
+/// Payloads are identified by the sha256 of the calldata. Encrypted envelopes get a fresh
+/// nonce on every seal, they are identified by the sha256 of the same envelope in
+/// plaintext, decrypted with the encryption of the wrapped settler. When a `RevertRecord`
+/// is sent, the payloads of the blocks it reverts are forgotten, so a block that becomes
+/// canonical again after a reorg is posted again after its revert.
+pub struct DedupWvmDataSettler<S, I = MemoryContentIndex> {
+    settler: S,
+    index: I,
+    lookup: Option<Box<dyn OnChainLookup + Send + Sync>>,
+    hits: u64,
+    /// Content hash and revert record of the payloads queued in the wrapped settler
+    queued: HashMap<u64, ([u8; 32], Option<RevertRecord>)>,
+}
+
+impl<S, I> DedupWvmDataSettler<S, I>
+where
+    S: WvmDataSettler + Send,
+    I: ContentIndex,
+{
+    pub fn new(settler: S, index: I) -> Self {
+        Self {
+            settler,
+            index,
+            lookup: None,
+            hits: 0,
+            queued: HashMap::new(),
+        }
+    }
+
+    /// Consulted for payloads missing from the local index
+    pub fn with_lookup(mut self, lookup: impl OnChainLookup + Send + Sync + 'static) -> Self {
+        self.lookup = Some(Box::new(lookup));
+        self
+    }
+
+    pub fn settler(&self) -> &S {
+        &self.settler
+    }
+
+    pub fn index(&self) -> &I {
+        &self.index
+    }
+
+    /// Number of payloads that were not posted because they were already settled
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// sha256 of the calldata, or of the plaintext envelope for encrypted envelopes
+    fn content_hash(&self, block_data: &[u8]) -> [u8; 32] {
+        let plaintext = Envelope::decode(block_data)
+            .ok()
+            .filter(|envelope| envelope.header.cipher != CipherId::None)
+            .and_then(|envelope| {
+                let header = &envelope.header;
+                let payload = self
+                    .settler
+                    .encryption()?
+                    .open(
+                        &header.meta(),
+                        header.cipher,
+                        header.key_id,
+                        &envelope.payload,
+                    )
+                    .ok()?;
+                Envelope::seal(header.meta(), header.codec, header.serialization, payload)
+                    .encode()
+                    .ok()
+            });
+        payload_checksum(plaintext.as_deref().unwrap_or(block_data))
+    }
+
+    async fn find_settled(&self, content_hash: &[u8; 32]) -> Option<String> {
+        if let Some(tx_hash) = self.index.lookup(content_hash) {
+            return Some(tx_hash);
+        }
+        // The lookup only saves a transaction, posting again is always safe
+        self.lookup
+            .as_ref()?
+            .find(content_hash)
+            .await
+            .ok()
+            .flatten()
+    }
+
+    fn record_hit(
+        &mut self,
+        content_hash: [u8; 32],
+        tx_hash: String,
+    ) -> Result<String, WvmDataSettlerError> {
+        self.hits += 1;
+        if self.index.lookup(&content_hash).is_none() {
+            self.index
+                .insert(content_hash, &tx_hash)
+                .map_err(WvmDataSettlerError::Store)?;
+        }
+        Ok(tx_hash)
+    }
+
+    fn record_sent(
+        &mut self,
+        content_hash: [u8; 32],
+        revert: Option<RevertRecord>,
+        result: Result<String, WvmDataSettlerError>,
+    ) -> Result<String, WvmDataSettlerError> {
+        let tx_hash = result?;
+        if let Some(revert) = revert {
+            for reverted in &revert.reverted {
+                self.index
+                    .remove_tx(&reverted.tx_hash)
+                    .map_err(WvmDataSettlerError::Store)?;
+            }
+        }
+        self.index
+            .insert(content_hash, &tx_hash)
+            .map_err(WvmDataSettlerError::Store)?;
+        Ok(tx_hash)
+    }
+}
+
+#[async_trait]
+impl<S, I> WvmDataSettler for DedupWvmDataSettler<S, I>
+where
+    S: WvmDataSettler + Send + Sync,
+    I: ContentIndex + Sync,
+{
+    fn codec(&self) -> &dyn Codec {
+        self.settler.codec()
+    }
+
+    fn encryption(&self) -> Option<&Encryption> {
+        self.settler.encryption()
+    }
+
+    fn bump_gas(&mut self, percent: u64) {
+        self.settler.bump_gas(percent)
+    }
+
+    fn forget_tx(&mut self, tx_hash: &str) -> Result<(), WvmDataSettlerError> {
+        self.index
+            .remove_tx(tx_hash)
+            .map_err(WvmDataSettlerError::Store)?;
+        self.settler.forget_tx(tx_hash)
+    }
+
+    async fn send_wvm_calldata(
+        &mut self,
+        block_data: Vec<u8>,
+    ) -> Result<String, WvmDataSettlerError> {
+        let content_hash = self.content_hash(&block_data);
+
+        if let Some(tx_hash) = self.find_settled(&content_hash).await {
+            return self.record_hit(content_hash, tx_hash);
+        }
+
+        let revert = RevertRecord::decode(&block_data).ok();
+
+        let result = self.settler.send_wvm_calldata(block_data).await;
+        self.record_sent(content_hash, revert, result)
+    }
+
+    /// A payload queued twice before either settled is posted twice
+    async fn submit_wvm_calldata(&mut self, block_data: Vec<u8>) -> PendingSettlement {
+        let content_hash = self.content_hash(&block_data);
+
+        if let Some(tx_hash) = self.find_settled(&content_hash).await {
+            return PendingSettlement::Done(self.record_hit(content_hash, tx_hash));
+        }
+
+        let revert = RevertRecord::decode(&block_data).ok();
+
+        match self.settler.submit_wvm_calldata(block_data).await {
+            PendingSettlement::Queued(sequence) => {
+                self.queued.insert(sequence, (content_hash, revert));
+                PendingSettlement::Queued(sequence)
+            }
+            PendingSettlement::Done(result) => {
+                PendingSettlement::Done(self.record_sent(content_hash, revert, result))
+            }
+        }
+    }
+
+    async fn await_wvm_calldata(
+        &mut self,
+        pending: PendingSettlement,
+    ) -> Result<String, WvmDataSettlerError> {
+        let queued = match &pending {
+            PendingSettlement::Queued(sequence) => self.queued.remove(sequence),
+            PendingSettlement::Done(_) => None,
+        };
+        let result = self.settler.await_wvm_calldata(pending).await;
+        match queued {
+            Some((content_hash, revert)) => self.record_sent(content_hash, revert, result),
+            None => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::{CipherId, Encryption, StaticKeyProvider};
+    use crate::dedup::{ContentIndex, DedupWvmDataSettler, FileContentIndex, MemoryContentIndex};
+    use crate::envelope::{payload_checksum, BlockMeta};
+    use crate::mock::MockWvmSink;
+    use crate::revert::{RevertRecord, RevertedBlock};
+    use crate::{WvmDataSettler, WvmDataSettlerError};
+    use async_trait::async_trait;
+
+    #[tokio::test]
+    pub async fn test_dedup_and_revert() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("content-index");
+
+        let sink = MockWvmSink::new();
+        let index = FileContentIndex::open(&path).unwrap();
+        let mut settler = DedupWvmDataSettler::new(sink.clone(), index);
+
+        let block = vec![0xabu8; 64];
+        let tx_hash = settler.send_wvm_calldata(block.clone()).await.unwrap();
+        assert_eq!(
+            settler.send_wvm_calldata(block.clone()).await.unwrap(),
+            tx_hash
+        );
+        assert_eq!(sink.submissions().len(), 1);
+        assert_eq!(settler.hits(), 1);
+
+        // Survives a restart
+        let index = FileContentIndex::open(&path).unwrap();
+        assert_eq!(
+            index.lookup(&payload_checksum(&block)),
+            Some(tx_hash.clone())
+        );
+
+        let revert = RevertRecord {
+            chain_id: 9496,
+            reverted: vec![RevertedBlock {
+                block_number: 1,
+                block_hash: [1; 32],
+                tx_hash: tx_hash.clone(),
+            }],
+            superseded_by: None,
+        };
+        settler.send_wvm_calldata(revert.encode()).await.unwrap();
+
+        let reposted = settler.send_wvm_calldata(block.clone()).await.unwrap();
+        assert_ne!(reposted, tx_hash);
+        assert_eq!(sink.submissions().len(), 3);
+
+        // Dropped transactions are posted again
+        settler.forget_tx(&reposted).unwrap();
+        assert_ne!(settler.send_wvm_calldata(block).await.unwrap(), reposted);
+        assert_eq!(sink.submissions().len(), 4);
+    }
+
+    #[tokio::test]
+    pub async fn test_dedup_encrypted() {
+        struct EncryptingSink {
+            sink: MockWvmSink,
+            encryption: Encryption,
+        }
+
+        #[async_trait]
+        impl WvmDataSettler for EncryptingSink {
+            fn encryption(&self) -> Option<&Encryption> {
+                Some(&self.encryption)
+            }
+
+            async fn send_wvm_calldata(
+                &mut self,
+                block_data: Vec<u8>,
+            ) -> Result<String, WvmDataSettlerError> {
+                self.sink.send_wvm_calldata(block_data).await
+            }
+        }
+
+        let sink = MockWvmSink::new();
+        let encryption =
+            Encryption::new(CipherId::Aes256Gcm, StaticKeyProvider::new(1, [5; 32])).unwrap();
+        let mut settler = DedupWvmDataSettler::new(
+            EncryptingSink {
+                sink: sink.clone(),
+                encryption,
+            },
+            MemoryContentIndex::default(),
+        );
+
+        let meta = BlockMeta {
+            chain_id: 9496,
+            block_number: 1,
+            block_hash: [1; 32],
+        };
+        let block = vec![0xabu8; 64];
+        let first = settler.process_block_enveloped(&block, meta).unwrap();
+        let resealed = settler.process_block_enveloped(&block, meta).unwrap();
+        assert_ne!(first, resealed);
+
+        // Sealed with a fresh nonce, recognized by its plaintext
+        let tx_hash = settler.send_wvm_calldata(first).await.unwrap();
+        assert_eq!(settler.send_wvm_calldata(resealed).await.unwrap(), tx_hash);
+        assert_eq!(sink.submissions().len(), 1);
+    }
+}
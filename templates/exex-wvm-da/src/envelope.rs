@@ -0,0 +1,283 @@
+pub use crate::codec::CodecId;
+pub use crate::crypto::CipherId;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Leading bytes of every archived block blob.
+pub const ENVELOPE_MAGIC: [u8; 4] = *b"WVMD";
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// magic | version | codec | serialization | cipher | key_id | chain_id | block_number |
+/// block_hash | checksum | payload_len
+const HEADER_LEN: usize = 4 + 1 + 1 + 1 + 1 + 4 + 8 + 8 + 32 + 32 + 4;
+
+#[derive(Debug, Error)]
+pub enum EnvelopeError {
+    #[error("Invalid envelope magic bytes")]
+    InvalidMagic,
+
+    #[error("Unsupported envelope version: {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Unknown codec id: {0}")]
+    UnknownCodec(u8),
+
+    #[error("Unknown cipher id: {0}")]
+    UnknownCipher(u8),
+
+    #[error("Unknown serialization id: {0}")]
+    UnknownSerialization(u8),
+
+    #[error("Envelope truncated: expected {expected} bytes, got {actual}")]
+    Truncated { expected: usize, actual: usize },
+
+    #[error("Envelope checksum mismatch")]
+    ChecksumMismatch,
+
+    #[error("Payload of {0} bytes does not fit in an envelope")]
+    PayloadTooLarge(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SerializationId {
+    Borsh = 0,
+}
+
+impl TryFrom<u8> for SerializationId {
+    type Error = EnvelopeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(SerializationId::Borsh),
+            other => Err(EnvelopeError::UnknownSerialization(other)),
+        }
+    }
+}
+
+/// Identifies the block an envelope belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct BlockMeta {
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub block_hash: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvelopeHeader {
+    pub version: u8,
+    pub codec: CodecId,
+    pub serialization: SerializationId,
+    pub cipher: CipherId,
+    /// Encryption key id, 0 for plaintext payloads
+    pub key_id: u32,
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub block_hash: [u8; 32],
+    /// SHA-256 of the header fields before it and the payload
+    pub checksum: [u8; 32],
+}
+
+impl EnvelopeHeader {
+    pub fn meta(&self) -> BlockMeta {
+        BlockMeta {
+            chain_id: self.chain_id,
+            block_number: self.block_number,
+            block_hash: self.block_hash,
+        }
+    }
+}
+
+/// Self-describing wrapper around a processed block payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub header: EnvelopeHeader,
+    pub payload: Vec<u8>,
+}
+
+pub fn payload_checksum(payload: &[u8]) -> [u8; 32] {
+    Sha256::digest(payload).into()
+}
+
+impl Envelope {
+    pub fn seal(
+        meta: BlockMeta,
+        codec: CodecId,
+        serialization: SerializationId,
+        payload: Vec<u8>,
+    ) -> Self {
+        let mut envelope = Self {
+            header: EnvelopeHeader {
+                version: ENVELOPE_VERSION,
+                codec,
+                serialization,
+                cipher: CipherId::None,
+                key_id: 0,
+                chain_id: meta.chain_id,
+                block_number: meta.block_number,
+                block_hash: meta.block_hash,
+                checksum: [0; 32],
+            },
+            payload,
+        };
+        envelope.header.checksum = envelope.checksum();
+        envelope
+    }
+
+    /// Marks the payload as encrypted
+    pub fn with_cipher(mut self, cipher: CipherId, key_id: u32) -> Self {
+        self.header.cipher = cipher;
+        self.header.key_id = key_id;
+        self.header.checksum = self.checksum();
+        self
+    }
+
+    /// Fails for payloads whose length does not fit the 4 byte length field
+    pub fn encode(&self) -> Result<Vec<u8>, EnvelopeError> {
+        let payload_len = u32::try_from(self.payload.len())
+            .map_err(|_| EnvelopeError::PayloadTooLarge(self.payload.len()))?;
+
+        let mut buff = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        self.write_fields(&mut buff);
+        buff.extend_from_slice(&self.header.checksum);
+        buff.extend_from_slice(&payload_len.to_be_bytes());
+        buff.extend_from_slice(&self.payload);
+        Ok(buff)
+    }
+
+    /// Header fields preceding the checksum
+    fn write_fields(&self, buff: &mut Vec<u8>) {
+        let header = &self.header;
+        buff.extend_from_slice(&ENVELOPE_MAGIC);
+        buff.push(header.version);
+        buff.push(header.codec as u8);
+        buff.push(header.serialization as u8);
+        buff.push(header.cipher as u8);
+        buff.extend_from_slice(&header.key_id.to_be_bytes());
+        buff.extend_from_slice(&header.chain_id.to_be_bytes());
+        buff.extend_from_slice(&header.block_number.to_be_bytes());
+        buff.extend_from_slice(&header.block_hash);
+    }
+
+    fn checksum(&self) -> [u8; 32] {
+        let mut fields = Vec::with_capacity(HEADER_LEN);
+        self.write_fields(&mut fields);
+        let mut hasher = Sha256::new();
+        hasher.update(&fields);
+        hasher.update(&self.payload);
+        hasher.finalize().into()
+    }
+
+    ///
+    /// Parses and verifies an envelope produced by `Envelope::encode`
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - raw envelope bytes, as posted to WeaveVM
+    pub fn decode(data: &[u8]) -> Result<Self, EnvelopeError> {
+        if data.len() < ENVELOPE_MAGIC.len() + 1 {
+            return Err(EnvelopeError::Truncated {
+                expected: HEADER_LEN,
+                actual: data.len(),
+            });
+        }
+        if data[..4] != ENVELOPE_MAGIC {
+            return Err(EnvelopeError::InvalidMagic);
+        }
+        let version = data[4];
+        if version != ENVELOPE_VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+        if data.len() < HEADER_LEN {
+            return Err(EnvelopeError::Truncated {
+                expected: HEADER_LEN,
+                actual: data.len(),
+            });
+        }
+
+        let codec = CodecId::try_from(data[5])?;
+        let serialization = SerializationId::try_from(data[6])?;
+        let cipher = CipherId::try_from(data[7])?;
+        let key_id = u32::from_be_bytes(data[8..12].try_into().unwrap());
+        let fields = &data[12..HEADER_LEN];
+
+        let chain_id = u64::from_be_bytes(fields[0..8].try_into().unwrap());
+        let block_number = u64::from_be_bytes(fields[8..16].try_into().unwrap());
+        let block_hash: [u8; 32] = fields[16..48].try_into().unwrap();
+        let checksum: [u8; 32] = fields[48..80].try_into().unwrap();
+        let payload_len = u32::from_be_bytes(fields[80..84].try_into().unwrap()) as usize;
+
+        let payload = &data[HEADER_LEN..];
+        if payload.len() != payload_len {
+            return Err(EnvelopeError::Truncated {
+                expected: HEADER_LEN + payload_len,
+                actual: data.len(),
+            });
+        }
+
+        let envelope = Self {
+            header: EnvelopeHeader {
+                version,
+                codec,
+                serialization,
+                cipher,
+                key_id,
+                chain_id,
+                block_number,
+                block_hash,
+                checksum,
+            },
+            payload: payload.to_vec(),
+        };
+        if envelope.checksum() != checksum {
+            return Err(EnvelopeError::ChecksumMismatch);
+        }
+        Ok(envelope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::envelope::{
+        BlockMeta, CipherId, CodecId, Envelope, EnvelopeError, SerializationId, ENVELOPE_VERSION,
+    };
+
+    #[test]
+    pub fn test_envelope_round_trip() {
+        let meta = BlockMeta {
+            chain_id: 9496,
+            block_number: 42,
+            block_hash: [3u8; 32],
+        };
+        let envelope = Envelope::seal(meta, CodecId::Brotli, SerializationId::Borsh, vec![1, 2, 3]);
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        assert_eq!(decoded, envelope);
+        assert_eq!(decoded.header.version, ENVELOPE_VERSION);
+
+        let encrypted = envelope.clone().with_cipher(CipherId::ChaCha20Poly1305, 5);
+        let decoded = Envelope::decode(&encrypted.encode().unwrap()).unwrap();
+        assert_eq!(decoded, encrypted);
+
+        // block_number is at bytes 20..28
+        let mut corrupted_header = envelope.encode().unwrap();
+        corrupted_header[27] ^= 0xff;
+        assert!(matches!(
+            Envelope::decode(&corrupted_header),
+            Err(EnvelopeError::ChecksumMismatch)
+        ));
+
+        let mut unknown_version = envelope.encode().unwrap();
+        unknown_version[4] = ENVELOPE_VERSION + 1;
+        assert!(matches!(
+            Envelope::decode(&unknown_version),
+            Err(EnvelopeError::UnsupportedVersion(_))
+        ));
+
+        let mut corrupted = envelope.encode().unwrap();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(
+            Envelope::decode(&corrupted),
+            Err(EnvelopeError::ChecksumMismatch)
+        ));
+    }
+}
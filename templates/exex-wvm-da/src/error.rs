@@ -0,0 +1,178 @@
+use crate::codec::CodecError;
+use crate::crypto::CryptoError;
+use std::time::Duration;
+use thiserror::Error;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum WvmDataSettlerError {
+    #[error("Failed to serialize block: {0}")]
+    Serialization(#[source] std::io::Error),
+
+    #[error("Failed to compress block: {0}")]
+    Compression(#[from] CodecError),
+
+    #[error("Failed to encrypt block: {0}")]
+    Encryption(#[from] CryptoError),
+
+    #[error("Payload of {size} bytes exceeds the maximum of {max} bytes")]
+    PayloadTooLarge { size: usize, max: usize },
+
+    #[error("RPC transport error: {0}")]
+    Transport(#[source] BoxError),
+
+    #[error("Nonce error: {0}")]
+    Nonce(#[source] BoxError),
+
+    #[error("Gas error: {0}")]
+    Gas(#[source] BoxError),
+
+    #[error("Settlement transaction rejected by the node: {0}")]
+    Rejected(#[source] BoxError),
+
+    #[error("Settlement transaction {tx_hash} reverted")]
+    Reverted { tx_hash: String },
+
+    #[error("Settlement timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("Settlement store error: {0}")]
+    Store(#[source] std::io::Error),
+
+    #[error("Invalid settler configuration: {0}")]
+    Config(String),
+
+    #[error("Settlement estimated at {estimate} wei exceeds the budget of {limit} wei")]
+    BudgetExceeded { estimate: u128, limit: u128 },
+
+    #[error("Failed to build blob transaction: {0}")]
+    Blob(#[source] BoxError),
+
+    #[error("{succeeded} of {total} settlement backends succeeded, {required} required: {source}")]
+    FanOut {
+        succeeded: usize,
+        required: usize,
+        total: usize,
+        /// First backend failure
+        #[source]
+        source: Box<WvmDataSettlerError>,
+    },
+}
+
+impl WvmDataSettlerError {
+    ///
+    /// Classifies an error returned by the WeaveVM node. Nodes report nonce and fee problems
+    /// as plain JSON-RPC messages, so the classification is based on the message text.
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - error returned while submitting the settlement transaction
+    pub fn from_rpc_error(error: impl Into<BoxError>) -> Self {
+        let error = error.into();
+        let message = error.to_string().to_lowercase();
+        let matches_any = |patterns: &[&str]| patterns.iter().any(|p| message.contains(p));
+
+        if matches_any(&["oversized data", "transaction size exceeds"]) {
+            match parse_size_limit(&message) {
+                Some((size, max)) => WvmDataSettlerError::PayloadTooLarge { size, max },
+                None => WvmDataSettlerError::Rejected(error),
+            }
+        } else if matches_any(&[
+            "insufficient funds",
+            "intrinsic gas too low",
+            "exceeds block gas limit",
+            "max priority fee per gas higher than max fee per gas",
+        ]) {
+            // Resubmitting the same transaction fails the same way
+            WvmDataSettlerError::Rejected(error)
+        } else if matches_any(&["nonce too low", "nonce too high", "already known"]) {
+            WvmDataSettlerError::Nonce(error)
+        } else if matches_any(&[
+            "underpriced",
+            "fee cap less than block base fee",
+            "max fee per gas less than block base fee",
+        ]) {
+            WvmDataSettlerError::Gas(error)
+        } else {
+            WvmDataSettlerError::Transport(error)
+        }
+    }
+
+    /// Whether resubmitting the same payload may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            WvmDataSettlerError::Transport(_)
+            | WvmDataSettlerError::Nonce(_)
+            | WvmDataSettlerError::Gas(_)
+            | WvmDataSettlerError::Timeout(_) => true,
+            WvmDataSettlerError::FanOut { source, .. } => source.is_retryable(),
+            _ => false,
+        }
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        !self.is_retryable()
+    }
+}
+
+/// Size and limit of a geth "oversized data: transaction size {size}, limit {max}" error
+fn parse_size_limit(message: &str) -> Option<(usize, usize)> {
+    let number_after = |key: &str| -> Option<usize> {
+        let rest = &message[message.find(key)? + key.len()..];
+        let digits: String = rest
+            .trim_start()
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect();
+        digits.parse().ok()
+    };
+    Some((number_after("transaction size")?, number_after("limit")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::WvmDataSettlerError;
+    use std::error::Error;
+
+    #[test]
+    pub fn test_rpc_error_classification() {
+        let error = WvmDataSettlerError::from_rpc_error("nonce too low");
+        assert!(matches!(error, WvmDataSettlerError::Nonce(_)));
+        assert!(error.is_retryable());
+        assert_eq!(error.source().unwrap().to_string(), "nonce too low");
+
+        let error = WvmDataSettlerError::from_rpc_error("replacement transaction underpriced");
+        assert!(matches!(error, WvmDataSettlerError::Gas(_)));
+
+        let error = WvmDataSettlerError::from_rpc_error("connection refused");
+        assert!(matches!(error, WvmDataSettlerError::Transport(_)));
+
+        for message in [
+            "insufficient funds for gas * price + value: balance 0, tx cost 21000",
+            "intrinsic gas too low: gas 21000, minimum needed 53000",
+            "exceeds block gas limit",
+        ] {
+            let error = WvmDataSettlerError::from_rpc_error(message);
+            assert!(matches!(error, WvmDataSettlerError::Rejected(_)));
+            assert!(error.is_fatal());
+        }
+
+        let error = WvmDataSettlerError::from_rpc_error(
+            "oversized data: transaction size 200000, limit 131072",
+        );
+        assert!(matches!(
+            error,
+            WvmDataSettlerError::PayloadTooLarge {
+                size: 200000,
+                max: 131072
+            }
+        ));
+        assert!(error.is_fatal());
+
+        let error = WvmDataSettlerError::Reverted {
+            tx_hash: "0x01".to_string(),
+        };
+        assert!(error.is_fatal());
+    }
+}
@@ -0,0 +1,1101 @@
+use crate::backfill::BlockSource;
+use crate::confirm::{ConfirmationTracker, SettlementEvent};
+use crate::envelope::BlockMeta;
+use crate::index::{ArchiveIndex, IndexEntry};
+use crate::journal::SettlementJournal;
+use crate::metrics;
+use crate::profile::{DaPayload, PayloadProfile};
+use crate::retry::{IdempotencyStore, RetryPolicy};
+use crate::revert::{RevertRecord, RevertedBlock};
+use crate::source::ProviderBlockSource;
+use crate::{PendingSettlement, WvmDataSettler, WvmDataSettlerError};
+use eyre::Result;
+use reth::api::FullNodeComponents;
+use reth::providers::Chain;
+use reth_exex::{ExExContext, ExExEvent, ExExNotification};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Number of settled blocks below the tip remembered for revert records.
+const REVERT_HISTORY: u64 = 256;
+
+/// Blocks settled between two rewrites of the journal and the index.
+const COMPACTION_INTERVAL: u64 = 1024;
+
+/// Block settlement started by `WvmDataSettler::submit_wvm_calldata`
+struct InFlight {
+    meta: BlockMeta,
+    /// Calldata handed to the confirmation tracker, or kept for a retry
+    block_data: Vec<u8>,
+    /// Earlier failed attempts
+    attempts: u32,
+    pending: PendingSettlement,
+}
+
+/// Block waiting for another settlement attempt
+struct FailedSettlement {
+    meta: BlockMeta,
+    /// `None` when the block is read back from the node
+    block_data: Option<Vec<u8>>,
+    attempts: u32,
+    retry_at: Instant,
+}
+
+/// Revert record waiting for another attempt
+struct UnsentRevert {
+    record: RevertRecord,
+    attempts: u32,
+    retry_at: Instant,
+}
+
+/// Ready-to-run ExEx archiving every committed block to WeaveVM.
+/// Reorged and reverted blocks are marked with a `RevertRecord` referencing their
+/// original settlement transactions. Reverted blocks stay settled until their record is
+/// sent, failed records are retried like failed blocks.
+///
+/// Settlement failures are logged and hold back the `FinishedHeight` reported to reth, so
+/// blocks that were not archived are not pruned. Failed blocks are settled again with the
+/// backoff of the retry policy until they succeed; wrap the settler in a
+/// `RetryingWvmDataSettler` to retry within one attempt instead. Blocks of one notification
+/// are submitted through `submit_wvm_calldata` before any is awaited, so a
+/// `PipelinedWvmDataSettler` keeps them in flight together.
+pub struct WvmDaExEx<Node: FullNodeComponents, S> {
+    ctx: ExExContext<Node>,
+    settler: S,
+    chain_id: u64,
+    profile: PayloadProfile,
+    journal: Option<SettlementJournal>,
+    confirmations: Option<ConfirmationTracker>,
+    index: Option<ArchiveIndex>,
+    /// block number -> (block hash, settlement tx hash)
+    settled: BTreeMap<u64, ([u8; 32], String)>,
+    /// Blocks that failed to settle, tracked when there is no journal
+    unsettled: BTreeSet<u64>,
+    /// Failed blocks waiting for a retry, by block number
+    failed: BTreeMap<u64, FailedSettlement>,
+    /// Revert records waiting for a retry, in revert order
+    unsent_reverts: Vec<UnsentRevert>,
+    retry_policy: RetryPolicy,
+    /// Tip of the last committed chain
+    tip: Option<u64>,
+    /// Height below which the journal was last compacted
+    compacted_below: u64,
+}
+
+impl<Node, S> WvmDaExEx<Node, S>
+where
+    Node: FullNodeComponents,
+    S: WvmDataSettler + Send + Sync,
+{
+    pub fn new(ctx: ExExContext<Node>, settler: S) -> Self {
+        let chain_id = ctx.config.chain.chain.id();
+        metrics::describe_metrics();
+        Self {
+            ctx,
+            settler,
+            chain_id,
+            profile: PayloadProfile::default(),
+            journal: None,
+            confirmations: None,
+            index: None,
+            settled: BTreeMap::new(),
+            unsettled: BTreeSet::new(),
+            failed: BTreeMap::new(),
+            unsent_reverts: vec![],
+            retry_policy: RetryPolicy::default(),
+            tip: None,
+            compacted_below: 0,
+        }
+    }
+
+    /// Selects the block content archived as `DaPayload`, full block with senders by default.
+    pub fn with_profile(mut self, profile: PayloadProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Records every block in `journal`, and skips blocks it already holds a settlement for.
+    /// The reported `FinishedHeight` follows the journal, which is compacted as blocks
+    /// settle. Blocks the journal left unfinished are resumed when the ExEx starts, and
+    /// abandoned if they can't be read back from the node.
+    pub fn with_journal(mut self, journal: SettlementJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Follows every settlement until final, polling between notifications. Finalized
+    /// and failed settlements are recorded in the journal. Subscribe to the tracker before
+    /// passing it in to receive its events.
+    pub fn with_confirmations(mut self, tracker: ConfirmationTracker) -> Self {
+        self.confirmations = Some(tracker);
+        self
+    }
+
+    /// Indexes every settled block, and settles the index checkpoints it hands out.
+    /// The index is compacted along with the journal.
+    pub fn with_index(mut self, index: ArchiveIndex) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    /// Backoff between two settlement attempts of a failed block or revert record, only
+    /// `backoff` is used: failed blocks are retried until they settle or are reverted.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        self.resume().await?;
+
+        let poll_interval = self
+            .confirmations
+            .as_ref()
+            .map_or(Duration::from_secs(60), |tracker| tracker.poll_interval());
+        let mut poll = tokio::time::interval(poll_interval);
+
+        loop {
+            let next_retry = self
+                .failed
+                .values()
+                .map(|failed| failed.retry_at)
+                .chain(self.unsent_reverts.iter().map(|unsent| unsent.retry_at))
+                .min();
+            let retry = tokio::time::sleep_until(next_retry.unwrap_or_else(Instant::now));
+            tokio::select! {
+                notification = self.ctx.notifications.recv() => {
+                    let Some(notification) = notification else {
+                        break;
+                    };
+                    self.handle_notification(notification).await?;
+                }
+                _ = poll.tick(), if self.confirmations.is_some() => {
+                    if let Err(error) = self.poll_confirmations().await {
+                        warn!(target: "exex::wvm_da", %error, "Failed to poll settlements");
+                    }
+                }
+                _ = retry, if next_retry.is_some() => {
+                    self.retry_failed().await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Picks up the work a previous run left unfinished in the journal: follows its
+    /// submitted settlements again, and settles the blocks it did not, read back from the
+    /// node. Without a tracker submitted settlements count as done unless their block was
+    /// reorged. Blocks that can't be read or settled are retried like failed blocks.
+    async fn resume(&mut self) -> Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        let unfinished: Vec<(u64, [u8; 32])> = journal
+            .unfinished()
+            .map(|(block_number, entry)| (block_number, entry.block_hash))
+            .collect();
+
+        let mut in_flight = vec![];
+        for (block_number, block_hash) in unfinished {
+            let meta = BlockMeta {
+                chain_id: self.chain_id,
+                block_number,
+                block_hash,
+            };
+            in_flight.extend(self.settle_from_node(meta, 0).await?);
+        }
+        self.await_settlements(in_flight).await?;
+
+        if let Err(error) = self.poll_confirmations().await {
+            warn!(target: "exex::wvm_da", %error, "Failed to poll settlements");
+        }
+        Ok(())
+    }
+
+    ///
+    /// Reads the canonical block at the height of `journaled` back from the node and starts
+    /// settling it, or follows its journaled settlement again if it has one. A journaled
+    /// settlement of a block reorged while the node was down is reverted first.
+    ///
+    /// # Arguments
+    ///
+    /// * `journaled` - block as recorded in the journal
+    /// * `attempts` - earlier failed attempts
+    async fn settle_from_node(
+        &mut self,
+        journaled: BlockMeta,
+        attempts: u32,
+    ) -> Result<Option<InFlight>> {
+        let block_number = journaled.block_number;
+        let source = match ProviderBlockSource::new(
+            self.ctx.provider().clone(),
+            self.chain_id,
+            self.profile,
+        ) {
+            Ok(source) => source,
+            Err(error) => {
+                self.abandon(journaled, &error.to_string())?;
+                return Ok(None);
+            }
+        };
+
+        let (meta, payload) = match source.block(block_number).await {
+            Ok(Some(block)) => block,
+            Ok(None) => {
+                self.abandon(journaled, "block is not held by the node")?;
+                return Ok(None);
+            }
+            Err(error) => {
+                self.record_failure(journaled, None, attempts, &error)?;
+                return Ok(None);
+            }
+        };
+
+        let settled_tx = self.settlement_of(block_number, journaled.block_hash);
+        let reorged = meta.block_hash != journaled.block_hash;
+        if reorged {
+            if let Some(tx_hash) = settled_tx.clone() {
+                let record = RevertRecord {
+                    chain_id: self.chain_id,
+                    reverted: vec![RevertedBlock {
+                        block_number,
+                        block_hash: journaled.block_hash,
+                        tx_hash,
+                    }],
+                    superseded_by: Some((meta.block_number, meta.block_hash)),
+                };
+                self.send_revert_record(record, 0).await?;
+            }
+        } else if settled_tx.is_some() && self.confirmations.is_none() {
+            return Ok(None);
+        }
+
+        let block_data = match self
+            .settler
+            .process_block_enveloped_async(payload, meta)
+            .await
+        {
+            Ok(block_data) => block_data,
+            Err(error) => {
+                self.record_failure(meta, None, attempts, &error)?;
+                return Ok(None);
+            }
+        };
+
+        if let (false, Some(tx_hash), Some(tracker)) =
+            (reorged, settled_tx, &mut self.confirmations)
+        {
+            tracker.track(meta, tx_hash, block_data);
+            return Ok(None);
+        }
+
+        if let Some(journal) = &mut self.journal {
+            journal.mark_pending(meta.block_number, meta.block_hash)?;
+        }
+        Ok(Some(
+            self.submit_settlement(meta, block_data, attempts).await,
+        ))
+    }
+
+    async fn handle_notification(&mut self, notification: ExExNotification) -> Result<()> {
+        match &notification {
+            ExExNotification::ChainCommitted { new } => {
+                self.archive_chain(new).await?;
+            }
+            ExExNotification::ChainReorged { old, new } => {
+                let tip = new.tip();
+                self.revert_chain(old, Some((tip.number, tip.hash().0)))
+                    .await?;
+                self.archive_chain(new).await?;
+            }
+            ExExNotification::ChainReverted { old } => {
+                self.revert_chain(old, None).await?;
+            }
+        }
+
+        if let Some(committed_chain) = notification.committed_chain() {
+            let tip = committed_chain.tip().number;
+            self.tip = Some(tip);
+            if let Some(settled) = self.settled.keys().next_back() {
+                metrics::set_settlement_lag(tip.saturating_sub(*settled));
+            }
+            self.compact(tip)?;
+            self.record_journal_backlog();
+            self.report_finished_height()?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends again the revert records and settles again the failed blocks whose backoff
+    /// elapsed
+    async fn retry_failed(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.unsent_reverts)
+            .into_iter()
+            .partition(|unsent| unsent.retry_at <= now);
+        self.unsent_reverts = waiting;
+        for unsent in due {
+            metrics::record_retry("revert");
+            self.send_revert_record(unsent.record, unsent.attempts)
+                .await?;
+        }
+
+        let due: Vec<u64> = self
+            .failed
+            .iter()
+            .filter(|(_, failed)| failed.retry_at <= now)
+            .map(|(block_number, _)| *block_number)
+            .collect();
+
+        let mut in_flight = vec![];
+        for block_number in due {
+            let failed = self.failed.remove(&block_number).unwrap();
+            metrics::record_retry("failed");
+            let Some(block_data) = failed.block_data else {
+                in_flight.extend(self.settle_from_node(failed.meta, failed.attempts).await?);
+                continue;
+            };
+            if let Some(journal) = &mut self.journal {
+                journal.mark_pending(block_number, failed.meta.block_hash)?;
+            }
+            in_flight.push(
+                self.submit_settlement(failed.meta, block_data, failed.attempts)
+                    .await,
+            );
+        }
+        self.await_settlements(in_flight).await?;
+
+        self.record_journal_backlog();
+        self.report_finished_height()?;
+        self.publish_index_checkpoint().await
+    }
+
+    fn report_finished_height(&mut self) -> Result<()> {
+        if let Some(finished) = self.tip.and_then(|tip| self.finished_height(tip)) {
+            self.ctx.events.send(ExExEvent::FinishedHeight(finished))?;
+        }
+        Ok(())
+    }
+
+    /// Drops the settled journal entries that revert records no longer need, and the
+    /// index records of reorged blocks
+    fn compact(&mut self, tip: u64) -> Result<()> {
+        let keep_from = tip.saturating_sub(REVERT_HISTORY);
+        if keep_from < self.compacted_below + COMPACTION_INTERVAL {
+            return Ok(());
+        }
+
+        if let Some(journal) = &mut self.journal {
+            // Without a tracker, submitted settlements are never confirmed
+            match self.confirmations {
+                Some(_) => journal.compact(keep_from)?,
+                None => journal.compact_settled(keep_from)?,
+            }
+        }
+        if let Some(index) = &mut self.index {
+            index.compact()?;
+        }
+        self.compacted_below = keep_from;
+        Ok(())
+    }
+
+    /// Highest block such that it and every block below it is settled
+    fn finished_height(&self, tip: u64) -> Option<u64> {
+        let settled = match &self.journal {
+            Some(journal) => journal.highest_contiguous_settled()?,
+            None => match self.unsettled.first() {
+                Some(unsettled) => unsettled.checked_sub(1)?,
+                None => tip,
+            },
+        };
+        Some(settled.min(tip))
+    }
+
+    fn record_journal_backlog(&self) {
+        if let Some(journal) = &self.journal {
+            metrics::set_journal_backlog(journal.unfinished().count());
+        }
+    }
+
+    async fn poll_confirmations(&mut self) -> Result<()> {
+        let Some(tracker) = &mut self.confirmations else {
+            return Ok(());
+        };
+
+        let polled = tracker.poll(&mut self.settler).await?;
+        for (tx_hash, error) in &polled.errors {
+            warn!(target: "exex::wvm_da", %tx_hash, %error, "Failed to fetch settlement status");
+        }
+
+        for event in polled.events {
+            let block = *event.block();
+
+            if let SettlementEvent::Submitted { tx_hash, .. } = &event {
+                // Resubmitted, revert records must reference the new transaction
+                if let Some((_, settled_tx)) = self
+                    .settled
+                    .get_mut(&block.block_number)
+                    .filter(|(block_hash, _)| *block_hash == block.block_hash)
+                {
+                    *settled_tx = tx_hash.clone();
+                }
+                if let Some(index) = self.index.as_mut().filter(|index| {
+                    index
+                        .get(block.block_number)
+                        .is_some_and(|entry| entry.block_hash == block.block_hash)
+                }) {
+                    index.insert(IndexEntry {
+                        block_number: block.block_number,
+                        block_hash: block.block_hash,
+                        tx_hash: tx_hash.clone(),
+                        batch: None,
+                    })?;
+                }
+            }
+
+            // Settlements of blocks that were reorged since are not recorded
+            let Some(journal) = self.journal.as_mut().filter(|journal| {
+                journal
+                    .get(block.block_number)
+                    .is_some_and(|entry| entry.block_hash == block.block_hash)
+            }) else {
+                continue;
+            };
+
+            match &event {
+                SettlementEvent::Submitted { tx_hash, .. } => {
+                    journal.mark_submitted(block.block_number, block.block_hash, tx_hash)?;
+                }
+                SettlementEvent::Included { .. } => {}
+                SettlementEvent::Finalized { tx_hash, .. } => {
+                    journal.mark_confirmed(block.block_number, block.block_hash, tx_hash)?;
+                }
+                SettlementEvent::Failed { reason, .. } => {
+                    journal.mark_failed(block.block_number, block.block_hash, reason)?;
+                }
+            }
+        }
+
+        self.record_journal_backlog();
+        Ok(())
+    }
+
+    /// Submits every block of `chain` before awaiting the first, so settlers keeping
+    /// several transactions in flight settle them concurrently. Results are recorded in
+    /// block order.
+    async fn archive_chain(&mut self, chain: &Chain) -> Result<()> {
+        let mut in_flight = vec![];
+        for block in chain.blocks_iter() {
+            let meta = BlockMeta {
+                chain_id: self.chain_id,
+                block_number: block.number,
+                block_hash: block.hash().0,
+            };
+
+            if let Some(journal) = &mut self.journal {
+                if journal.settled(&meta).is_some() {
+                    continue;
+                }
+                journal.mark_pending(meta.block_number, meta.block_hash)?;
+            }
+
+            let payload = DaPayload::build(self.profile, chain, block);
+            let block_data = self
+                .settler
+                .process_block_enveloped_async(payload, meta)
+                .await?;
+            in_flight.push(self.submit_settlement(meta, block_data, 0).await);
+        }
+
+        self.await_settlements(in_flight).await?;
+
+        let lowest_kept = chain.tip().number.saturating_sub(REVERT_HISTORY);
+        self.settled = self.settled.split_off(&lowest_kept);
+
+        self.publish_index_checkpoint().await
+    }
+
+    async fn submit_settlement(
+        &mut self,
+        meta: BlockMeta,
+        block_data: Vec<u8>,
+        attempts: u32,
+    ) -> InFlight {
+        InFlight {
+            meta,
+            block_data: block_data.clone(),
+            attempts,
+            pending: self.settler.submit_wvm_calldata(block_data).await,
+        }
+    }
+
+    /// Waits for the settlements of `in_flight` and records them in order
+    async fn await_settlements(&mut self, in_flight: Vec<InFlight>) -> Result<()> {
+        for InFlight {
+            meta,
+            block_data,
+            attempts,
+            pending,
+        } in in_flight
+        {
+            let result = self.settler.await_wvm_calldata(pending).await;
+            self.record_settlement(meta, block_data, attempts, result)?;
+        }
+        Ok(())
+    }
+
+    fn record_settlement(
+        &mut self,
+        meta: BlockMeta,
+        block_data: Vec<u8>,
+        attempts: u32,
+        result: Result<String, WvmDataSettlerError>,
+    ) -> Result<()> {
+        match result {
+            Ok(tx_hash) => {
+                if let Some(tracker) = &mut self.confirmations {
+                    tracker.track(meta, tx_hash.clone(), block_data);
+                }
+                if let Some(journal) = &mut self.journal {
+                    journal.mark_submitted(meta.block_number, meta.block_hash, &tx_hash)?;
+                }
+                if let Some(index) = &mut self.index {
+                    index.insert(IndexEntry {
+                        block_number: meta.block_number,
+                        block_hash: meta.block_hash,
+                        tx_hash: tx_hash.clone(),
+                        batch: None,
+                    })?;
+                }
+                self.settled
+                    .insert(meta.block_number, (meta.block_hash, tx_hash));
+                self.unsettled.remove(&meta.block_number);
+            }
+            Err(error) => self.record_failure(meta, Some(block_data), attempts, &error)?,
+        }
+        Ok(())
+    }
+
+    /// Gives up on a block that can't be read back from the node, so that it no longer holds
+    /// back the `FinishedHeight`
+    fn abandon(&mut self, meta: BlockMeta, reason: &str) -> Result<()> {
+        warn!(
+            target: "exex::wvm_da",
+            block_number = meta.block_number,
+            reason,
+            "Abandoned unsettled block"
+        );
+        if let Some(journal) = &mut self.journal {
+            journal.mark_abandoned(meta.block_number, meta.block_hash, reason)?;
+        }
+        self.unsettled.remove(&meta.block_number);
+        Ok(())
+    }
+
+    /// Marks `meta` as failed and queues it for a retry after the backoff
+    fn record_failure(
+        &mut self,
+        meta: BlockMeta,
+        block_data: Option<Vec<u8>>,
+        attempts: u32,
+        error: &dyn Display,
+    ) -> Result<()> {
+        warn!(
+            target: "exex::wvm_da",
+            block_number = meta.block_number,
+            attempts = attempts + 1,
+            %error,
+            "Failed to settle block"
+        );
+        match &mut self.journal {
+            Some(journal) => {
+                journal.mark_failed(meta.block_number, meta.block_hash, &error.to_string())?
+            }
+            None => {
+                self.unsettled.insert(meta.block_number);
+            }
+        }
+        let attempts = attempts + 1;
+        self.failed.insert(
+            meta.block_number,
+            FailedSettlement {
+                meta,
+                block_data,
+                attempts,
+                retry_at: Instant::now() + self.retry_policy.backoff(attempts),
+            },
+        );
+        Ok(())
+    }
+
+    async fn publish_index_checkpoint(&mut self) -> Result<()> {
+        let Some(checkpoint) = self
+            .index
+            .as_ref()
+            .and_then(|index| index.checkpoint_due(self.chain_id))
+        else {
+            return Ok(());
+        };
+
+        // A failed checkpoint is published with the next one that is due
+        match self.settler.send_wvm_calldata(checkpoint.encode()).await {
+            Ok(tx_hash) => {
+                if let Some(index) = &mut self.index {
+                    index.mark_checkpointed(&tx_hash)?;
+                }
+            }
+            Err(error) => {
+                warn!(target: "exex::wvm_da", %error, "Failed to settle index checkpoint");
+            }
+        }
+        Ok(())
+    }
+
+    /// Publishes a `RevertRecord` for every settled block of `chain`.
+    async fn revert_chain(
+        &mut self,
+        chain: &Chain,
+        superseded_by: Option<(u64, [u8; 32])>,
+    ) -> Result<()> {
+        // Failed blocks of the old chain are not retried
+        for block in chain.blocks_iter() {
+            if self
+                .failed
+                .get(&block.number)
+                .is_some_and(|failed| failed.meta.block_hash == block.hash().0)
+            {
+                self.failed.remove(&block.number);
+                self.unsettled.remove(&block.number);
+            }
+        }
+
+        let reverted: Vec<RevertedBlock> = chain
+            .blocks_iter()
+            .filter_map(|block| {
+                let block_hash = block.hash().0;
+                self.settlement_of(block.number, block_hash)
+                    .map(|tx_hash| RevertedBlock {
+                        block_number: block.number,
+                        block_hash,
+                        tx_hash,
+                    })
+            })
+            .collect();
+
+        if reverted.is_empty() {
+            return Ok(());
+        }
+
+        let record = RevertRecord {
+            chain_id: self.chain_id,
+            reverted,
+            superseded_by,
+        };
+        self.send_revert_record(record, 0).await
+    }
+
+    /// Forgets the settlements of the reverted blocks once `record` is sent, or queues it
+    /// for a retry
+    async fn send_revert_record(&mut self, record: RevertRecord, attempts: u32) -> Result<()> {
+        match self.settler.send_wvm_calldata(record.encode()).await {
+            Ok(_) => {
+                for block in &record.reverted {
+                    // Replaced by a block of the new chain meanwhile
+                    if self
+                        .settled
+                        .get(&block.block_number)
+                        .is_some_and(|(block_hash, _)| *block_hash == block.block_hash)
+                    {
+                        self.settled.remove(&block.block_number);
+                    }
+                    if let Some(index) = &mut self.index {
+                        index.remove(block.block_number, block.block_hash)?;
+                    }
+                    if let Some(journal) = &mut self.journal {
+                        journal.mark_reverted(block.block_number, block.block_hash)?;
+                    }
+                }
+            }
+            Err(error) => {
+                warn!(
+                    target: "exex::wvm_da",
+                    attempts = attempts + 1,
+                    %error,
+                    "Failed to settle revert record"
+                );
+                let attempts = attempts + 1;
+                self.unsent_reverts.push(UnsentRevert {
+                    record,
+                    attempts,
+                    retry_at: Instant::now() + self.retry_policy.backoff(attempts),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn settlement_of(&self, block_number: u64, block_hash: [u8; 32]) -> Option<String> {
+        if let Some((hash, tx_hash)) = self.settled.get(&block_number) {
+            return (*hash == block_hash).then(|| tx_hash.clone());
+        }
+        self.journal
+            .as_ref()
+            .and_then(|journal| journal.get(block_number))
+            .filter(|entry| entry.block_hash == block_hash)
+            .and_then(|entry| entry.state.tx_hash())
+            .map(str::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::confirm::{ConfirmationConfig, ConfirmationTracker};
+    use crate::envelope::Envelope;
+    use crate::exex::WvmDaExEx;
+    use crate::journal::{SettlementJournal, SettlementState};
+    use crate::mock::{MockFailure, MockWvmSink};
+    use crate::nonce::{NonceAccount, PipelineConfig, PipelinedWvmDataSettler};
+    use crate::profile::PayloadProfile;
+    use crate::retry::RetryPolicy;
+    use crate::revert::RevertRecord;
+    use crate::{WvmDataSettler, WvmDataSettlerError};
+    use async_trait::async_trait;
+    use reth::primitives::{Header, SealedBlockWithSenders, SealedHeader};
+    use reth::providers::Chain;
+    use reth_exex::{ExExEvent, ExExNotification};
+    use reth_exex_test_utils::test_exex_context;
+    use std::ops::Range;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn test_chain(numbers: Range<u64>) -> Chain {
+        let blocks = numbers.map(|number| {
+            let mut block = SealedBlockWithSenders::default();
+            block.block.header = SealedHeader::seal(Header {
+                number,
+                ..Default::default()
+            });
+            block
+        });
+        Chain::new(blocks, Default::default(), None)
+    }
+
+    #[tokio::test]
+    pub async fn test_wvm_da_exex() {
+        struct TestWvmDa {
+            sent: Arc<Mutex<Vec<Vec<u8>>>>,
+        }
+
+        #[async_trait]
+        impl WvmDataSettler for TestWvmDa {
+            async fn send_wvm_calldata(
+                &mut self,
+                block_data: Vec<u8>,
+            ) -> Result<String, WvmDataSettlerError> {
+                self.sent.lock().unwrap().push(block_data);
+                Ok("0x01".to_string())
+            }
+        }
+
+        let (ctx, mut handle) = test_exex_context().await.unwrap();
+
+        let chain_def = Chain::from_block(Default::default(), Default::default(), None);
+        handle
+            .notifications_tx
+            .send(ExExNotification::ChainCommitted {
+                new: Arc::new(chain_def),
+            })
+            .await
+            .unwrap();
+        drop(handle.notifications_tx);
+
+        let sent = Arc::new(Mutex::new(vec![]));
+        let settler = TestWvmDa { sent: sent.clone() };
+        WvmDaExEx::new(ctx, settler).run().await.unwrap();
+
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        assert!(matches!(
+            handle.events_rx.recv().await,
+            Some(ExExEvent::FinishedHeight(0))
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_wvm_da_exex_pipelined() {
+        /// Includes its whole mempool once two transactions wait in it, or after ten polls
+        #[derive(Default)]
+        struct BatchingAccount {
+            /// (included count, broadcast tx hashes by nonce, polls without inclusion)
+            chain: Mutex<(u64, Vec<String>, u64)>,
+            max_in_flight: Mutex<u64>,
+        }
+
+        #[async_trait]
+        impl NonceAccount for Arc<BatchingAccount> {
+            async fn submit(
+                &self,
+                nonce: u64,
+                _data: Vec<u8>,
+                _gas_bump_percent: u64,
+            ) -> Result<String, WvmDataSettlerError> {
+                let mut chain = self.chain.lock().unwrap();
+                let tx_hash = format!("0x{:02x}", nonce);
+                chain.1.push(tx_hash.clone());
+                let mut max_in_flight = self.max_in_flight.lock().unwrap();
+                *max_in_flight = (*max_in_flight).max(chain.1.len() as u64 - chain.0);
+                Ok(tx_hash)
+            }
+
+            async fn nonces(&self) -> Result<(u64, u64), WvmDataSettlerError> {
+                let mut chain = self.chain.lock().unwrap();
+                let pending = chain.1.len() as u64;
+                if pending - chain.0 >= 2 || chain.2 >= 10 {
+                    chain.0 = pending;
+                    chain.2 = 0;
+                } else {
+                    chain.2 += 1;
+                }
+                Ok((chain.0, pending))
+            }
+
+            async fn is_included(&self, tx_hash: &str) -> Result<bool, WvmDataSettlerError> {
+                let chain = self.chain.lock().unwrap();
+                Ok(chain.1[..chain.0 as usize]
+                    .iter()
+                    .any(|mined| mined == tx_hash))
+            }
+        }
+
+        let (ctx, mut handle) = test_exex_context().await.unwrap();
+        handle
+            .notifications_tx
+            .send(ExExNotification::ChainCommitted {
+                new: Arc::new(test_chain(0..4)),
+            })
+            .await
+            .unwrap();
+        drop(handle.notifications_tx);
+
+        let account = Arc::new(BatchingAccount::default());
+        let config = PipelineConfig {
+            poll_interval: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let settler = PipelinedWvmDataSettler::new(account.clone(), config)
+            .await
+            .unwrap();
+        WvmDaExEx::new(ctx, settler).run().await.unwrap();
+
+        assert!(*account.max_in_flight.lock().unwrap() > 1);
+        assert_eq!(account.chain.lock().unwrap().1.len(), 4);
+        assert!(matches!(
+            handle.events_rx.recv().await,
+            Some(ExExEvent::FinishedHeight(3))
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_wvm_da_exex_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+        let sink = MockWvmSink::new();
+
+        // Stopped after the genesis block failed to settle
+        let mut journal = SettlementJournal::open(&path).unwrap();
+        journal.mark_failed(0, [0; 32], "connection reset").unwrap();
+        drop(journal);
+
+        let (ctx, handle) = test_exex_context().await.unwrap();
+        drop(handle.notifications_tx);
+        WvmDaExEx::new(ctx, sink.clone())
+            .with_journal(SettlementJournal::open(&path).unwrap())
+            .run()
+            .await
+            .unwrap();
+
+        let tx_hash = sink.submissions()[0].tx_hash.clone();
+        let journal = SettlementJournal::open(&path).unwrap();
+        assert_eq!(
+            journal.get(0).unwrap().state,
+            SettlementState::Submitted {
+                tx_hash: tx_hash.clone()
+            }
+        );
+        drop(journal);
+
+        // Stopped again before the settlement was confirmed
+        sink.mine_blocks(1);
+        let config = ConfirmationConfig {
+            confirmations: 1,
+            ..Default::default()
+        };
+        let (ctx, handle) = test_exex_context().await.unwrap();
+        drop(handle.notifications_tx);
+        WvmDaExEx::new(ctx, sink.clone())
+            .with_journal(SettlementJournal::open(&path).unwrap())
+            .with_confirmations(ConfirmationTracker::new(sink.clone(), config))
+            .run()
+            .await
+            .unwrap();
+
+        let journal = SettlementJournal::open(&path).unwrap();
+        assert_eq!(
+            journal.get(0).unwrap().state,
+            SettlementState::Confirmed { tx_hash }
+        );
+        assert_eq!(sink.submissions().len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_wvm_da_exex_retries_failed_block() {
+        let (ctx, handle) = test_exex_context().await.unwrap();
+        let (notifications_tx, mut events_rx) = (handle.notifications_tx, handle.events_rx);
+        let sink = MockWvmSink::new();
+        sink.fail_next(MockFailure::Transport);
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(10),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let exex = WvmDaExEx::new(ctx, sink.clone())
+            .with_retry_policy(policy)
+            .run();
+
+        let node = async {
+            notifications_tx
+                .send(ExExNotification::ChainCommitted {
+                    new: Arc::new(test_chain(0..3)),
+                })
+                .await
+                .unwrap();
+            // Held back at the failed block 0 until its retry settles
+            let finished = events_rx.recv().await;
+            drop(notifications_tx);
+            finished
+        };
+
+        let (result, finished) = tokio::join!(exex, node);
+        result.unwrap();
+        assert!(matches!(finished, Some(ExExEvent::FinishedHeight(2))));
+        let submissions = sink.submissions();
+        assert_eq!(submissions.len(), 3);
+        let retried = Envelope::decode(&submissions[2].payload).unwrap();
+        assert_eq!(retried.header.meta().block_number, 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_wvm_da_exex_retries_revert_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+        let (ctx, handle) = test_exex_context().await.unwrap();
+        let (notifications_tx, mut events_rx) = (handle.notifications_tx, handle.events_rx);
+        let sink = MockWvmSink::new();
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(10),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let exex = WvmDaExEx::new(ctx, sink.clone())
+            .with_journal(SettlementJournal::open(&path).unwrap())
+            .with_retry_policy(policy)
+            .run();
+
+        let node = async {
+            notifications_tx
+                .send(ExExNotification::ChainCommitted {
+                    new: Arc::new(test_chain(0..2)),
+                })
+                .await
+                .unwrap();
+            events_rx.recv().await.unwrap();
+
+            sink.fail_next(MockFailure::Transport);
+            notifications_tx
+                .send(ExExNotification::ChainReverted {
+                    old: Arc::new(test_chain(1..2)),
+                })
+                .await
+                .unwrap();
+            while sink.submissions().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            drop(notifications_tx);
+        };
+
+        let (result, ()) = tokio::join!(exex, node);
+        result.unwrap();
+        let submissions = sink.submissions();
+        let record = RevertRecord::decode(&submissions[2].payload).unwrap();
+        assert_eq!(record.reverted.len(), 1);
+        assert_eq!(record.reverted[0].block_number, 1);
+        assert_eq!(record.reverted[0].tx_hash, submissions[1].tx_hash);
+
+        let journal = SettlementJournal::open(&path).unwrap();
+        assert!(journal.get(0).is_some());
+        assert!(journal.get(1).is_none());
+    }
+
+    #[tokio::test]
+    pub async fn test_wvm_da_exex_abandons_unreadable_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+        let mut journal = SettlementJournal::open(&path).unwrap();
+        journal.mark_failed(0, [0; 32], "connection reset").unwrap();
+        drop(journal);
+
+        // State diffs can't be read back from the node
+        let (ctx, handle) = test_exex_context().await.unwrap();
+        drop(handle.notifications_tx);
+        WvmDaExEx::new(ctx, MockWvmSink::new())
+            .with_profile(PayloadProfile::BlockWithStateDiff)
+            .with_journal(SettlementJournal::open(&path).unwrap())
+            .run()
+            .await
+            .unwrap();
+
+        let journal = SettlementJournal::open(&path).unwrap();
+        assert!(matches!(
+            journal.get(0).unwrap().state,
+            SettlementState::Abandoned { .. }
+        ));
+        assert_eq!(journal.highest_contiguous_settled(), Some(0));
+    }
+
+    #[tokio::test]
+    pub async fn test_wvm_da_exex_resume_reorged_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+        let sink = MockWvmSink::new();
+
+        // Settled a genesis block that is no longer canonical
+        let mut journal = SettlementJournal::open(&path).unwrap();
+        journal.mark_submitted(0, [9; 32], "0x09").unwrap();
+        drop(journal);
+
+        let (ctx, handle) = test_exex_context().await.unwrap();
+        drop(handle.notifications_tx);
+        WvmDaExEx::new(ctx, sink.clone())
+            .with_journal(SettlementJournal::open(&path).unwrap())
+            .run()
+            .await
+            .unwrap();
+
+        let submissions = sink.submissions();
+        assert_eq!(submissions.len(), 2);
+        let record = RevertRecord::decode(&submissions[0].payload).unwrap();
+        assert_eq!(record.reverted[0].block_hash, [9; 32]);
+        assert_eq!(record.reverted[0].tx_hash, "0x09");
+        let settled = Envelope::decode(&submissions[1].payload).unwrap();
+        assert_eq!(record.superseded_by, Some((0, settled.header.block_hash)));
+
+        let journal = SettlementJournal::open(&path).unwrap();
+        let entry = journal.get(0).unwrap();
+        assert_eq!(entry.block_hash, settled.header.block_hash);
+        assert_eq!(entry.state.tx_hash(), Some(submissions[1].tx_hash.as_str()));
+    }
+}
@@ -0,0 +1,582 @@
+use crate::batch::{BatchPayload, BatchReceipt};
+use crate::chunk::fetch_payload;
+use crate::provider::CalldataProvider;
+use crate::revert::ArchiveRecordError;
+use borsh::{BorshDeserialize, BorshSerialize};
+use eyre::{eyre, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Leading bytes of every index checkpoint.
+pub const INDEX_MAGIC: [u8; 4] = *b"WVMI";
+pub const INDEX_VERSION: u8 = 1;
+
+/// Longest chain of checkpoints `ArchiveIndex::fetch` follows.
+pub const MAX_CHECKPOINT_CHAIN: usize = 1 << 20;
+
+/// Position of a block inside a `BatchPayload`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct BatchSlot {
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// Where an archived block lives on WeaveVM.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct IndexEntry {
+    pub block_number: u64,
+    pub block_hash: [u8; 32],
+    /// Settlement transaction, or chunk manifest transaction for chunked payloads
+    pub tx_hash: String,
+    /// Set if the block was settled as part of a batch
+    pub batch: Option<BatchSlot>,
+}
+
+impl IndexEntry {
+    pub fn from_batch_receipt(receipt: &BatchReceipt, block_hash: [u8; 32]) -> Self {
+        Self {
+            block_number: receipt.block_number,
+            block_hash,
+            tx_hash: receipt.tx_hash.clone(),
+            batch: Some(BatchSlot {
+                offset: receipt.offset,
+                len: receipt.len,
+            }),
+        }
+    }
+
+    /// Fetches the settled payload of the block, reassembling chunks and slicing batches
+    pub async fn fetch<P>(&self, provider: &P) -> Result<Vec<u8>, Error>
+    where
+        P: CalldataProvider + ?Sized + Sync,
+    {
+        let payload = fetch_payload(provider, &self.tx_hash).await?;
+        if self.batch.is_none() {
+            return Ok(payload);
+        }
+        BatchPayload::decode(&payload)?
+            .block(self.block_number)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| eyre!("Block {} is not part of its batch", self.block_number))
+    }
+}
+
+/// Index entries settled since the previous checkpoint, posted to WeaveVM.
+///
+/// Checkpoints are linked through `previous`, so a consumer holding the latest
+/// checkpoint tx rebuilds the whole index with `ArchiveIndex::fetch`.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct IndexCheckpoint {
+    pub chain_id: u64,
+    pub entries: Vec<IndexEntry>,
+    /// Blocks of earlier checkpoints that were reverted since, by number and hash
+    pub removed: Vec<(u64, [u8; 32])>,
+    /// Transaction of the previous checkpoint, `None` for the first one
+    pub previous: Option<String>,
+}
+
+impl IndexCheckpoint {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buff = INDEX_MAGIC.to_vec();
+        buff.push(INDEX_VERSION);
+        // Writing into a Vec cannot fail
+        borsh::to_writer(&mut buff, self).expect("index checkpoint serializes");
+        buff
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ArchiveRecordError> {
+        if data.len() < 5 || data[..4] != INDEX_MAGIC {
+            return Err(ArchiveRecordError::UnknownRecord);
+        }
+        if data[4] != INDEX_VERSION {
+            return Err(ArchiveRecordError::UnsupportedVersion(data[4]));
+        }
+        borsh::from_slice(&data[5..]).map_err(ArchiveRecordError::InvalidIndexCheckpoint)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+enum IndexRecord {
+    Insert {
+        #[serde(rename = "blockNumber")]
+        block_number: u64,
+        #[serde(rename = "blockHash")]
+        block_hash: String,
+        #[serde(rename = "txHash")]
+        tx_hash: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        offset: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        len: Option<u32>,
+    },
+    Remove {
+        #[serde(rename = "blockNumber")]
+        block_number: u64,
+        #[serde(rename = "blockHash")]
+        block_hash: String,
+    },
+    Checkpoint {
+        #[serde(rename = "txHash")]
+        tx_hash: String,
+    },
+    /// Removal of a checkpointed block not yet published, written by `compact`
+    Removed {
+        #[serde(rename = "blockNumber")]
+        block_number: u64,
+        #[serde(rename = "blockHash")]
+        block_hash: String,
+    },
+}
+
+fn parse_hash(hash: &str) -> Option<[u8; 32]> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(hash, &mut bytes).ok()?;
+    Some(bytes)
+}
+
+/// Queryable map from block number and hash to the settlement of the block.
+///
+/// File-backed indexes append one JSON line per change, like `SettlementJournal`, and
+/// are rewritten without reorged and reverted entries by `compact`. With
+/// `checkpoint_every` set, `checkpoint_due` hands out an `IndexCheckpoint` to settle
+/// every time that many blocks were indexed since the last one.
+#[derive(Default)]
+pub struct ArchiveIndex {
+    path: Option<PathBuf>,
+    file: Option<File>,
+    entries: BTreeMap<u64, IndexEntry>,
+    by_hash: HashMap<[u8; 32], u64>,
+    /// Blocks indexed since the last checkpoint
+    unpublished: BTreeMap<u64, [u8; 32]>,
+    removed: Vec<(u64, [u8; 32])>,
+    last_checkpoint: Option<String>,
+    checkpoint_every: Option<u64>,
+}
+
+impl ArchiveIndex {
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let mut index = Self::default();
+
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                // A torn last line from a crash mid-write is skipped
+                let Ok(record) = serde_json::from_str::<IndexRecord>(&line?) else {
+                    continue;
+                };
+                index.apply(record);
+            }
+        }
+
+        index.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        index.path = Some(path.to_path_buf());
+        Ok(index)
+    }
+
+    /// Publishes a checkpoint every `blocks` indexed blocks
+    pub fn with_checkpoint_every(mut self, blocks: u64) -> Self {
+        self.checkpoint_every = Some(blocks);
+        self
+    }
+
+    fn apply(&mut self, record: IndexRecord) {
+        match record {
+            IndexRecord::Insert {
+                block_number,
+                block_hash,
+                tx_hash,
+                offset,
+                len,
+            } => {
+                let Some(block_hash) = parse_hash(&block_hash) else {
+                    return;
+                };
+                let batch = offset
+                    .zip(len)
+                    .map(|(offset, len)| BatchSlot { offset, len });
+                self.insert_entry(IndexEntry {
+                    block_number,
+                    block_hash,
+                    tx_hash,
+                    batch,
+                });
+            }
+            IndexRecord::Remove {
+                block_number,
+                block_hash,
+            } => {
+                if let Some(block_hash) = parse_hash(&block_hash) {
+                    self.remove_entry(block_number, block_hash);
+                }
+            }
+            IndexRecord::Checkpoint { tx_hash } => {
+                self.unpublished.clear();
+                self.removed.clear();
+                self.last_checkpoint = Some(tx_hash);
+            }
+            IndexRecord::Removed {
+                block_number,
+                block_hash,
+            } => {
+                if let Some(block_hash) = parse_hash(&block_hash) {
+                    self.removed.push((block_number, block_hash));
+                }
+            }
+        }
+    }
+
+    fn append(&mut self, record: &IndexRecord) -> std::io::Result<()> {
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{}", serde_json::to_string(record)?)?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn insert_record(entry: &IndexEntry) -> IndexRecord {
+        IndexRecord::Insert {
+            block_number: entry.block_number,
+            block_hash: hex::encode(entry.block_hash),
+            tx_hash: entry.tx_hash.clone(),
+            offset: entry.batch.map(|slot| slot.offset),
+            len: entry.batch.map(|slot| slot.len),
+        }
+    }
+
+    fn insert_entry(&mut self, entry: IndexEntry) {
+        let replaced = self
+            .entries
+            .get(&entry.block_number)
+            .map(|replaced| replaced.block_hash)
+            .filter(|block_hash| *block_hash != entry.block_hash);
+        if let Some(block_hash) = replaced {
+            self.remove_entry(entry.block_number, block_hash);
+        }
+        self.by_hash.insert(entry.block_hash, entry.block_number);
+        self.unpublished
+            .insert(entry.block_number, entry.block_hash);
+        self.entries.insert(entry.block_number, entry);
+    }
+
+    fn is_indexed(&self, block_number: u64, block_hash: [u8; 32]) -> bool {
+        self.entries
+            .get(&block_number)
+            .is_some_and(|entry| entry.block_hash == block_hash)
+    }
+
+    fn remove_entry(&mut self, block_number: u64, block_hash: [u8; 32]) -> bool {
+        if !self.is_indexed(block_number, block_hash) {
+            return false;
+        }
+
+        self.entries.remove(&block_number);
+        self.by_hash.remove(&block_hash);
+        if self.unpublished.remove(&block_number).is_none() {
+            self.removed.push((block_number, block_hash));
+        }
+        true
+    }
+
+    /// Indexes a settled block, replacing the entry of a reorged block at the same height
+    pub fn insert(&mut self, entry: IndexEntry) -> std::io::Result<()> {
+        self.append(&Self::insert_record(&entry))?;
+        self.insert_entry(entry);
+        Ok(())
+    }
+
+    /// Forgets a reverted block. Returns whether it was indexed
+    pub fn remove(&mut self, block_number: u64, block_hash: [u8; 32]) -> std::io::Result<bool> {
+        if !self.is_indexed(block_number, block_hash) {
+            return Ok(false);
+        }
+        self.append(&IndexRecord::Remove {
+            block_number,
+            block_hash: hex::encode(block_hash),
+        })?;
+        Ok(self.remove_entry(block_number, block_hash))
+    }
+
+    /// Rewrites the index file with the current entries only, dropping the records of
+    /// reorged and reverted blocks. Changes not checkpointed yet are kept for the next
+    /// checkpoint. Does nothing for in-memory indexes.
+    pub fn compact(&mut self) -> std::io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        // Checkpointed entries, the checkpoint, then the changes since
+        let mut records: Vec<IndexRecord> = self
+            .entries
+            .values()
+            .filter(|entry| !self.unpublished.contains_key(&entry.block_number))
+            .map(Self::insert_record)
+            .collect();
+        records.extend(
+            self.last_checkpoint
+                .iter()
+                .map(|tx_hash| IndexRecord::Checkpoint {
+                    tx_hash: tx_hash.clone(),
+                }),
+        );
+        records.extend(
+            self.unpublished
+                .keys()
+                .filter_map(|block_number| self.entries.get(block_number))
+                .map(Self::insert_record),
+        );
+        records.extend(self.removed.iter().map(|(block_number, block_hash)| {
+            IndexRecord::Removed {
+                block_number: *block_number,
+                block_hash: hex::encode(block_hash),
+            }
+        }));
+
+        let tmp_path = path.with_extension("compact");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for record in &records {
+                writeln!(writer, "{}", serde_json::to_string(record)?)?;
+            }
+            writer.into_inner()?.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, path)?;
+
+        self.file = Some(OpenOptions::new().append(true).open(path)?);
+        Ok(())
+    }
+
+    pub fn get(&self, block_number: u64) -> Option<&IndexEntry> {
+        self.entries.get(&block_number)
+    }
+
+    pub fn by_hash(&self, block_hash: &[u8; 32]) -> Option<&IndexEntry> {
+        self.by_hash
+            .get(block_hash)
+            .and_then(|block_number| self.entries.get(block_number))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &IndexEntry> {
+        self.entries.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Transaction of the last settled checkpoint
+    pub fn last_checkpoint(&self) -> Option<&str> {
+        self.last_checkpoint.as_deref()
+    }
+
+    /// Checkpoint of every change since the last one, `None` if nothing changed
+    pub fn checkpoint(&self, chain_id: u64) -> Option<IndexCheckpoint> {
+        if self.unpublished.is_empty() && self.removed.is_empty() {
+            return None;
+        }
+        Some(IndexCheckpoint {
+            chain_id,
+            entries: self
+                .unpublished
+                .keys()
+                .filter_map(|block_number| self.entries.get(block_number))
+                .cloned()
+                .collect(),
+            removed: self.removed.clone(),
+            previous: self.last_checkpoint.clone(),
+        })
+    }
+
+    /// Same as `checkpoint`, once `checkpoint_every` blocks were indexed since the last one
+    pub fn checkpoint_due(&self, chain_id: u64) -> Option<IndexCheckpoint> {
+        let every = self.checkpoint_every?;
+        if (self.unpublished.len() as u64) < every {
+            return None;
+        }
+        self.checkpoint(chain_id)
+    }
+
+    /// Records that the last `checkpoint` was settled in `tx_hash`
+    pub fn mark_checkpointed(&mut self, tx_hash: &str) -> std::io::Result<()> {
+        let record = IndexRecord::Checkpoint {
+            tx_hash: tx_hash.to_string(),
+        };
+        self.append(&record)?;
+        self.apply(record);
+        Ok(())
+    }
+
+    /// Applies a checkpoint read from WeaveVM to an in-memory index
+    pub fn apply_checkpoint(&mut self, checkpoint: &IndexCheckpoint) {
+        for (block_number, block_hash) in &checkpoint.removed {
+            self.remove_entry(*block_number, *block_hash);
+        }
+        for entry in &checkpoint.entries {
+            self.insert_entry(entry.clone());
+        }
+    }
+
+    ///
+    /// Rebuilds the index from the chain of checkpoints ending at `tx_hash`
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - source of WeaveVM calldata
+    /// * `chain_id` - chain the checkpoints must belong to
+    /// * `tx_hash` - transaction of the latest checkpoint
+    pub async fn fetch<P>(provider: &P, chain_id: u64, tx_hash: &str) -> Result<Self, Error>
+    where
+        P: CalldataProvider + ?Sized + Sync,
+    {
+        let mut checkpoints = vec![];
+        let mut visited = HashSet::new();
+        let mut next = Some(tx_hash.to_string());
+        while let Some(tx_hash) = next {
+            if !visited.insert(tx_hash.clone()) {
+                return Err(eyre!("Checkpoint {} links back to itself", tx_hash));
+            }
+            if checkpoints.len() == MAX_CHECKPOINT_CHAIN {
+                return Err(eyre!(
+                    "More than {} linked checkpoints",
+                    MAX_CHECKPOINT_CHAIN
+                ));
+            }
+
+            let checkpoint = IndexCheckpoint::decode(&fetch_payload(provider, &tx_hash).await?)?;
+            if checkpoint.chain_id != chain_id {
+                return Err(eyre!(
+                    "Checkpoint {} belongs to chain {}, expected {}",
+                    tx_hash,
+                    checkpoint.chain_id,
+                    chain_id
+                ));
+            }
+            next = checkpoint.previous.clone();
+            checkpoints.push((tx_hash, checkpoint));
+        }
+
+        let mut index = Self::in_memory();
+        for (tx_hash, checkpoint) in checkpoints.iter().rev() {
+            index.apply_checkpoint(checkpoint);
+            index.apply(IndexRecord::Checkpoint {
+                tx_hash: tx_hash.clone(),
+            });
+        }
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::{ArchiveIndex, BatchSlot, IndexCheckpoint, IndexEntry};
+    use crate::mock::MockWvmSink;
+    use crate::{CalldataProvider, WvmDataSettler};
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+
+    struct TestProvider(HashMap<String, Vec<u8>>);
+
+    #[async_trait]
+    impl CalldataProvider for TestProvider {
+        async fn get_calldata(&self, tx_hash: &str) -> eyre::Result<Vec<u8>> {
+            self.0
+                .get(tx_hash)
+                .cloned()
+                .ok_or_else(|| eyre::eyre!("unknown tx {}", tx_hash))
+        }
+    }
+
+    fn entry(block_number: u64, hash: u8) -> IndexEntry {
+        IndexEntry {
+            block_number,
+            block_hash: [hash; 32],
+            tx_hash: format!("0x{:02x}", hash),
+            batch: (hash != 3).then_some(BatchSlot {
+                offset: 41,
+                len: 10,
+            }),
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_archive_index_checkpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let mut sink = MockWvmSink::new();
+
+        let mut index = ArchiveIndex::open(&path).unwrap().with_checkpoint_every(3);
+        index.insert(entry(1, 1)).unwrap();
+        index.insert(entry(2, 2)).unwrap();
+        assert!(index.checkpoint_due(9496).is_none());
+        index.insert(entry(3, 3)).unwrap();
+
+        let first = index.checkpoint_due(9496).unwrap();
+        assert_eq!(first.entries.len(), 3);
+        let tx_hash = sink.send_wvm_calldata(first.encode()).await.unwrap();
+        index.mark_checkpointed(&tx_hash).unwrap();
+
+        // Block 3 reorged, block 4 added on the new branch
+        index.insert(entry(3, 0x33)).unwrap();
+        index.insert(entry(4, 4)).unwrap();
+        assert!(index.by_hash(&[3; 32]).is_none());
+        assert_eq!(index.by_hash(&[0x33; 32]).unwrap().block_number, 3);
+        index.insert(entry(5, 5)).unwrap();
+        index.remove(5, [5; 32]).unwrap();
+
+        // Compaction drops the replaced and removed blocks, and keeps the pending changes
+        let lines = |path: &std::path::Path| std::fs::read_to_string(path).unwrap().lines().count();
+        let before = lines(&path);
+        index.compact().unwrap();
+        assert!(lines(&path) < before);
+        let reopened = ArchiveIndex::open(&path).unwrap();
+        assert_eq!(reopened.checkpoint(9496), index.checkpoint(9496));
+        assert!(reopened.get(5).is_none());
+
+        let second = index.checkpoint(9496).unwrap();
+        assert_eq!(second.removed, vec![(3, [3; 32])]);
+        assert_eq!(second.previous.as_deref(), Some(tx_hash.as_str()));
+        assert_eq!(IndexCheckpoint::decode(&second.encode()).unwrap(), second);
+        let tx_hash = sink.send_wvm_calldata(second.encode()).await.unwrap();
+        index.mark_checkpointed(&tx_hash).unwrap();
+
+        let reopened = ArchiveIndex::open(&path).unwrap();
+        let fetched = ArchiveIndex::fetch(&sink, 9496, &tx_hash).await.unwrap();
+        for rebuilt in [&reopened, &fetched] {
+            assert_eq!(
+                rebuilt.iter().collect::<Vec<_>>(),
+                index.iter().collect::<Vec<_>>()
+            );
+            assert_eq!(rebuilt.last_checkpoint(), Some(tx_hash.as_str()));
+            assert!(rebuilt.checkpoint(9496).is_none());
+        }
+
+        assert!(ArchiveIndex::fetch(&sink, 1, &tx_hash).await.is_err());
+
+        // A checkpoint chain that loops must not be followed forever
+        let looping = |previous: &str| {
+            IndexCheckpoint {
+                chain_id: 9496,
+                entries: vec![],
+                removed: vec![],
+                previous: Some(previous.to_string()),
+            }
+            .encode()
+        };
+        let provider = TestProvider(HashMap::from([
+            ("0xaa".to_string(), looping("0xbb")),
+            ("0xbb".to_string(), looping("0xaa")),
+        ]));
+        assert!(ArchiveIndex::fetch(&provider, 9496, "0xaa").await.is_err());
+    }
+}
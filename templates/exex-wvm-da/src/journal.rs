@@ -0,0 +1,426 @@
+use crate::envelope::BlockMeta;
+use crate::retry::IdempotencyStore;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
+pub enum SettlementState {
+    /// Processed, not yet handed to WeaveVM
+    Pending,
+    Submitted {
+        #[serde(rename = "txHash")]
+        tx_hash: String,
+    },
+    Confirmed {
+        #[serde(rename = "txHash")]
+        tx_hash: String,
+    },
+    Failed {
+        reason: String,
+    },
+    /// Given up on, the block can no longer be read back to settle it
+    Abandoned {
+        reason: String,
+    },
+    /// The block left the canonical chain and its settlement was revoked on WeaveVM.
+    /// Only ever written to the log, replaying it drops the entry.
+    Reverted {
+        #[serde(rename = "txHash")]
+        tx_hash: String,
+    },
+}
+
+impl SettlementState {
+    pub fn tx_hash(&self) -> Option<&str> {
+        match self {
+            SettlementState::Submitted { tx_hash } | SettlementState::Confirmed { tx_hash } => {
+                Some(tx_hash)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.tx_hash().is_some()
+    }
+
+    /// Confirmed or abandoned, nothing is left to do for the block
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            SettlementState::Confirmed { .. } | SettlementState::Abandoned { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub block_hash: [u8; 32],
+    pub state: SettlementState,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalRecord {
+    #[serde(rename = "blockNumber")]
+    block_number: u64,
+    #[serde(rename = "blockHash")]
+    block_hash: String,
+    #[serde(flatten)]
+    state: SettlementState,
+}
+
+impl JournalRecord {
+    fn into_entry(self) -> Option<(u64, JournalEntry)> {
+        let mut block_hash = [0u8; 32];
+        hex::decode_to_slice(&self.block_hash, &mut block_hash).ok()?;
+        Some((
+            self.block_number,
+            JournalEntry {
+                block_hash,
+                state: self.state,
+            },
+        ))
+    }
+}
+
+/// On-disk write-ahead log of the settlement state of every block.
+///
+/// Every transition is appended as one JSON line and synced before returning,
+/// so replaying the file on startup yields the last known state of each block.
+pub struct SettlementJournal {
+    path: PathBuf,
+    file: File,
+    entries: BTreeMap<u64, JournalEntry>,
+}
+
+impl SettlementJournal {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = BTreeMap::new();
+
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                // A torn last line from a crash mid-write is skipped
+                let Ok(record) = serde_json::from_str::<JournalRecord>(&line?) else {
+                    continue;
+                };
+                let Some((block_number, entry)) = record.into_entry() else {
+                    continue;
+                };
+                if let SettlementState::Reverted { .. } = entry.state {
+                    Self::remove_entry(&mut entries, block_number, entry.block_hash);
+                } else {
+                    entries.insert(block_number, entry);
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            entries,
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        block_number: u64,
+        block_hash: [u8; 32],
+        state: SettlementState,
+    ) -> std::io::Result<()> {
+        let record = self.append(block_number, block_hash, state)?;
+        self.entries.insert(
+            block_number,
+            JournalEntry {
+                block_hash,
+                state: record.state,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn mark_pending(&mut self, block_number: u64, block_hash: [u8; 32]) -> std::io::Result<()> {
+        self.record(block_number, block_hash, SettlementState::Pending)
+    }
+
+    pub fn mark_submitted(
+        &mut self,
+        block_number: u64,
+        block_hash: [u8; 32],
+        tx_hash: &str,
+    ) -> std::io::Result<()> {
+        let state = SettlementState::Submitted {
+            tx_hash: tx_hash.to_string(),
+        };
+        self.record(block_number, block_hash, state)
+    }
+
+    pub fn mark_confirmed(
+        &mut self,
+        block_number: u64,
+        block_hash: [u8; 32],
+        tx_hash: &str,
+    ) -> std::io::Result<()> {
+        let state = SettlementState::Confirmed {
+            tx_hash: tx_hash.to_string(),
+        };
+        self.record(block_number, block_hash, state)
+    }
+
+    pub fn mark_failed(
+        &mut self,
+        block_number: u64,
+        block_hash: [u8; 32],
+        reason: &str,
+    ) -> std::io::Result<()> {
+        let state = SettlementState::Failed {
+            reason: reason.to_string(),
+        };
+        self.record(block_number, block_hash, state)
+    }
+
+    pub fn mark_abandoned(
+        &mut self,
+        block_number: u64,
+        block_hash: [u8; 32],
+        reason: &str,
+    ) -> std::io::Result<()> {
+        let state = SettlementState::Abandoned {
+            reason: reason.to_string(),
+        };
+        self.record(block_number, block_hash, state)
+    }
+
+    /// Drops the settlement of a reverted block, unless the height was settled again for a
+    /// block of the new chain meanwhile.
+    pub fn mark_reverted(
+        &mut self,
+        block_number: u64,
+        block_hash: [u8; 32],
+    ) -> std::io::Result<()> {
+        let Some(tx_hash) = self
+            .entries
+            .get(&block_number)
+            .filter(|entry| entry.block_hash == block_hash)
+            .and_then(|entry| entry.state.tx_hash())
+            .map(str::to_string)
+        else {
+            return Ok(());
+        };
+        self.append(
+            block_number,
+            block_hash,
+            SettlementState::Reverted { tx_hash },
+        )?;
+        Self::remove_entry(&mut self.entries, block_number, block_hash);
+        Ok(())
+    }
+
+    fn append(
+        &mut self,
+        block_number: u64,
+        block_hash: [u8; 32],
+        state: SettlementState,
+    ) -> std::io::Result<JournalRecord> {
+        let record = JournalRecord {
+            block_number,
+            block_hash: hex::encode(block_hash),
+            state,
+        };
+        let line = serde_json::to_string(&record)?;
+        writeln!(self.file, "{}", line)?;
+        self.file.sync_data()?;
+        Ok(record)
+    }
+
+    fn remove_entry(
+        entries: &mut BTreeMap<u64, JournalEntry>,
+        block_number: u64,
+        block_hash: [u8; 32],
+    ) {
+        if entries
+            .get(&block_number)
+            .is_some_and(|entry| entry.block_hash == block_hash)
+        {
+            entries.remove(&block_number);
+        }
+    }
+
+    pub fn get(&self, block_number: u64) -> Option<&JournalEntry> {
+        self.entries.get(&block_number)
+    }
+
+    /// Blocks that still need work after a restart: pending, submitted but unconfirmed, or failed.
+    pub fn unfinished(&self) -> impl Iterator<Item = (u64, &JournalEntry)> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| !entry.state.is_finished())
+            .map(|(block_number, entry)| (*block_number, entry))
+    }
+
+    /// Highest block such that it and every journaled block below it has a settlement tx or
+    /// was abandoned. This is the height that is safe to report as `FinishedHeight`.
+    pub fn highest_contiguous_settled(&self) -> Option<u64> {
+        self.highest_contiguous(|state| state.is_settled() || state.is_finished())
+    }
+
+    /// Same as `highest_contiguous_settled`, but only counts confirmed settlements.
+    pub fn highest_contiguous_confirmed(&self) -> Option<u64> {
+        self.highest_contiguous(SettlementState::is_finished)
+    }
+
+    fn highest_contiguous(&self, done: impl Fn(&SettlementState) -> bool) -> Option<u64> {
+        let mut highest = None;
+        for (block_number, entry) in &self.entries {
+            if highest.is_some_and(|h: u64| *block_number != h + 1) || !done(&entry.state) {
+                break;
+            }
+            highest = Some(*block_number);
+        }
+        highest
+    }
+
+    ///
+    /// Rewrites the journal without the confirmed and abandoned blocks below
+    /// `block_number`, keeping it from growing without bound.
+    ///
+    /// # Arguments
+    ///
+    /// * `block_number` - finished blocks strictly below this height are dropped
+    pub fn compact(&mut self, block_number: u64) -> std::io::Result<()> {
+        self.compact_where(block_number, SettlementState::is_finished)
+    }
+
+    /// Same as `compact`, but also drops blocks that were submitted and not confirmed,
+    /// for setups where nothing confirms settlements.
+    pub fn compact_settled(&mut self, block_number: u64) -> std::io::Result<()> {
+        self.compact_where(block_number, |state| {
+            state.is_settled() || state.is_finished()
+        })
+    }
+
+    fn compact_where(
+        &mut self,
+        block_number: u64,
+        done: impl Fn(&SettlementState) -> bool,
+    ) -> std::io::Result<()> {
+        self.entries
+            .retain(|number, entry| *number >= block_number || !done(&entry.state));
+
+        let tmp_path = self.path.with_extension("compact");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for (number, entry) in &self.entries {
+                let record = JournalRecord {
+                    block_number: *number,
+                    block_hash: hex::encode(entry.block_hash),
+                    state: entry.state.clone(),
+                };
+                writeln!(writer, "{}", serde_json::to_string(&record)?)?;
+            }
+            writer.into_inner()?.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl IdempotencyStore for SettlementJournal {
+    fn settled(&self, block: &BlockMeta) -> Option<String> {
+        self.entries
+            .get(&block.block_number)
+            .filter(|entry| entry.block_hash == block.block_hash)
+            .and_then(|entry| entry.state.tx_hash())
+            .map(str::to_string)
+    }
+
+    fn record_settled(&mut self, block: &BlockMeta, tx_hash: &str) -> std::io::Result<()> {
+        self.mark_submitted(block.block_number, block.block_hash, tx_hash)
+    }
+
+    fn in_flight(&self, block: &BlockMeta) -> bool {
+        self.entries.get(&block.block_number).is_some_and(|entry| {
+            entry.block_hash == block.block_hash && entry.state == SettlementState::Pending
+        })
+    }
+
+    fn record_in_flight(&mut self, block: &BlockMeta) -> std::io::Result<()> {
+        self.mark_pending(block.block_number, block.block_hash)
+    }
+
+    fn forget_tx(&mut self, tx_hash: &str) -> std::io::Result<()> {
+        let blocks: Vec<(u64, [u8; 32])> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.state.tx_hash() == Some(tx_hash))
+            .map(|(block_number, entry)| (*block_number, entry.block_hash))
+            .collect();
+        for (block_number, block_hash) in blocks {
+            self.mark_pending(block_number, block_hash)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::journal::{SettlementJournal, SettlementState};
+
+    #[test]
+    pub fn test_journal_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+
+        {
+            let mut journal = SettlementJournal::open(&path).unwrap();
+            journal.mark_pending(1, [1; 32]).unwrap();
+            journal.mark_submitted(1, [1; 32], "0x01").unwrap();
+            journal.mark_confirmed(1, [1; 32], "0x01").unwrap();
+            journal.mark_submitted(2, [2; 32], "0x02").unwrap();
+            journal.mark_pending(3, [3; 32]).unwrap();
+            journal.mark_submitted(4, [4; 32], "0x04").unwrap();
+        }
+
+        let mut journal = SettlementJournal::open(&path).unwrap();
+        assert_eq!(
+            journal.get(2).unwrap().state,
+            SettlementState::Submitted {
+                tx_hash: "0x02".to_string()
+            }
+        );
+        assert_eq!(journal.unfinished().count(), 3);
+        assert_eq!(journal.highest_contiguous_settled(), Some(2));
+        assert_eq!(journal.highest_contiguous_confirmed(), Some(1));
+
+        journal.compact(2).unwrap();
+        let journal = SettlementJournal::open(&path).unwrap();
+        assert!(journal.get(1).is_none());
+        assert_eq!(journal.highest_contiguous_settled(), Some(2));
+
+        let mut journal = SettlementJournal::open(&path).unwrap();
+        journal.compact_settled(5).unwrap();
+        assert!(journal.get(2).is_none());
+        assert!(journal.get(3).is_some());
+
+        // Pruned from the node, it no longer holds back the settled height
+        journal.mark_abandoned(3, [3; 32], "pruned").unwrap();
+        assert_eq!(journal.unfinished().count(), 0);
+        assert_eq!(journal.highest_contiguous_settled(), Some(3));
+
+        // Reverting another block's settlement at the same height is a no-op
+        journal.mark_reverted(4, [5; 32]).unwrap();
+        assert!(journal.get(4).is_some());
+        journal.mark_reverted(4, [4; 32]).unwrap();
+        assert!(journal.get(4).is_none());
+        let journal = SettlementJournal::open(&path).unwrap();
+        assert!(journal.get(4).is_none());
+        assert_eq!(journal.get(3).unwrap().block_hash, [3; 32]);
+    }
+}
@@ -1,47 +1,413 @@
-mod utils;
+pub mod backend;
+pub mod backfill;
+pub mod batch;
+pub mod blob;
+pub mod chunk;
+pub mod codec;
+pub mod config;
+pub mod confirm;
+pub mod cost;
+pub mod crypto;
+pub mod dedup;
+pub mod envelope;
+pub mod error;
+pub mod exex;
+pub mod index;
+pub mod journal;
+pub mod merkle;
+pub mod metrics;
+pub mod mock;
+pub mod nonce;
+pub mod profile;
+pub mod provider;
+pub mod retry;
+pub mod revert;
+pub mod sender;
+pub mod source;
 
-use crate::utils::to_brotli;
+pub use crate::backend::{
+    payload_key, BackendOutcome, FanOutResult, FanOutSettler, FilesystemBackend,
+    ObjectStoreBackend, SettlementBackend, SuccessPolicy, WvmBackend,
+};
+pub use crate::backfill::{
+    Backfill, BackfillCheckpoint, BackfillConfig, BackfillReport, BlockSource,
+};
+pub use crate::batch::{BatchConfig, BatchReceipt, BatchingWvmDataSettler};
+pub use crate::blob::{
+    blob_count, build_sidecar, decode_sidecar, estimate_blob_gas, BlobConfig, BlobSubmitter,
+    BlobWvmDataSettler, FeeMarket, SettlementMode,
+};
+use crate::chunk::fetch_payload;
+pub use crate::chunk::{ChainLimits, ChunkManifest, ChunkingWvmDataSettler};
+use crate::codec::default_codec;
+pub use crate::codec::{
+    BrotliCodec, Codec, CodecError, CodecId, IdentityCodec, Lz4Codec, ZstdCodec,
+};
+pub use crate::config::{GasPolicy, NonceMode, WvmSettlerConfig};
+pub use crate::confirm::{ConfirmationConfig, ConfirmationTracker, PollOutcome, SettlementEvent};
+pub use crate::cost::{
+    estimate_calldata_gas, Budget, BudgetAction, BudgetAlert, BudgetPressure,
+    BudgetedWvmDataSettler, CostEstimate, SpendTracker,
+};
+pub use crate::crypto::{
+    CipherId, CryptoError, Encryption, EnvKeyProvider, KeyFileProvider, KeyProvider,
+    StaticKeyProvider,
+};
+pub use crate::dedup::{
+    ContentIndex, DedupWvmDataSettler, FileContentIndex, MemoryContentIndex, OnChainLookup,
+};
+pub use crate::envelope::{BlockMeta, Envelope, EnvelopeError, EnvelopeHeader};
+use crate::envelope::{SerializationId, ENVELOPE_MAGIC};
+pub use crate::error::WvmDataSettlerError;
+pub use crate::exex::WvmDaExEx;
+pub use crate::index::{ArchiveIndex, BatchSlot, IndexCheckpoint, IndexEntry};
+pub use crate::journal::{SettlementJournal, SettlementState};
+pub use crate::merkle::{MerkleProof, MerkleTree};
+pub use crate::metrics::describe_metrics;
+use crate::metrics::CountingWriter;
+pub use crate::mock::{MockFailure, MockWvmSink};
+pub use crate::nonce::{NonceManager, PipelineConfig, PipelinedWvmDataSettler};
+pub use crate::profile::{DaPayload, PayloadProfile};
+pub use crate::provider::{
+    CalldataProvider, GasPriceProvider, ReceiptProvider, TxStatus, Web3CalldataProvider,
+};
+pub use crate::retry::{
+    FileIdempotencyStore, IdempotencyStore, MemoryIdempotencyStore, RetryPolicy,
+    RetryingWvmDataSettler,
+};
+pub use crate::revert::{ArchiveRecord, CanonicalView, RevertRecord};
+pub use crate::sender::{Web3Account, Web3Sender};
+pub use crate::source::ProviderBlockSource;
+use alloy_consensus::BlobTransactionSidecar;
 use async_trait::async_trait;
-use borsh::BorshSerialize;
+use borsh::{BorshDeserialize, BorshSerialize};
 use eyre::Error;
+use std::time::Instant;
 use wvm_archiver::utils::transaction::send_wvm_calldata;
 
-pub struct DefaultWvmDataSettler;
+static DEFAULT_CODEC: BrotliCodec = BrotliCodec::DEFAULT;
 
-pub enum WvmDataSettlerError {
-    InvalidSendRequest,
+/// Settles through `wvm_archiver`, configured from the environment, unless built with
+/// `from_config`.
+pub struct DefaultWvmDataSettler {
+    codec: Box<dyn Codec>,
+    encryption: Option<Encryption>,
+    sender: Option<Web3Sender>,
+}
+
+impl DefaultWvmDataSettler {
+    pub fn with_codec(codec: impl Codec + 'static) -> Self {
+        Self {
+            codec: Box::new(codec),
+            encryption: None,
+            sender: None,
+        }
+    }
+
+    ///
+    /// Creates a settler submitting to the endpoint, chain and target of `config`,
+    /// signing with its keys round-robin
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - explicit settlement settings
+    /// * `codec` - codec applied by `process_block`
+    pub fn from_config(
+        config: &WvmSettlerConfig,
+        codec: impl Codec + 'static,
+    ) -> Result<Self, WvmDataSettlerError> {
+        Ok(Self {
+            codec: Box::new(codec),
+            encryption: None,
+            sender: Some(Web3Sender::new(config)?),
+        })
+    }
+
+    /// Encrypts enveloped payloads after compression
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+}
+
+impl Default for DefaultWvmDataSettler {
+    fn default() -> Self {
+        Self::with_codec(BrotliCodec::DEFAULT)
+    }
+}
+
+fn compress_block<T: BorshSerialize + ?Sized>(
+    codec: &dyn Codec,
+    data: &T,
+) -> Result<Vec<u8>, WvmDataSettlerError> {
+    let started = Instant::now();
+    let mut raw_bytes = 0;
+    let compressed = codec
+        .compress_from(&mut |writer| {
+            let mut counting = CountingWriter {
+                inner: writer,
+                written: 0,
+            };
+            data.serialize(&mut counting)?;
+            raw_bytes = counting.written;
+            Ok(())
+        })
+        .map_err(|e| match e {
+            CodecError::Input(e) => WvmDataSettlerError::Serialization(e),
+            e => WvmDataSettlerError::Compression(e),
+        })?;
+
+    metrics::record_processed(raw_bytes, compressed.len() as u64, started.elapsed());
+    Ok(compressed)
+}
+
+/// Encrypts the compressed payload if the settler is configured to, and wraps it in an envelope
+fn seal_envelope<S: WvmDataSettler + ?Sized>(
+    settler: &S,
+    meta: BlockMeta,
+    payload: Vec<u8>,
+) -> Result<Vec<u8>, WvmDataSettlerError> {
+    let codec = settler.codec().id();
+    let envelope = match settler.encryption() {
+        Some(encryption) => {
+            let (key_id, sealed) = encryption.seal(&meta, &payload)?;
+            Envelope::seal(meta, codec, SerializationId::Borsh, sealed)
+                .with_cipher(encryption.cipher(), key_id)
+        }
+        None => Envelope::seal(meta, codec, SerializationId::Borsh, payload),
+    };
+    envelope
+        .encode()
+        .map_err(|_| WvmDataSettlerError::PayloadTooLarge {
+            size: envelope.payload.len(),
+            max: u32::MAX as usize,
+        })
+}
+
+/// Settlement started by `submit_wvm_calldata` and finished by `await_wvm_calldata`
+#[derive(Debug)]
+pub enum PendingSettlement {
+    /// Settled before `submit_wvm_calldata` returned
+    Done(Result<String, WvmDataSettlerError>),
+    /// Queued by the settler under a sequence number
+    Queued(u64),
 }
 
 #[async_trait]
 pub trait WvmDataSettler {
-    fn process_block<T: BorshSerialize + ?Sized>(&self, data: &T) -> Result<Vec<u8>, Error> {
-        let borsh_data = borsh::to_vec(&data)?;
-        let brotli_borsh = to_brotli(borsh_data);
-        Ok(brotli_borsh)
+    /// Codec applied by `process_block`, Brotli unless overridden
+    fn codec(&self) -> &dyn Codec {
+        &DEFAULT_CODEC
+    }
+
+    /// Encryption applied by `process_block_enveloped` and required to decode encrypted
+    /// envelopes, none unless overridden
+    fn encryption(&self) -> Option<&Encryption> {
+        None
+    }
+
+    /// Called before resubmitting a transaction that was stuck or underpriced.
+    /// Settlers that do not control gas pricing can ignore it.
+    fn bump_gas(&mut self, _percent: u64) {}
+
+    /// Called when `tx_hash` was dropped or reverted, before its payload is resubmitted.
+    /// Settlers remembering settled payloads must not hand out `tx_hash` again.
+    fn forget_tx(&mut self, _tx_hash: &str) -> Result<(), WvmDataSettlerError> {
+        Ok(())
+    }
+
+    /// Serializes `data` straight into the codec, without an uncompressed copy of the block
+    fn process_block<T: BorshSerialize + ?Sized>(
+        &self,
+        data: &T,
+    ) -> Result<Vec<u8>, WvmDataSettlerError> {
+        compress_block(self.codec(), data)
+    }
+
+    /// `process_block` on the blocking thread pool, for use from async tasks
+    async fn process_block_async<T>(&self, data: T) -> Result<Vec<u8>, WvmDataSettlerError>
+    where
+        Self: Sync,
+        T: BorshSerialize + Send + 'static,
+    {
+        let codec = self.codec().boxed_clone();
+        tokio::task::spawn_blocking(move || compress_block(codec.as_ref(), &data))
+            .await
+            .map_err(|e| CodecError::Compress(std::io::Error::other(e)))?
+    }
+
+    fn decode_block<T: BorshDeserialize>(&self, block_data: &[u8]) -> Result<T, Error> {
+        let borsh_data = self.codec().decompress(block_data)?;
+        let block = borsh::from_slice(&borsh_data)?;
+        Ok(block)
+    }
+
+    fn process_block_enveloped<T: BorshSerialize + ?Sized>(
+        &self,
+        data: &T,
+        meta: BlockMeta,
+    ) -> Result<Vec<u8>, WvmDataSettlerError> {
+        let payload = self.process_block(data)?;
+        seal_envelope(self, meta, payload)
+    }
+
+    /// `process_block_enveloped` compressing on the blocking thread pool
+    async fn process_block_enveloped_async<T>(
+        &self,
+        data: T,
+        meta: BlockMeta,
+    ) -> Result<Vec<u8>, WvmDataSettlerError>
+    where
+        Self: Sync,
+        T: BorshSerialize + Send + 'static,
+    {
+        let payload = self.process_block_async(data).await?;
+        seal_envelope(self, meta, payload)
+    }
+
+    fn decode_enveloped_block<T: BorshDeserialize>(
+        &self,
+        block_data: &[u8],
+    ) -> Result<(EnvelopeHeader, T), Error> {
+        let envelope = Envelope::decode(block_data)?;
+        let header = &envelope.header;
+        let payload = match header.cipher {
+            CipherId::None => envelope.payload,
+            cipher => self.encryption().ok_or(CryptoError::NotConfigured)?.open(
+                &header.meta(),
+                cipher,
+                header.key_id,
+                &envelope.payload,
+            )?,
+        };
+        let borsh_data = if header.codec == self.codec().id() {
+            self.codec().decompress(&payload)?
+        } else {
+            default_codec(header.codec).decompress(&payload)?
+        };
+        let block = borsh::from_slice(&borsh_data)?;
+        Ok((envelope.header, block))
+    }
+
+    /// Reads back a payload posted by `send_wvm_calldata`, enveloped or not
+    async fn fetch_and_decode<T, P>(&self, provider: &P, tx_hash: &str) -> Result<T, Error>
+    where
+        Self: Sync,
+        T: BorshDeserialize,
+        P: CalldataProvider + ?Sized + Sync,
+    {
+        let block_data = fetch_payload(provider, tx_hash).await?;
+        if block_data.starts_with(&ENVELOPE_MAGIC) {
+            let (_, block) = self.decode_enveloped_block(&block_data)?;
+            Ok(block)
+        } else {
+            self.decode_block(&block_data)
+        }
     }
 
     async fn send_wvm_calldata(
         &mut self,
         block_data: Vec<u8>,
     ) -> Result<String, WvmDataSettlerError> {
-        send_wvm_calldata(block_data)
+        let result = send_wvm_calldata(block_data)
             .await
-            .map_err(|_| WvmDataSettlerError::InvalidSendRequest)
+            .map_err(WvmDataSettlerError::from_rpc_error);
+        metrics::record_submission(&result);
+        result
+    }
+
+    /// Starts settling `block_data` without waiting for it, for settlers keeping several
+    /// transactions in flight. Settles right away unless overridden. Wrapping settlers pass
+    /// it on to the settler they wrap, so pipelining works below any of them but a
+    /// `FanOutSettler`.
+    async fn submit_wvm_calldata(&mut self, block_data: Vec<u8>) -> PendingSettlement {
+        PendingSettlement::Done(self.send_wvm_calldata(block_data).await)
+    }
+
+    /// Waits for a settlement started by `submit_wvm_calldata` and returns its tx hash
+    async fn await_wvm_calldata(
+        &mut self,
+        pending: PendingSettlement,
+    ) -> Result<String, WvmDataSettlerError> {
+        match pending {
+            PendingSettlement::Done(result) => result,
+            PendingSettlement::Queued(sequence) => Err(WvmDataSettlerError::Config(format!(
+                "settlement {} was not queued by this settler",
+                sequence
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl WvmDataSettler for DefaultWvmDataSettler {
+    fn codec(&self) -> &dyn Codec {
+        self.codec.as_ref()
+    }
+
+    fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+
+    fn bump_gas(&mut self, percent: u64) {
+        if let Some(sender) = &mut self.sender {
+            sender.bump_gas(percent);
+        }
+    }
+
+    fn forget_tx(&mut self, tx_hash: &str) -> Result<(), WvmDataSettlerError> {
+        if let Some(sender) = &mut self.sender {
+            sender.forget_tx(tx_hash);
+        }
+        Ok(())
+    }
+
+    async fn send_wvm_calldata(
+        &mut self,
+        block_data: Vec<u8>,
+    ) -> Result<String, WvmDataSettlerError> {
+        match &mut self.sender {
+            Some(sender) => sender.send(block_data).await,
+            None => {
+                let result = send_wvm_calldata(block_data)
+                    .await
+                    .map_err(WvmDataSettlerError::from_rpc_error);
+                metrics::record_submission(&result);
+                result
+            }
+        }
     }
 }
 
-impl WvmDataSettler for DefaultWvmDataSettler {}
+#[async_trait]
+impl BlobSubmitter for DefaultWvmDataSettler {
+    async fn send_blobs(
+        &mut self,
+        sidecar: BlobTransactionSidecar,
+        max_fee_per_blob_gas: u128,
+    ) -> Result<String, WvmDataSettlerError> {
+        match &mut self.sender {
+            Some(sender) => sender.send_blobs(sidecar, max_fee_per_blob_gas).await,
+            None => Err(WvmDataSettlerError::Config(
+                "blob transactions require a settler built with from_config".to_string(),
+            )),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::{WvmDataSettler, WvmDataSettlerError};
+    use crate::{
+        BlockMeta, CalldataProvider, CipherId, DefaultWvmDataSettler, Encryption, IdentityCodec,
+        StaticKeyProvider, WvmDataSettler, WvmDataSettlerError,
+    };
     use async_trait::async_trait;
-    use eyre::Report;
+    use eyre::{Error, Report};
     use reth::providers::Chain;
     use reth_exex::ExExNotification;
     use reth_exex_test_utils::test_exex_context;
     use std::sync::Arc;
-    use wevm_borsh::block::BorshSealedBlockWithSenders;
+    use wvm_borsh::block::BorshSealedBlockWithSenders;
 
     #[tokio::test]
     pub async fn test_wvm_da() {
@@ -94,4 +460,66 @@ mod tests {
 
         assert!(wvm_da.called);
     }
+
+    #[tokio::test]
+    pub async fn test_wvm_da_round_trip() {
+        struct TestWvmDa;
+
+        impl WvmDataSettler for TestWvmDa {}
+
+        struct TestProvider {
+            calldata: Vec<u8>,
+        }
+
+        #[async_trait]
+        impl CalldataProvider for TestProvider {
+            async fn get_calldata(&self, _tx_hash: &str) -> Result<Vec<u8>, Error> {
+                Ok(self.calldata.clone())
+            }
+        }
+
+        let wvm_da = TestWvmDa;
+        let block = (1u64, vec![7u8; 64], "block".to_string());
+        let provider = TestProvider {
+            calldata: wvm_da.process_block(&block).unwrap(),
+        };
+
+        let decoded: (u64, Vec<u8>, String) =
+            wvm_da.fetch_and_decode(&provider, "0x01").await.unwrap();
+
+        assert_eq!(decoded, block);
+        assert_eq!(
+            wvm_da.process_block_async(block.clone()).await.unwrap(),
+            provider.calldata
+        );
+
+        let meta = BlockMeta {
+            chain_id: 9496,
+            block_number: 1,
+            block_hash: [1; 32],
+        };
+        let provider = TestProvider {
+            calldata: wvm_da.process_block_enveloped(&block, meta).unwrap(),
+        };
+        let decoded: (u64, Vec<u8>, String) =
+            wvm_da.fetch_and_decode(&provider, "0x01").await.unwrap();
+        assert_eq!(decoded, block);
+
+        let encrypted = DefaultWvmDataSettler::with_codec(IdentityCodec).with_encryption(
+            Encryption::new(CipherId::Aes256Gcm, StaticKeyProvider::new(1, [5; 32])).unwrap(),
+        );
+        let meta = BlockMeta {
+            chain_id: 9496,
+            block_number: 1,
+            block_hash: [1; 32],
+        };
+        let block_data = encrypted.process_block_enveloped(&block, meta).unwrap();
+        let (header, decoded): (_, (u64, Vec<u8>, String)) =
+            encrypted.decode_enveloped_block(&block_data).unwrap();
+        assert_eq!(header.key_id, 1);
+        assert_eq!(decoded, block);
+        assert!(wvm_da
+            .decode_enveloped_block::<(u64, Vec<u8>, String)>(&block_data)
+            .is_err());
+    }
 }
@@ -0,0 +1,187 @@
+use crate::envelope::payload_checksum;
+use borsh::{BorshDeserialize, BorshSerialize};
+use sha2::{Digest, Sha256};
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Leaf committing to a block number and the sha256 of its payload.
+pub fn leaf_hash(block_number: u64, payload_hash: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(block_number.to_be_bytes());
+    hasher.update(payload_hash);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Binary Merkle tree over the blocks of a batch, in batch order.
+///
+/// Leaves and inner nodes are domain separated, and the last node of an odd level is
+/// carried up unchanged instead of being paired with itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    block_numbers: Vec<u64>,
+    payload_hashes: Vec<[u8; 32]>,
+    /// levels[0] are the leaves, the last level holds the root
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl MerkleTree {
+    pub fn new<'a>(blocks: impl IntoIterator<Item = (u64, &'a [u8])>) -> Self {
+        let (block_numbers, payload_hashes): (Vec<u64>, Vec<[u8; 32]>) = blocks
+            .into_iter()
+            .map(|(block_number, payload)| (block_number, payload_checksum(payload)))
+            .unzip();
+
+        let leaves: Vec<[u8; 32]> = block_numbers
+            .iter()
+            .zip(&payload_hashes)
+            .map(|(block_number, payload_hash)| leaf_hash(*block_number, payload_hash))
+            .collect();
+
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let next = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        Self {
+            block_numbers,
+            payload_hashes,
+            levels,
+        }
+    }
+
+    /// Root of the tree, all zeroes for an empty batch
+    pub fn root(&self) -> [u8; 32] {
+        self.levels
+            .last()
+            .and_then(|level| level.first())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Inclusion proof of `block_number`, `None` if the block is not part of the tree
+    pub fn prove(&self, block_number: u64) -> Option<MerkleProof> {
+        let index = self.block_numbers.iter().position(|n| *n == block_number)?;
+
+        let mut siblings = vec![];
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(*sibling);
+            }
+            position /= 2;
+        }
+
+        Some(MerkleProof {
+            block_number,
+            payload_hash: self.payload_hashes[index],
+            index: index as u32,
+            leaf_count: self.block_numbers.len() as u32,
+            siblings,
+        })
+    }
+}
+
+/// Proves that a block payload is part of a batch with a given Merkle root.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct MerkleProof {
+    pub block_number: u64,
+    pub payload_hash: [u8; 32],
+    /// Position of the block in its batch
+    pub index: u32,
+    pub leaf_count: u32,
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl MerkleProof {
+    /// Whether the proof connects its block to `root`
+    pub fn verify(&self, root: &[u8; 32]) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+
+        let mut hash = leaf_hash(self.block_number, &self.payload_hash);
+        let mut siblings = self.siblings.iter();
+        let mut position = self.index;
+        let mut width = self.leaf_count;
+
+        while width > 1 {
+            // The last node of an odd level has no sibling and is carried up
+            if position ^ 1 < width {
+                let Some(sibling) = siblings.next() else {
+                    return false;
+                };
+                hash = if position & 1 == 0 {
+                    node_hash(&hash, sibling)
+                } else {
+                    node_hash(sibling, &hash)
+                };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+
+        siblings.next().is_none() && hash == *root
+    }
+
+    ///
+    /// Verifies that `payload` is the archived payload of `block_number` in the batch
+    /// committed to by `root`
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - Merkle root read from the settled batch
+    /// * `block_number` - block the payload is claimed to belong to
+    /// * `payload` - block payload, as extracted from the batch
+    pub fn verify_payload(&self, root: &[u8; 32], block_number: u64, payload: &[u8]) -> bool {
+        self.block_number == block_number
+            && self.payload_hash == payload_checksum(payload)
+            && self.verify(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merkle::MerkleTree;
+
+    #[test]
+    pub fn test_merkle_proofs() {
+        for count in 1..=7u64 {
+            let blocks: Vec<(u64, Vec<u8>)> = (0..count)
+                .map(|n| (100 + n, vec![n as u8; 10 + n as usize]))
+                .collect();
+            let tree = MerkleTree::new(blocks.iter().map(|(n, data)| (*n, data.as_slice())));
+            let root = tree.root();
+
+            for (block_number, payload) in &blocks {
+                let proof = tree.prove(*block_number).unwrap();
+                assert!(proof.verify_payload(&root, *block_number, payload));
+                assert!(!proof.verify_payload(&root, *block_number, b"tampered"));
+                assert!(!proof.verify_payload(&root, block_number + 1, payload));
+
+                let mut wrong_index = proof.clone();
+                wrong_index.index = (proof.index + 1) % count as u32;
+                assert!(count == 1 || !wrong_index.verify(&root));
+            }
+            assert!(tree.prove(99).is_none());
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use crate::WvmDataSettlerError;
+use metrics::{
+    counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit,
+};
+use std::io::Write;
+use std::time::Duration;
+
+pub const BLOCKS_PROCESSED: &str = "wvm_da_blocks_processed_total";
+pub const RAW_BYTES: &str = "wvm_da_raw_bytes_total";
+pub const COMPRESSED_BYTES: &str = "wvm_da_compressed_bytes_total";
+pub const COMPRESSION_RATIO: &str = "wvm_da_compression_ratio";
+pub const PROCESS_SECONDS: &str = "wvm_da_process_seconds";
+pub const SUBMISSIONS: &str = "wvm_da_submissions_total";
+pub const SUBMISSION_FAILURES: &str = "wvm_da_submission_failures_total";
+pub const RETRIES: &str = "wvm_da_retries_total";
+pub const INCLUSION_SECONDS: &str = "wvm_da_inclusion_seconds";
+pub const CONFIRMATION_SECONDS: &str = "wvm_da_confirmation_seconds";
+pub const FEE_GWEI: &str = "wvm_da_fee_gwei";
+pub const SPENT_GWEI: &str = "wvm_da_spent_gwei";
+pub const GAS_LIMIT: &str = "wvm_da_gas_limit_total";
+pub const GAS_PRICE_GWEI: &str = "wvm_da_gas_price_gwei";
+pub const SETTLEMENT_LAG: &str = "wvm_da_settlement_lag_blocks";
+pub const JOURNAL_BACKLOG: &str = "wvm_da_journal_backlog";
+
+/// Registers the descriptions of the DA metrics with the installed recorder.
+///
+/// Metrics are emitted through the `metrics` facade, so they show up on the Prometheus
+/// endpoint of the reth node running the ExEx without further setup. `WvmDaExEx::new`
+/// calls this, other users call it once after installing their recorder.
+pub fn describe_metrics() {
+    describe_counter!(BLOCKS_PROCESSED, "Blocks serialized and compressed");
+    describe_counter!(
+        RAW_BYTES,
+        Unit::Bytes,
+        "Borsh-serialized size of processed blocks"
+    );
+    describe_counter!(
+        COMPRESSED_BYTES,
+        Unit::Bytes,
+        "Compressed size of processed blocks"
+    );
+    describe_histogram!(
+        COMPRESSION_RATIO,
+        "Serialized over compressed size of each block"
+    );
+    describe_histogram!(
+        PROCESS_SECONDS,
+        Unit::Seconds,
+        "Time spent serializing and compressing a block"
+    );
+    describe_counter!(SUBMISSIONS, "Settlement transactions broadcast");
+    describe_counter!(
+        SUBMISSION_FAILURES,
+        "Settlement transactions that failed to broadcast, by error kind"
+    );
+    describe_counter!(RETRIES, "Settlements retried or resubmitted, by reason");
+    describe_histogram!(
+        INCLUSION_SECONDS,
+        Unit::Seconds,
+        "Time from broadcast to inclusion in a WeaveVM block"
+    );
+    describe_histogram!(
+        CONFIRMATION_SECONDS,
+        Unit::Seconds,
+        "Time from broadcast to the configured number of confirmations"
+    );
+    describe_histogram!(
+        FEE_GWEI,
+        "Fee paid by each mined settlement transaction, in gwei"
+    );
+    describe_gauge!(
+        SPENT_GWEI,
+        "Total fee paid by mined settlement transactions, gas used times effective gas price \
+         plus blob fees, in gwei"
+    );
+    describe_counter!(
+        GAS_LIMIT,
+        "Gas limit of the settlement transactions broadcast, an upper bound of the gas used"
+    );
+    describe_histogram!(
+        GAS_PRICE_GWEI,
+        "Gas price of each settlement transaction broadcast, in gwei"
+    );
+    describe_gauge!(
+        SETTLEMENT_LAG,
+        "Blocks between the chain tip and the highest settled block"
+    );
+    describe_gauge!(
+        JOURNAL_BACKLOG,
+        "Journaled blocks pending, unconfirmed or failed"
+    );
+}
+
+fn error_kind(error: &WvmDataSettlerError) -> &'static str {
+    match error {
+        WvmDataSettlerError::Serialization(_) => "serialization",
+        WvmDataSettlerError::Compression(_) => "compression",
+        WvmDataSettlerError::Encryption(_) => "encryption",
+        WvmDataSettlerError::PayloadTooLarge { .. } => "payload_too_large",
+        WvmDataSettlerError::Transport(_) => "transport",
+        WvmDataSettlerError::Nonce(_) => "nonce",
+        WvmDataSettlerError::Gas(_) => "gas",
+        WvmDataSettlerError::Rejected(_) => "rejected",
+        WvmDataSettlerError::Reverted { .. } => "reverted",
+        WvmDataSettlerError::Timeout(_) => "timeout",
+        WvmDataSettlerError::Store(_) => "store",
+        WvmDataSettlerError::Config(_) => "config",
+        WvmDataSettlerError::BudgetExceeded { .. } => "budget_exceeded",
+        WvmDataSettlerError::Blob(_) => "blob",
+        WvmDataSettlerError::FanOut { .. } => "fan_out",
+    }
+}
+
+/// Counts the bytes written through it, to measure streamed serialization.
+pub(crate) struct CountingWriter<W> {
+    pub inner: W,
+    pub written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) fn record_processed(raw_bytes: u64, compressed_bytes: u64, elapsed: Duration) {
+    counter!(BLOCKS_PROCESSED).increment(1);
+    counter!(RAW_BYTES).increment(raw_bytes);
+    counter!(COMPRESSED_BYTES).increment(compressed_bytes);
+    if compressed_bytes > 0 {
+        histogram!(COMPRESSION_RATIO).record(raw_bytes as f64 / compressed_bytes as f64);
+    }
+    histogram!(PROCESS_SECONDS).record(elapsed.as_secs_f64());
+}
+
+/// Called with the outcome of every settlement transaction broadcast
+pub(crate) fn record_submission(result: &Result<String, WvmDataSettlerError>) {
+    match result {
+        Ok(_) => counter!(SUBMISSIONS).increment(1),
+        Err(error) => counter!(SUBMISSION_FAILURES, "kind" => error_kind(error)).increment(1),
+    }
+}
+
+/// Called for every settlement transaction broadcast, with or without a `ConfirmationTracker`
+pub(crate) fn record_broadcast(gas_limit: u64, gas_price_wei: u128) {
+    counter!(GAS_LIMIT).increment(gas_limit);
+    histogram!(GAS_PRICE_GWEI).record(gas_price_wei as f64 / 1e9);
+}
+
+pub(crate) fn record_retry(reason: &'static str) {
+    counter!(RETRIES, "reason" => reason).increment(1);
+}
+
+pub(crate) fn record_retried_error(error: &WvmDataSettlerError) {
+    record_retry(error_kind(error));
+}
+
+/// `fee_wei` is read from the receipt, see `TxStatus::Mined`
+pub(crate) fn record_included(since_broadcast: Duration, fee_wei: u128) {
+    histogram!(INCLUSION_SECONDS).record(since_broadcast.as_secs_f64());
+    histogram!(FEE_GWEI).record(fee_wei as f64 / 1e9);
+    // A gauge, counters only take whole numbers
+    gauge!(SPENT_GWEI).increment(fee_wei as f64 / 1e9);
+}
+
+pub(crate) fn record_finalized(since_broadcast: Duration) {
+    histogram!(CONFIRMATION_SECONDS).record(since_broadcast.as_secs_f64());
+}
+
+pub(crate) fn set_settlement_lag(blocks: u64) {
+    gauge!(SETTLEMENT_LAG).set(blocks as f64);
+}
+
+pub(crate) fn set_journal_backlog(blocks: usize) {
+    gauge!(JOURNAL_BACKLOG).set(blocks as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{
+        record_submission, BLOCKS_PROCESSED, COMPRESSED_BYTES, RAW_BYTES, SUBMISSION_FAILURES,
+    };
+    use crate::{Codec, IdentityCodec, WvmDataSettler, WvmDataSettlerError};
+    use metrics::{
+        with_local_recorder, Counter, CounterFn, Gauge, Histogram, Key, KeyName, Metadata,
+        Recorder, SharedString, Unit,
+    };
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct CountingRecorder {
+        counters: Mutex<HashMap<String, Arc<TestCounter>>>,
+    }
+
+    #[derive(Default)]
+    struct TestCounter(AtomicU64);
+
+    impl CounterFn for TestCounter {
+        fn increment(&self, value: u64) {
+            self.0.fetch_add(value, Ordering::Relaxed);
+        }
+
+        fn absolute(&self, value: u64) {
+            self.0.store(value, Ordering::Relaxed);
+        }
+    }
+
+    impl CountingRecorder {
+        fn get(&self, name: &str) -> u64 {
+            self.counters
+                .lock()
+                .unwrap()
+                .get(name)
+                .map_or(0, |counter| counter.0.load(Ordering::Relaxed))
+        }
+    }
+
+    impl Recorder for CountingRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            let counter = self
+                .counters
+                .lock()
+                .unwrap()
+                .entry(key.name().to_string())
+                .or_default()
+                .clone();
+            Counter::from_arc(counter)
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    #[test]
+    pub fn test_pipeline_metrics() {
+        struct TestWvmDa;
+
+        impl WvmDataSettler for TestWvmDa {
+            fn codec(&self) -> &dyn Codec {
+                &IdentityCodec
+            }
+        }
+
+        let recorder = CountingRecorder::default();
+        with_local_recorder(&recorder, || {
+            let block = vec![7u8; 100];
+            let compressed = TestWvmDa.process_block(&block).unwrap();
+            assert_eq!(recorder.get(BLOCKS_PROCESSED), 1);
+            // Borsh prefixes the vec with its u32 length
+            assert_eq!(recorder.get(RAW_BYTES), 104);
+            assert_eq!(recorder.get(COMPRESSED_BYTES), compressed.len() as u64);
+
+            record_submission(&Err(WvmDataSettlerError::Reverted {
+                tx_hash: "0x01".to_string(),
+            }));
+            assert_eq!(recorder.get(SUBMISSION_FAILURES), 1);
+        });
+    }
+}
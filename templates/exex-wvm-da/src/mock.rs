@@ -0,0 +1,439 @@
+use crate::blob::{decode_sidecar, estimate_blob_gas, BlobSubmitter};
+use crate::cost::{estimate_calldata_gas, TX_BASE_GAS};
+use crate::envelope::payload_checksum;
+use crate::provider::{CalldataProvider, GasPriceProvider, ReceiptProvider, TxStatus};
+use crate::{WvmDataSettler, WvmDataSettlerError};
+use alloy_consensus::BlobTransactionSidecar;
+use async_trait::async_trait;
+use eyre::eyre;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Gas price charged for mined mock transactions, 1 gwei
+pub const MOCK_GAS_PRICE: u128 = 1_000_000_000;
+
+/// Failure injected into the next submission of a `MockWvmSink`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockFailure {
+    Transport,
+    Nonce,
+    Gas,
+    Reverted,
+    Timeout,
+}
+
+impl MockFailure {
+    fn into_error(self, tx_hash: String) -> WvmDataSettlerError {
+        match self {
+            MockFailure::Transport => WvmDataSettlerError::from_rpc_error("mock: connection reset"),
+            MockFailure::Nonce => WvmDataSettlerError::from_rpc_error("mock: nonce too low"),
+            MockFailure::Gas => {
+                WvmDataSettlerError::from_rpc_error("mock: replacement transaction underpriced")
+            }
+            MockFailure::Reverted => WvmDataSettlerError::Reverted { tx_hash },
+            MockFailure::Timeout => WvmDataSettlerError::Timeout(Duration::from_secs(0)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submission {
+    pub tx_hash: String,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Successful submissions, in inclusion order
+    submissions: Vec<Submission>,
+    payloads: HashMap<String, Vec<u8>>,
+    /// tx hash -> WeaveVM block the transaction is mined in
+    included_at: HashMap<String, u64>,
+    /// tx hash -> fee of blob transactions, which is not derived from their payload
+    blob_fees: HashMap<String, u128>,
+    blob_base_fee: Option<u128>,
+    head: u64,
+    attempts: u64,
+    failures: VecDeque<MockFailure>,
+    latency: Option<Duration>,
+    reorder_window: usize,
+    dir: Option<PathBuf>,
+}
+
+/// In-memory or file-backed stand-in for WeaveVM, for testing settlers offline.
+///
+/// Clones share state, so a test can keep a handle while the settler is moved into an ExEx.
+/// Tx hashes are derived from the submission sequence number and payload, and are
+/// therefore deterministic across runs.
+#[derive(Clone, Default)]
+pub struct MockWvmSink {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockWvmSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also writes every payload to `dir` and reloads the ones already there.
+    pub fn file_backed(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut files: Vec<PathBuf> = std::fs::read_dir(&dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<_, _>>()?;
+        files.sort();
+
+        let mut state = MockState::default();
+        for file in files {
+            // <sequence>-<tx_hash>.bin
+            let Some((sequence, tx_hash)) = file
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.split_once('-'))
+                .and_then(|(sequence, tx_hash)| Some((sequence.parse::<u64>().ok()?, tx_hash)))
+            else {
+                continue;
+            };
+            let payload = std::fs::read(&file)?;
+            let tx_hash = tx_hash.to_string();
+            state.payloads.insert(tx_hash.clone(), payload.clone());
+            state.included_at.insert(tx_hash.clone(), 0);
+            state.submissions.push(Submission { tx_hash, payload });
+            state.attempts = state.attempts.max(sequence + 1);
+        }
+        state.dir = Some(dir);
+
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    pub fn fail_next(&self, failure: MockFailure) {
+        self.fail_next_n(1, failure)
+    }
+
+    pub fn fail_next_n(&self, n: usize, failure: MockFailure) {
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..n {
+            state.failures.push_back(failure);
+        }
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = Some(latency);
+    }
+
+    ///
+    /// Makes inclusion order differ from submission order: every submission is mined in a
+    /// block of its own, and overtakes up to `window` of the pending submissions before it,
+    /// deterministically. Receipts report the resulting blocks.
+    ///
+    /// # Arguments
+    ///
+    /// * `window` - maximum displacement, 0 keeps submission order
+    pub fn set_reorder_window(&self, window: usize) {
+        self.state.lock().unwrap().reorder_window = window;
+    }
+
+    /// Enables blob transactions at `fee` wei per blob gas, disabled by default
+    pub fn set_blob_base_fee(&self, fee: Option<u128>) {
+        self.state.lock().unwrap().blob_base_fee = fee;
+    }
+
+    /// Successful submissions, in inclusion order
+    pub fn submissions(&self) -> Vec<Submission> {
+        self.state.lock().unwrap().submissions.clone()
+    }
+
+    pub fn payload(&self, tx_hash: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().payloads.get(tx_hash).cloned()
+    }
+
+    /// Advances the WeaveVM head, mining every submission made before the call
+    pub fn mine_blocks(&self, n: u64) {
+        self.state.lock().unwrap().head += n;
+    }
+
+    pub fn head(&self) -> u64 {
+        self.state.lock().unwrap().head
+    }
+
+    /// Makes the node forget a submission, as if dropped from the mempool or replaced
+    pub fn drop_tx(&self, tx_hash: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.included_at.remove(tx_hash);
+        state.payloads.remove(tx_hash);
+        state.blob_fees.remove(tx_hash);
+        state
+            .submissions
+            .retain(|submission| submission.tx_hash != tx_hash);
+    }
+
+    /// Number of `send_wvm_calldata` and `send_blobs` calls, failed ones included
+    pub fn attempts(&self) -> u64 {
+        self.state.lock().unwrap().attempts
+    }
+
+    fn submit(&self, payload: Vec<u8>) -> Result<String, WvmDataSettlerError> {
+        let mut state = self.state.lock().unwrap();
+        let sequence = state.attempts;
+        state.attempts += 1;
+
+        let mut preimage = sequence.to_be_bytes().to_vec();
+        preimage.extend_from_slice(&payload);
+        let tx_hash = format!("0x{}", hex::encode(payload_checksum(&preimage)));
+
+        if let Some(failure) = state.failures.pop_front() {
+            return Err(failure.into_error(tx_hash));
+        }
+
+        if let Some(dir) = &state.dir {
+            let file = dir.join(format!("{:016}-{}.bin", sequence, tx_hash));
+            std::fs::write(file, &payload).map_err(WvmDataSettlerError::Store)?;
+        }
+
+        let len = state.submissions.len();
+        let (displacement, included_at) = match state.reorder_window {
+            0 => (0, state.head + 1),
+            window => {
+                // Pending submissions are mined one per block, in `submissions` order
+                let pending = state
+                    .submissions
+                    .iter()
+                    .rev()
+                    .take_while(|submission| state.included_at[&submission.tx_hash] > state.head)
+                    .count();
+                let displacement = (sequence as usize % (window + 1)).min(pending);
+                let MockState {
+                    submissions,
+                    included_at,
+                    ..
+                } = &mut *state;
+                for overtaken in &submissions[len - displacement..] {
+                    *included_at.get_mut(&overtaken.tx_hash).unwrap() += 1;
+                }
+                (
+                    displacement,
+                    state.head + 1 + (pending - displacement) as u64,
+                )
+            }
+        };
+        state.included_at.insert(tx_hash.clone(), included_at);
+        state.payloads.insert(tx_hash.clone(), payload.clone());
+        state.submissions.insert(
+            len - displacement,
+            Submission {
+                tx_hash: tx_hash.clone(),
+                payload,
+            },
+        );
+
+        Ok(tx_hash)
+    }
+}
+
+#[async_trait]
+impl WvmDataSettler for MockWvmSink {
+    async fn send_wvm_calldata(
+        &mut self,
+        block_data: Vec<u8>,
+    ) -> Result<String, WvmDataSettlerError> {
+        let latency = self.state.lock().unwrap().latency;
+        if let Some(latency) = latency {
+            tokio::time::sleep(latency).await;
+        }
+        self.submit(block_data)
+    }
+}
+
+/// Stores the payload decoded from the blobs, as if read back from a consensus client
+#[async_trait]
+impl BlobSubmitter for MockWvmSink {
+    async fn send_blobs(
+        &mut self,
+        sidecar: BlobTransactionSidecar,
+        _max_fee_per_blob_gas: u128,
+    ) -> Result<String, WvmDataSettlerError> {
+        let payload = decode_sidecar(&sidecar)
+            .ok_or_else(|| WvmDataSettlerError::Blob("mock: blobs do not hold a payload".into()))?;
+        let blob_gas = estimate_blob_gas(payload.len()) as u128;
+        let tx_hash = self.submit(payload)?;
+
+        let mut state = self.state.lock().unwrap();
+        let fee = TX_BASE_GAS as u128 * MOCK_GAS_PRICE
+            + blob_gas * state.blob_base_fee.unwrap_or_default();
+        state.blob_fees.insert(tx_hash.clone(), fee);
+        Ok(tx_hash)
+    }
+}
+
+#[async_trait]
+impl CalldataProvider for MockWvmSink {
+    async fn get_calldata(&self, tx_hash: &str) -> eyre::Result<Vec<u8>> {
+        self.payload(tx_hash)
+            .ok_or_else(|| eyre!("transaction {} not found", tx_hash))
+    }
+}
+
+#[async_trait]
+impl ReceiptProvider for MockWvmSink {
+    async fn block_number(&self) -> eyre::Result<u64> {
+        Ok(self.head())
+    }
+
+    async fn tx_status(&self, tx_hash: &str) -> eyre::Result<TxStatus> {
+        let state = self.state.lock().unwrap();
+        Ok(match state.included_at.get(tx_hash) {
+            Some(&block_number) if block_number <= state.head => TxStatus::Mined {
+                block_number,
+                success: true,
+                fee: match state.blob_fees.get(tx_hash) {
+                    Some(fee) => *fee,
+                    None => state.payloads.get(tx_hash).map_or(0, |payload| {
+                        estimate_calldata_gas(payload) as u128 * MOCK_GAS_PRICE
+                    }),
+                },
+            },
+            Some(_) => TxStatus::Pending,
+            None => TxStatus::Unknown,
+        })
+    }
+}
+
+#[async_trait]
+impl GasPriceProvider for MockWvmSink {
+    async fn gas_price(&self) -> eyre::Result<u128> {
+        Ok(MOCK_GAS_PRICE)
+    }
+
+    async fn blob_base_fee(&self) -> eyre::Result<Option<u128>> {
+        Ok(self.state.lock().unwrap().blob_base_fee)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::envelope::BlockMeta;
+    use crate::mock::{MockFailure, MockWvmSink};
+    use crate::provider::{ReceiptProvider, TxStatus};
+    use crate::retry::{MemoryIdempotencyStore, RetryPolicy, RetryingWvmDataSettler};
+    use crate::WvmDataSettler;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    pub async fn test_mock_round_trip_with_retries() {
+        let sink = MockWvmSink::new();
+        sink.fail_next_n(2, MockFailure::Transport);
+
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let mut settler =
+            RetryingWvmDataSettler::new(sink.clone(), policy, MemoryIdempotencyStore::default());
+
+        let block = (7u64, vec![0xabu8; 256]);
+        let meta = BlockMeta {
+            chain_id: 9496,
+            block_number: 7,
+            block_hash: [7; 32],
+        };
+        let block_data = settler.process_block_enveloped(&block, meta).unwrap();
+        let tx_hash = settler.settle(meta, block_data).await.unwrap();
+
+        assert_eq!(sink.attempts(), 3);
+        assert_eq!(sink.submissions().len(), 1);
+
+        let calldata = sink.payload(&tx_hash).unwrap();
+        let (header, decoded): (_, (u64, Vec<u8>)) =
+            settler.decode_enveloped_block(&calldata).unwrap();
+        assert_eq!(header.block_number, 7);
+        assert_eq!(decoded, block);
+
+        sink.fail_next(MockFailure::Reverted);
+        let error = settler.send_wvm_calldata(vec![1]).await.unwrap_err();
+        assert!(error.is_fatal());
+    }
+
+    #[tokio::test]
+    pub async fn test_mock_file_backed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = MockWvmSink::file_backed(dir.path()).unwrap();
+        let first = sink.send_wvm_calldata(vec![1]).await.unwrap();
+        let second = sink.send_wvm_calldata(vec![2]).await.unwrap();
+
+        let mut reopened = MockWvmSink::file_backed(dir.path()).unwrap();
+        assert_eq!(reopened.submissions(), sink.submissions());
+        assert_eq!(reopened.payload(&second), Some(vec![2]));
+        assert!(matches!(
+            reopened.tx_status(&first).await.unwrap(),
+            TxStatus::Mined { .. }
+        ));
+        // Sequence numbers continue, the same payload gets a new hash
+        let third = reopened.send_wvm_calldata(vec![1]).await.unwrap();
+        assert_ne!(third, first);
+        assert_eq!(reopened.attempts(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn test_mock_latency() {
+        let mut sink = MockWvmSink::new();
+        sink.set_latency(Duration::from_millis(250));
+
+        let started = Instant::now();
+        sink.send_wvm_calldata(vec![1]).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    pub async fn test_mock_reordered_inclusion() {
+        let mut sink = MockWvmSink::new();
+        sink.set_reorder_window(1);
+
+        let mut tx_hashes = vec![];
+        for payload in 0..3u8 {
+            tx_hashes.push(sink.send_wvm_calldata(vec![payload]).await.unwrap());
+        }
+
+        // The second submission overtakes the first one
+        let included: Vec<String> = sink
+            .submissions()
+            .into_iter()
+            .map(|submission| submission.tx_hash)
+            .collect();
+        assert_eq!(
+            included,
+            vec![
+                tx_hashes[1].clone(),
+                tx_hashes[0].clone(),
+                tx_hashes[2].clone()
+            ]
+        );
+
+        sink.mine_blocks(1);
+        assert!(matches!(
+            sink.tx_status(&tx_hashes[1]).await.unwrap(),
+            TxStatus::Mined {
+                block_number: 1,
+                ..
+            }
+        ));
+        assert_eq!(
+            sink.tx_status(&tx_hashes[0]).await.unwrap(),
+            TxStatus::Pending
+        );
+
+        sink.mine_blocks(2);
+        for (tx_hash, block) in [(&tx_hashes[0], 2), (&tx_hashes[2], 3)] {
+            assert!(matches!(
+                sink.tx_status(tx_hash).await.unwrap(),
+                TxStatus::Mined { block_number, .. } if block_number == block
+            ));
+        }
+    }
+}
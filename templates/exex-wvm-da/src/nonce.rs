@@ -0,0 +1,817 @@
+use crate::error::WvmDataSettlerError;
+use crate::metrics;
+use crate::sender::Web3Account;
+use crate::{PendingSettlement, WvmDataSettler};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use web3::types::{BlockNumber, U256};
+
+/// Local nonce bookkeeping of one account with many transactions in flight.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonceManager {
+    /// Transaction count at the latest block, every lower nonce is included
+    confirmed: u64,
+    next: u64,
+    /// Reserved nonces that were never broadcast, reused before new ones
+    gaps: BTreeSet<u64>,
+}
+
+impl NonceManager {
+    ///
+    /// # Arguments
+    ///
+    /// * `confirmed` - transaction count of the account at the latest block
+    /// * `next` - first nonce to hand out, the pending transaction count
+    pub fn new(confirmed: u64, next: u64) -> Self {
+        Self {
+            confirmed,
+            next: next.max(confirmed),
+            gaps: BTreeSet::new(),
+        }
+    }
+
+    pub fn reserve(&mut self) -> u64 {
+        if let Some(nonce) = self.gaps.pop_first() {
+            return nonce;
+        }
+        let nonce = self.next;
+        self.next += 1;
+        nonce
+    }
+
+    /// Returns a reserved nonce whose transaction was not broadcast.
+    pub fn release(&mut self, nonce: u64) {
+        if nonce < self.confirmed || nonce >= self.next {
+            return;
+        }
+        self.gaps.insert(nonce);
+        while self.next > self.confirmed && self.gaps.remove(&(self.next - 1)) {
+            self.next -= 1;
+        }
+    }
+
+    /// Takes `nonce` out of the gaps, to fill it with a transaction of its own.
+    pub fn take_gap(&mut self, nonce: u64) -> bool {
+        self.gaps.remove(&nonce)
+    }
+
+    /// Advances to the node's latest transaction count and returns the nonces it newly
+    /// includes. A count beyond the local one means the account was used elsewhere.
+    pub fn confirm(&mut self, confirmed: u64) -> Range<u64> {
+        if confirmed <= self.confirmed {
+            return self.confirmed..self.confirmed;
+        }
+        let included = self.confirmed..confirmed.min(self.next);
+        self.confirmed = confirmed;
+        self.next = self.next.max(confirmed);
+        self.gaps = self.gaps.split_off(&confirmed);
+        included
+    }
+
+    pub fn confirmed(&self) -> u64 {
+        self.confirmed
+    }
+
+    pub fn next(&self) -> u64 {
+        self.next
+    }
+
+    pub fn gaps(&self) -> impl Iterator<Item = u64> + '_ {
+        self.gaps.iter().copied()
+    }
+
+    /// Number of reserved nonces not yet included
+    pub fn in_flight(&self) -> u64 {
+        self.next - self.confirmed - self.gaps.len() as u64
+    }
+}
+
+/// Single account submitting settlement transactions with caller-chosen nonces.
+#[async_trait]
+pub trait NonceAccount: Send + Sync + 'static {
+    async fn submit(
+        &self,
+        nonce: u64,
+        data: Vec<u8>,
+        gas_bump_percent: u64,
+    ) -> Result<String, WvmDataSettlerError>;
+
+    /// Transaction counts of the account at the latest block and in the mempool
+    async fn nonces(&self) -> Result<(u64, u64), WvmDataSettlerError>;
+
+    /// Whether `tx_hash` was included, according to its receipt
+    async fn is_included(&self, tx_hash: &str) -> Result<bool, WvmDataSettlerError>;
+}
+
+#[async_trait]
+impl NonceAccount for Web3Account {
+    async fn submit(
+        &self,
+        nonce: u64,
+        data: Vec<u8>,
+        gas_bump_percent: u64,
+    ) -> Result<String, WvmDataSettlerError> {
+        Web3Account::submit(self, U256::from(nonce), data, gas_bump_percent).await
+    }
+
+    async fn nonces(&self) -> Result<(u64, u64), WvmDataSettlerError> {
+        let latest = self.transaction_count(BlockNumber::Latest).await?;
+        let pending = self.transaction_count(BlockNumber::Pending).await?;
+        Ok((latest.low_u64(), pending.low_u64()))
+    }
+
+    async fn is_included(&self, tx_hash: &str) -> Result<bool, WvmDataSettlerError> {
+        Web3Account::is_included(self, tx_hash).await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    pub max_in_flight: usize,
+    /// How often the background task polls the account nonces
+    pub poll_interval: Duration,
+    /// Age after which a broadcast transaction that is still not included is resubmitted
+    pub resubmit_after: Duration,
+    /// Gas bump applied to every resubmission
+    pub gas_bump_percent: u64,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 16,
+            poll_interval: Duration::from_secs(2),
+            resubmit_after: Duration::from_secs(60),
+            gas_bump_percent: 15,
+        }
+    }
+}
+
+struct Submission {
+    data: Vec<u8>,
+    /// Hashes of every broadcast for `nonce`, latest last
+    tx_hashes: Vec<String>,
+    /// `None` while a broadcast is in progress
+    sent_at: Option<Instant>,
+    result: Option<Result<String, WvmDataSettlerError>>,
+    /// Whether the result is kept for `next_result`
+    report: bool,
+    /// Whether `send_wvm_calldata` or `await_wvm_calldata` waits for the result
+    awaited: bool,
+}
+
+struct PipelineState {
+    nonces: NonceManager,
+    /// Submissions not yet reported, by sequence number
+    submissions: BTreeMap<u64, Submission>,
+    /// nonce -> sequence number of the submission using it
+    by_nonce: BTreeMap<u64, u64>,
+    /// Included nonces whose mined transaction is not identified yet
+    included: BTreeMap<u64, u64>,
+    gas_bump_percent: u64,
+}
+
+impl PipelineState {
+    /// The result is kept until `next_result` or `send_wvm_calldata` takes it
+    fn resolve(&mut self, sequence: u64, result: Result<String, WvmDataSettlerError>) {
+        if let Some(submission) = self.submissions.get_mut(&sequence) {
+            if submission.report || submission.awaited {
+                submission.result = Some(result);
+            } else {
+                self.submissions.remove(&sequence);
+            }
+        }
+    }
+}
+
+struct Shared<A> {
+    account: A,
+    state: Mutex<PipelineState>,
+    progress: Notify,
+}
+
+/// Settler keeping up to `max_in_flight` transactions of one account in flight.
+///
+/// Nonces are assigned locally. A background task polls the account to detect inclusion,
+/// tells from the receipts which broadcast of an included nonce was mined, resubmits
+/// transactions that were dropped from the mempool or stuck for too long, and fills nonce
+/// gaps left by failed broadcasts. `submit` returns as soon as the transaction is queued
+/// and `next_result` reports inclusion in submission order. `send_wvm_calldata` returns
+/// the hash of the broadcast that was included, so resubmissions never go unreported, and
+/// `submit_wvm_calldata` queues a transaction whose hash `await_wvm_calldata` returns.
+pub struct PipelinedWvmDataSettler<A: NonceAccount> {
+    shared: Arc<Shared<A>>,
+    config: PipelineConfig,
+    next_sequence: u64,
+    monitor: JoinHandle<()>,
+}
+
+impl<A: NonceAccount> PipelinedWvmDataSettler<A> {
+    pub async fn new(account: A, config: PipelineConfig) -> Result<Self, WvmDataSettlerError> {
+        let (latest, pending) = account.nonces().await?;
+        let shared = Arc::new(Shared {
+            account,
+            state: Mutex::new(PipelineState {
+                nonces: NonceManager::new(latest, pending),
+                submissions: BTreeMap::new(),
+                by_nonce: BTreeMap::new(),
+                included: BTreeMap::new(),
+                gas_bump_percent: 0,
+            }),
+            progress: Notify::new(),
+        });
+        let monitor = tokio::spawn(monitor(shared.clone(), config.clone()));
+
+        Ok(Self {
+            shared,
+            config,
+            next_sequence: 0,
+            monitor,
+        })
+    }
+
+    pub fn nonces(&self) -> NonceManager {
+        self.shared.state.lock().unwrap().nonces.clone()
+    }
+
+    ///
+    /// Queues `data` for settlement once fewer than `max_in_flight` transactions are
+    /// in flight, and returns its sequence number
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - calldata of the settlement transaction
+    pub async fn submit(&mut self, data: Vec<u8>) -> u64 {
+        self.enqueue(data, true).await
+    }
+
+    /// Waits for the oldest unreported submission to be included or to fail.
+    /// Returns `None` once every submission has been reported.
+    pub async fn next_result(&mut self) -> Option<(u64, Result<String, WvmDataSettlerError>)> {
+        loop {
+            let notified = self.shared.progress.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                let (&sequence, submission) = state
+                    .submissions
+                    .iter_mut()
+                    .find(|(_, submission)| submission.report)?;
+                if let Some(result) = submission.result.take() {
+                    state.submissions.remove(&sequence);
+                    return Some((sequence, result));
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Waits for the result of a submission queued with `report` unset
+    async fn wait_for(&self, sequence: u64) -> Result<String, WvmDataSettlerError> {
+        loop {
+            let notified = self.shared.progress.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                let Some(submission) = state.submissions.get_mut(&sequence) else {
+                    return Err(WvmDataSettlerError::Transport(
+                        "submission was dropped before broadcast".into(),
+                    ));
+                };
+                if submission.result.is_some() {
+                    let submission = state.submissions.remove(&sequence).unwrap();
+                    return submission.result.unwrap();
+                }
+            }
+            notified.await;
+        }
+    }
+
+    async fn enqueue(&mut self, data: Vec<u8>, report: bool) -> u64 {
+        loop {
+            let notified = self.shared.progress.notified();
+            {
+                let state = self.shared.state.lock().unwrap();
+                if state.nonces.in_flight() < self.config.max_in_flight as u64 {
+                    break;
+                }
+            }
+            notified.await;
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let (nonce, gas_bump_percent) = {
+            let mut state = self.shared.state.lock().unwrap();
+            let nonce = state.nonces.reserve();
+            state.by_nonce.insert(nonce, sequence);
+            state.submissions.insert(
+                sequence,
+                Submission {
+                    data: data.clone(),
+                    tx_hashes: vec![],
+                    sent_at: None,
+                    result: None,
+                    report,
+                    awaited: !report,
+                },
+            );
+            (nonce, state.gas_bump_percent)
+        };
+
+        tokio::spawn(broadcast(
+            self.shared.clone(),
+            sequence,
+            nonce,
+            data,
+            gas_bump_percent,
+        ));
+        sequence
+    }
+}
+
+impl<A: NonceAccount> Drop for PipelinedWvmDataSettler<A> {
+    fn drop(&mut self) {
+        self.monitor.abort();
+    }
+}
+
+#[async_trait]
+impl<A: NonceAccount> WvmDataSettler for PipelinedWvmDataSettler<A> {
+    fn bump_gas(&mut self, percent: u64) {
+        self.shared.state.lock().unwrap().gas_bump_percent += percent;
+    }
+
+    /// Stops resubmitting the payload of `tx_hash` and frees its nonce, if not included yet
+    fn forget_tx(&mut self, tx_hash: &str) -> Result<(), WvmDataSettlerError> {
+        let mut state = self.shared.state.lock().unwrap();
+        let forgotten = state
+            .by_nonce
+            .iter()
+            .map(|(nonce, sequence)| (*nonce, *sequence))
+            .find(|(_, sequence)| {
+                state.submissions.get(sequence).is_some_and(|submission| {
+                    submission.tx_hashes.iter().any(|sent| sent == tx_hash)
+                })
+            });
+        if let Some((nonce, sequence)) = forgotten {
+            state.by_nonce.remove(&nonce);
+            state.nonces.release(nonce);
+            state.resolve(
+                sequence,
+                Err(WvmDataSettlerError::Transport(
+                    format!("{} was forgotten before inclusion", tx_hash).into(),
+                )),
+            );
+        }
+        drop(state);
+        self.shared.progress.notify_waiters();
+        Ok(())
+    }
+
+    /// Returns once a broadcast of the transaction is included, with its hash.
+    async fn send_wvm_calldata(
+        &mut self,
+        block_data: Vec<u8>,
+    ) -> Result<String, WvmDataSettlerError> {
+        let sequence = self.enqueue(block_data, false).await;
+        self.wait_for(sequence).await
+    }
+
+    /// Returns once the transaction is queued, see `submit`
+    async fn submit_wvm_calldata(&mut self, block_data: Vec<u8>) -> PendingSettlement {
+        PendingSettlement::Queued(self.enqueue(block_data, false).await)
+    }
+
+    async fn await_wvm_calldata(
+        &mut self,
+        pending: PendingSettlement,
+    ) -> Result<String, WvmDataSettlerError> {
+        match pending {
+            PendingSettlement::Done(result) => result,
+            PendingSettlement::Queued(sequence) => self.wait_for(sequence).await,
+        }
+    }
+}
+
+async fn broadcast<A: NonceAccount>(
+    shared: Arc<Shared<A>>,
+    sequence: u64,
+    nonce: u64,
+    data: Vec<u8>,
+    gas_bump_percent: u64,
+) {
+    let result = shared.account.submit(nonce, data, gas_bump_percent).await;
+    {
+        let mut state = shared.state.lock().unwrap();
+        match result {
+            Ok(tx_hash) => {
+                if let Some(submission) = state.submissions.get_mut(&sequence) {
+                    submission.tx_hashes.push(tx_hash);
+                    submission.sent_at = Some(Instant::now());
+                }
+            }
+            // The nonce stays reserved, the monitor resubmits the payload unless it lands
+            Err(error) if may_be_broadcast(&error) => {
+                if let Some(submission) = state.submissions.get_mut(&sequence) {
+                    submission.sent_at = Some(Instant::now());
+                }
+            }
+            Err(error) => {
+                state.nonces.release(nonce);
+                state.by_nonce.remove(&nonce);
+                state.resolve(sequence, Err(error));
+            }
+        }
+    }
+    shared.progress.notify_waiters();
+}
+
+/// Whether the node may hold a transaction whose broadcast failed with `error`: the
+/// request was lost in transit, or the node already knows the transaction.
+fn may_be_broadcast(error: &WvmDataSettlerError) -> bool {
+    match error {
+        WvmDataSettlerError::Transport(_) | WvmDataSettlerError::Timeout(_) => true,
+        WvmDataSettlerError::Nonce(source) => {
+            source.to_string().to_lowercase().contains("already known")
+        }
+        _ => false,
+    }
+}
+
+async fn monitor<A: NonceAccount>(shared: Arc<Shared<A>>, config: PipelineConfig) {
+    let mut interval = tokio::time::interval(config.poll_interval);
+    loop {
+        interval.tick().await;
+
+        let polled_at = Instant::now();
+        let Ok((latest, pending)) = shared.account.nonces().await else {
+            continue;
+        };
+
+        let mut resubmit = vec![];
+        let mut fillers = vec![];
+        let mined: Vec<(u64, u64, Vec<String>)>;
+        let gas_bump_percent;
+        {
+            let mut state = shared.state.lock().unwrap();
+            gas_bump_percent = state.gas_bump_percent + config.gas_bump_percent;
+
+            for nonce in state.nonces.confirm(latest) {
+                if let Some(sequence) = state.by_nonce.remove(&nonce) {
+                    state.included.insert(nonce, sequence);
+                }
+            }
+            // Identified once their broadcasts returned, unless a broadcast failed
+            let PipelineState {
+                submissions,
+                included,
+                ..
+            } = &mut *state;
+            included.retain(|_, sequence| {
+                submissions
+                    .get(sequence)
+                    .is_some_and(|submission| submission.result.is_none())
+            });
+            mined = state
+                .included
+                .iter()
+                .filter_map(|(nonce, sequence)| {
+                    let submission = state.submissions.get(sequence)?;
+                    let broadcast = submission.sent_at.is_some();
+                    broadcast.then(|| (*nonce, *sequence, submission.tx_hashes.clone()))
+                })
+                .collect();
+            // Reservations the node included without us, e.g. after a restart
+            let confirmed = state.nonces.confirmed();
+            state.by_nonce = state.by_nonce.split_off(&confirmed);
+
+            // The mempool count stops at the first missing nonce: a transaction with that
+            // nonce broadcast before the poll was dropped. Anything else only if stuck.
+            let by_nonce: Vec<(u64, u64)> = state.by_nonce.iter().map(|(n, s)| (*n, *s)).collect();
+            for (nonce, sequence) in by_nonce {
+                let Some(submission) = state.submissions.get_mut(&sequence) else {
+                    continue;
+                };
+                let Some(sent_at) = submission.sent_at else {
+                    continue;
+                };
+                let dropped = nonce == pending && sent_at < polled_at;
+                if dropped || sent_at.elapsed() >= config.resubmit_after {
+                    submission.sent_at = None;
+                    resubmit.push((sequence, nonce, submission.data.clone()));
+                }
+            }
+
+            // A gap blocks every later nonce, fill it with an empty transaction
+            let gaps: Vec<u64> = state.nonces.gaps().collect();
+            for gap in gaps {
+                if state.by_nonce.range(gap..).next().is_some() && state.nonces.take_gap(gap) {
+                    fillers.push(gap);
+                }
+            }
+        }
+        shared.progress.notify_waiters();
+
+        for (nonce, sequence, tx_hashes) in mined {
+            if let Some(result) = mined_tx(&shared.account, nonce, tx_hashes).await {
+                let mut state = shared.state.lock().unwrap();
+                state.included.remove(&nonce);
+                state.resolve(sequence, result);
+            }
+        }
+        shared.progress.notify_waiters();
+
+        for (sequence, nonce, data) in resubmit {
+            metrics::record_retry("resubmit");
+            let result = shared.account.submit(nonce, data, gas_bump_percent).await;
+            let mut state = shared.state.lock().unwrap();
+            if let Some(submission) = state.submissions.get_mut(&sequence) {
+                // A failed replacement leaves the previous broadcast in place
+                if let Ok(tx_hash) = result {
+                    submission.tx_hashes.push(tx_hash);
+                }
+                submission.sent_at = Some(Instant::now());
+            }
+        }
+
+        for nonce in fillers {
+            if shared
+                .account
+                .submit(nonce, vec![], gas_bump_percent)
+                .await
+                .is_err()
+            {
+                shared.state.lock().unwrap().nonces.release(nonce);
+            }
+        }
+    }
+}
+
+/// Result of a submission whose nonce was included: the broadcast holding a receipt, or
+/// an error when the nonce was used by another transaction or by a broadcast whose
+/// response was lost. `None` to retry on the next poll when receipts can't be fetched.
+async fn mined_tx<A: NonceAccount>(
+    account: &A,
+    nonce: u64,
+    tx_hashes: Vec<String>,
+) -> Option<Result<String, WvmDataSettlerError>> {
+    if tx_hashes.is_empty() {
+        return Some(Err(WvmDataSettlerError::Transport(
+            format!(
+                "nonce {} was included, but no broadcast of it returned a hash",
+                nonce
+            )
+            .into(),
+        )));
+    }
+    for tx_hash in tx_hashes.into_iter().rev() {
+        match account.is_included(&tx_hash).await {
+            Ok(true) => return Some(Ok(tx_hash)),
+            Ok(false) => {}
+            Err(_) => return None,
+        }
+    }
+    Some(Err(WvmDataSettlerError::Nonce(
+        format!("nonce {} was used by another transaction", nonce).into(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{ChainLimits, ChunkingWvmDataSettler};
+    use crate::dedup::{DedupWvmDataSettler, MemoryContentIndex};
+    use crate::nonce::{NonceAccount, NonceManager, PipelineConfig, PipelinedWvmDataSettler};
+    use crate::retry::{MemoryIdempotencyStore, RetryPolicy, RetryingWvmDataSettler};
+    use crate::{PendingSettlement, WvmDataSettler, WvmDataSettlerError};
+    use async_trait::async_trait;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_nonce_manager_gaps() {
+        let mut nonces = NonceManager::new(5, 5);
+        assert_eq!(
+            (nonces.reserve(), nonces.reserve(), nonces.reserve()),
+            (5, 6, 7)
+        );
+
+        nonces.release(6);
+        assert_eq!(nonces.gaps().collect::<Vec<_>>(), vec![6]);
+        assert_eq!(nonces.in_flight(), 2);
+        assert_eq!(nonces.reserve(), 6);
+
+        nonces.release(7);
+        assert_eq!(nonces.next(), 7);
+
+        assert_eq!(nonces.confirm(7), 5..7);
+        assert_eq!(nonces.in_flight(), 0);
+        // Account used elsewhere
+        assert_eq!(nonces.confirm(9), 7..7);
+        assert_eq!(nonces.reserve(), 9);
+    }
+
+    /// Includes one transaction per poll and drops the first broadcast of nonce 1.
+    #[derive(Default)]
+    struct TestAccount {
+        /// nonce -> number of broadcasts
+        broadcasts: Mutex<BTreeMap<u64, u8>>,
+        mempool: Mutex<BTreeMap<u64, String>>,
+        included: Mutex<u64>,
+        /// Whether the dropped broadcast fails in transit instead of returning its hash
+        lost_response: bool,
+    }
+
+    #[async_trait]
+    impl NonceAccount for Arc<TestAccount> {
+        async fn submit(
+            &self,
+            nonce: u64,
+            _data: Vec<u8>,
+            _gas_bump_percent: u64,
+        ) -> Result<String, WvmDataSettlerError> {
+            let mut broadcasts = self.broadcasts.lock().unwrap();
+            let count = broadcasts.entry(nonce).or_default();
+            let tx_hash = format!("0x{:02x}{:02x}", nonce, count);
+            *count += 1;
+            if nonce != 1 || *count > 1 {
+                self.mempool.lock().unwrap().insert(nonce, tx_hash.clone());
+            } else if self.lost_response {
+                return Err(WvmDataSettlerError::Transport("connection reset".into()));
+            }
+            Ok(tx_hash)
+        }
+
+        async fn nonces(&self) -> Result<(u64, u64), WvmDataSettlerError> {
+            let mempool = self.mempool.lock().unwrap();
+            let mut included = self.included.lock().unwrap();
+            if mempool.contains_key(&included) {
+                *included += 1;
+            }
+            let mut pending = *included;
+            while mempool.contains_key(&pending) {
+                pending += 1;
+            }
+            Ok((*included, pending))
+        }
+
+        async fn is_included(&self, tx_hash: &str) -> Result<bool, WvmDataSettlerError> {
+            let included = *self.included.lock().unwrap();
+            let mempool = self.mempool.lock().unwrap();
+            Ok(mempool.range(..included).any(|(_, mined)| mined == tx_hash))
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_pipelined_results_in_order() {
+        let account = Arc::new(TestAccount::default());
+        let config = PipelineConfig {
+            max_in_flight: 2,
+            poll_interval: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let mut settler = PipelinedWvmDataSettler::new(account.clone(), config)
+            .await
+            .unwrap();
+
+        for block in 0..4u8 {
+            settler.submit(vec![block]).await;
+        }
+
+        let mut results = vec![];
+        while let Some((sequence, result)) = settler.next_result().await {
+            results.push((sequence, result.unwrap()));
+        }
+
+        assert_eq!(
+            results,
+            vec![
+                (0, "0x0000".to_string()),
+                (1, "0x0101".to_string()),
+                (2, "0x0200".to_string()),
+                (3, "0x0300".to_string()),
+            ]
+        );
+        assert_eq!(settler.nonces().in_flight(), 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_pipelined_reports_included_broadcast() {
+        let account = Arc::new(TestAccount::default());
+        let config = PipelineConfig {
+            poll_interval: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let mut settler = PipelinedWvmDataSettler::new(account.clone(), config)
+            .await
+            .unwrap();
+
+        assert_eq!(settler.send_wvm_calldata(vec![0]).await.unwrap(), "0x0000");
+        // The first broadcast of nonce 1 is dropped, the resubmission is what got mined
+        assert_eq!(settler.send_wvm_calldata(vec![1]).await.unwrap(), "0x0101");
+        assert_eq!(settler.nonces().in_flight(), 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_pipelined_ambiguous_broadcast_error() {
+        let account = Arc::new(TestAccount {
+            lost_response: true,
+            ..Default::default()
+        });
+        let config = PipelineConfig {
+            poll_interval: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let mut settler = PipelinedWvmDataSettler::new(account.clone(), config)
+            .await
+            .unwrap();
+
+        assert_eq!(settler.send_wvm_calldata(vec![0]).await.unwrap(), "0x0000");
+        // Nonce 1 stays reserved through the transport error and its payload is resubmitted
+        assert_eq!(settler.send_wvm_calldata(vec![1]).await.unwrap(), "0x0101");
+        assert_eq!(settler.nonces().in_flight(), 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_pipelined_under_wrappers() {
+        let account = Arc::new(TestAccount::default());
+        let config = PipelineConfig {
+            poll_interval: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let pipelined = PipelinedWvmDataSettler::new(account.clone(), config)
+            .await
+            .unwrap();
+        let mut settler = RetryingWvmDataSettler::new(
+            DedupWvmDataSettler::new(
+                ChunkingWvmDataSettler::new(pipelined, ChainLimits::default()),
+                MemoryContentIndex::default(),
+            ),
+            RetryPolicy::default(),
+            MemoryIdempotencyStore::default(),
+        );
+
+        let mut pending = vec![];
+        for block in 0..3u8 {
+            let submitted = settler.submit_wvm_calldata(vec![block]).await;
+            assert!(matches!(submitted, PendingSettlement::Queued(_)));
+            pending.push(submitted);
+        }
+        let mut tx_hashes = vec![];
+        for submitted in pending {
+            tx_hashes.push(settler.await_wvm_calldata(submitted).await.unwrap());
+        }
+        assert_eq!(tx_hashes, vec!["0x0000", "0x0101", "0x0200"]);
+
+        // Already settled, nothing is queued
+        let submitted = settler.submit_wvm_calldata(vec![0]).await;
+        assert_eq!(
+            settler.await_wvm_calldata(submitted).await.unwrap(),
+            "0x0000"
+        );
+        assert_eq!(settler.settler().hits(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_pipelined_broadcast_error() {
+        struct OversizedAccount;
+
+        #[async_trait]
+        impl NonceAccount for OversizedAccount {
+            async fn submit(
+                &self,
+                _nonce: u64,
+                data: Vec<u8>,
+                _gas_bump_percent: u64,
+            ) -> Result<String, WvmDataSettlerError> {
+                Err(WvmDataSettlerError::PayloadTooLarge {
+                    size: data.len(),
+                    max: 0,
+                })
+            }
+
+            async fn nonces(&self) -> Result<(u64, u64), WvmDataSettlerError> {
+                Ok((0, 0))
+            }
+
+            async fn is_included(&self, _tx_hash: &str) -> Result<bool, WvmDataSettlerError> {
+                Ok(false)
+            }
+        }
+
+        let mut settler = PipelinedWvmDataSettler::new(OversizedAccount, PipelineConfig::default())
+            .await
+            .unwrap();
+        let error = settler.send_wvm_calldata(vec![0; 4]).await.unwrap_err();
+        assert!(matches!(
+            error,
+            WvmDataSettlerError::PayloadTooLarge { size: 4, .. }
+        ));
+        assert_eq!(settler.nonces().in_flight(), 0);
+    }
+}
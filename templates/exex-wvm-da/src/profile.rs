@@ -0,0 +1,399 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use reth::primitives::{Receipt, SealedBlockWithSenders};
+use reth::providers::{Chain, ExecutionOutcome};
+use reth::revm::db::states::{AccountInfoRevert, RevertToSlot};
+use reth::revm::primitives::U256;
+use wvm_borsh::block::BorshSealedBlockWithSenders;
+
+/// Which parts of a block are archived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadProfile {
+    HeaderOnly,
+    #[default]
+    BlockWithSenders,
+    BlockWithReceipts,
+    /// Block plus the accounts and storage slots it changed, with their values after it
+    BlockWithStateDiff,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct DaHeader {
+    pub number: u64,
+    pub hash: [u8; 32],
+    pub parent_hash: [u8; 32],
+    pub beneficiary: [u8; 20],
+    pub state_root: [u8; 32],
+    pub transactions_root: [u8; 32],
+    pub receipts_root: [u8; 32],
+    pub timestamp: u64,
+    pub gas_limit: u64,
+    pub gas_used: u64,
+    pub base_fee_per_gas: Option<u64>,
+    pub extra_data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct DaLog {
+    pub address: [u8; 20],
+    pub topics: Vec<[u8; 32]>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct DaReceipt {
+    pub tx_type: u8,
+    pub success: bool,
+    pub cumulative_gas_used: u64,
+    pub logs: Vec<DaLog>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct DaAccountInfo {
+    pub nonce: u64,
+    pub balance: [u8; 32],
+    pub code_hash: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct DaStorageChange {
+    pub slot: [u8; 32],
+    pub value: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct DaAccountChange {
+    pub address: [u8; 20],
+    /// `None` if the account was destroyed
+    pub info: Option<DaAccountInfo>,
+    /// Whether the block cleared the whole storage of the account (selfdestruct), slots
+    /// not listed in `storage` are empty after the block
+    pub storage_wiped: bool,
+    pub storage: Vec<DaStorageChange>,
+}
+
+/// Archived block content. The Borsh enum tag records the profile it was built with,
+/// so `WvmDataSettler::decode_enveloped_block::<DaPayload>` decodes any profile.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub enum DaPayload {
+    Header(DaHeader),
+    BlockWithSenders(BorshSealedBlockWithSenders),
+    BlockWithReceipts {
+        block: BorshSealedBlockWithSenders,
+        receipts: Vec<DaReceipt>,
+    },
+    BlockWithStateDiff {
+        block: BorshSealedBlockWithSenders,
+        state_diff: Vec<DaAccountChange>,
+    },
+}
+
+impl DaPayload {
+    ///
+    /// Builds the payload of `block` for the given profile
+    ///
+    /// # Arguments
+    ///
+    /// * `profile` - selected payload profile
+    /// * `chain` - committed chain `block` belongs to, source of receipts and state
+    /// * `block` - block to archive
+    pub fn build(profile: PayloadProfile, chain: &Chain, block: &SealedBlockWithSenders) -> Self {
+        match profile {
+            PayloadProfile::HeaderOnly => DaPayload::Header(da_header(block)),
+            PayloadProfile::BlockWithSenders => {
+                DaPayload::BlockWithSenders(BorshSealedBlockWithSenders(block.clone()))
+            }
+            PayloadProfile::BlockWithReceipts => DaPayload::BlockWithReceipts {
+                block: BorshSealedBlockWithSenders(block.clone()),
+                receipts: da_receipts(chain, block.number),
+            },
+            PayloadProfile::BlockWithStateDiff => DaPayload::BlockWithStateDiff {
+                block: BorshSealedBlockWithSenders(block.clone()),
+                state_diff: da_state_diff(chain.execution_outcome(), block.number),
+            },
+        }
+    }
+
+    ///
+    /// Builds the payload of a historical block read outside of a committed chain
+    ///
+    /// # Arguments
+    ///
+    /// * `profile` - selected payload profile
+    /// * `block` - block to archive
+    /// * `receipts` - receipts of `block`, only used by `BlockWithReceipts`
+    ///
+    /// Returns `None` for `BlockWithStateDiff`, the bundle state is only known for
+    /// blocks executed by the node while the ExEx runs.
+    pub fn from_block(
+        profile: PayloadProfile,
+        block: &SealedBlockWithSenders,
+        receipts: &[Receipt],
+    ) -> Option<Self> {
+        Some(match profile {
+            PayloadProfile::HeaderOnly => DaPayload::Header(da_header(block)),
+            PayloadProfile::BlockWithSenders => {
+                DaPayload::BlockWithSenders(BorshSealedBlockWithSenders(block.clone()))
+            }
+            PayloadProfile::BlockWithReceipts => DaPayload::BlockWithReceipts {
+                block: BorshSealedBlockWithSenders(block.clone()),
+                receipts: receipts.iter().map(da_receipt).collect(),
+            },
+            PayloadProfile::BlockWithStateDiff => return None,
+        })
+    }
+
+    pub fn profile(&self) -> PayloadProfile {
+        match self {
+            DaPayload::Header(_) => PayloadProfile::HeaderOnly,
+            DaPayload::BlockWithSenders(_) => PayloadProfile::BlockWithSenders,
+            DaPayload::BlockWithReceipts { .. } => PayloadProfile::BlockWithReceipts,
+            DaPayload::BlockWithStateDiff { .. } => PayloadProfile::BlockWithStateDiff,
+        }
+    }
+}
+
+fn da_header(block: &SealedBlockWithSenders) -> DaHeader {
+    let header = &block.header;
+    DaHeader {
+        number: header.number,
+        hash: block.hash().0,
+        parent_hash: header.parent_hash.0,
+        beneficiary: header.beneficiary.0 .0,
+        state_root: header.state_root.0,
+        transactions_root: header.transactions_root.0,
+        receipts_root: header.receipts_root.0,
+        timestamp: header.timestamp,
+        gas_limit: header.gas_limit,
+        gas_used: header.gas_used,
+        base_fee_per_gas: header.base_fee_per_gas,
+        extra_data: header.extra_data.to_vec(),
+    }
+}
+
+fn da_receipt(receipt: &Receipt) -> DaReceipt {
+    DaReceipt {
+        tx_type: receipt.tx_type as u8,
+        success: receipt.success,
+        cumulative_gas_used: receipt.cumulative_gas_used,
+        logs: receipt
+            .logs
+            .iter()
+            .map(|log| DaLog {
+                address: log.address.0 .0,
+                topics: log.topics().iter().map(|topic| topic.0).collect(),
+                data: log.data.data.to_vec(),
+            })
+            .collect(),
+    }
+}
+
+fn da_receipts(chain: &Chain, block_number: u64) -> Vec<DaReceipt> {
+    chain
+        .execution_outcome()
+        .receipts_by_block(block_number)
+        .iter()
+        .flatten()
+        .map(da_receipt)
+        .collect()
+}
+
+/// Accounts and slots changed by `block_number`, read from the per-block reverts of the
+/// outcome. Their value after the block is the one the next block changing them reverts
+/// to, or the present value if no later block changes them. A later block wiping the
+/// storage records every slot it cleared, so a slot it does not list was empty.
+fn da_state_diff(outcome: &ExecutionOutcome, block_number: u64) -> Vec<DaAccountChange> {
+    let Some(index) = block_number
+        .checked_sub(outcome.first_block)
+        .map(|index| index as usize)
+    else {
+        return vec![];
+    };
+    let reverts = &outcome.bundle.reverts;
+    let Some(changed) = reverts.get(index) else {
+        return vec![];
+    };
+    let later = &reverts[index + 1..];
+
+    let mut state_diff: Vec<DaAccountChange> = changed
+        .iter()
+        .map(|(address, revert)| {
+            let later_reverts = || {
+                later.iter().filter_map(|block| {
+                    block
+                        .iter()
+                        .find(|(changed, _)| changed == address)
+                        .map(|(_, revert)| revert)
+                })
+            };
+            let present = outcome.bundle.state.get(address);
+
+            let info = later_reverts()
+                .find_map(|revert| match &revert.account {
+                    AccountInfoRevert::DoNothing => None,
+                    AccountInfoRevert::DeleteIt => Some(None),
+                    AccountInfoRevert::RevertTo(info) => Some(Some(info.clone())),
+                })
+                .unwrap_or_else(|| present.and_then(|account| account.info.clone()));
+
+            let storage = revert
+                .storage
+                .keys()
+                .map(|slot| {
+                    let value = later_reverts()
+                        .find_map(|revert| match revert.storage.get(slot) {
+                            Some(previous) => Some(RevertToSlot::to_previous_value(previous)),
+                            None => revert.wipe_storage.then_some(U256::ZERO),
+                        })
+                        .or_else(|| {
+                            present
+                                .and_then(|account| account.storage.get(slot))
+                                .map(|value| value.present_value)
+                        })
+                        .unwrap_or_default();
+                    DaStorageChange {
+                        slot: slot.to_be_bytes::<32>(),
+                        value: value.to_be_bytes::<32>(),
+                    }
+                })
+                .collect();
+
+            DaAccountChange {
+                address: address.0 .0,
+                info: info.map(|info| DaAccountInfo {
+                    nonce: info.nonce,
+                    balance: info.balance.to_be_bytes::<32>(),
+                    code_hash: info.code_hash.0,
+                }),
+                storage_wiped: revert.wipe_storage,
+                storage,
+            }
+        })
+        .collect();
+
+    // Bundle state is a hash map, sort for a deterministic payload
+    state_diff.sort_by_key(|change| change.address);
+    for change in &mut state_diff {
+        change.storage.sort_by_key(|storage| storage.slot);
+    }
+    state_diff
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profile::{da_state_diff, DaAccountChange, DaHeader, DaPayload, PayloadProfile};
+    use reth::primitives::Address;
+    use reth::providers::ExecutionOutcome;
+    use reth::revm::db::BundleState;
+    use reth::revm::primitives::{AccountInfo, U256};
+
+    #[test]
+    pub fn test_header_payload_round_trip() {
+        let header = DaHeader {
+            number: 7,
+            hash: [7; 32],
+            parent_hash: [6; 32],
+            beneficiary: [1; 20],
+            state_root: [2; 32],
+            transactions_root: [3; 32],
+            receipts_root: [4; 32],
+            timestamp: 1_700_000_000,
+            gas_limit: 30_000_000,
+            gas_used: 21_000,
+            base_fee_per_gas: Some(7),
+            extra_data: vec![],
+        };
+
+        let encoded = borsh::to_vec(&DaPayload::Header(header.clone())).unwrap();
+        let decoded: DaPayload = borsh::from_slice(&encoded).unwrap();
+        assert_eq!(decoded.profile(), PayloadProfile::HeaderOnly);
+        assert!(matches!(decoded, DaPayload::Header(h) if h == header));
+    }
+
+    #[test]
+    pub fn test_state_diff_per_block() {
+        let account = |nonce| AccountInfo {
+            nonce,
+            ..Default::default()
+        };
+        let (first, second) = (Address::repeat_byte(1), Address::repeat_byte(2));
+
+        // Block 1 creates `first`, block 2 creates `second` and bumps `first`
+        let bundle = BundleState::builder(1..=2)
+            .state_present_account_info(first, account(2))
+            .state_present_account_info(second, account(1))
+            .revert_account_info(1, first, Some(None))
+            .revert_account_info(2, second, Some(None))
+            .revert_account_info(2, first, Some(Some(account(1))))
+            .build();
+        let outcome = ExecutionOutcome {
+            bundle,
+            first_block: 1,
+            ..Default::default()
+        };
+
+        let diff = da_state_diff(&outcome, 1);
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff[0].address, first.0 .0);
+        assert_eq!(diff[0].info.as_ref().unwrap().nonce, 1);
+
+        let diff = da_state_diff(&outcome, 2);
+        assert_eq!(diff.len(), 2);
+        assert_eq!(diff[0].address, first.0 .0);
+        assert_eq!(diff[0].info.as_ref().unwrap().nonce, 2);
+        assert_eq!(diff[1].address, second.0 .0);
+    }
+
+    #[test]
+    pub fn test_state_diff_storage_wipe() {
+        let contract = Address::repeat_byte(1);
+        let slot = |value: u64| U256::from(value);
+
+        // Block 1 sets slots 1 and 2, block 2 selfdestructs and recreates the contract
+        // setting slot 3, block 3 sets slot 1 again
+        let mut bundle = BundleState::builder(1..=3)
+            .state_storage(
+                contract,
+                [
+                    (slot(1), (slot(0), slot(11))),
+                    (slot(3), (slot(0), slot(30))),
+                ]
+                .into_iter()
+                .collect(),
+            )
+            .revert_storage(1, contract, vec![(slot(1), slot(0)), (slot(2), slot(0))])
+            .revert_storage(
+                2,
+                contract,
+                vec![(slot(1), slot(10)), (slot(2), slot(20)), (slot(3), slot(0))],
+            )
+            .revert_storage(3, contract, vec![(slot(1), slot(0))])
+            .build();
+        for (_, revert) in bundle.reverts[1].iter_mut() {
+            revert.wipe_storage = true;
+        }
+        let outcome = ExecutionOutcome {
+            bundle,
+            first_block: 1,
+            ..Default::default()
+        };
+        let values = |diff: &[DaAccountChange]| -> Vec<(u8, u8)> {
+            diff[0]
+                .storage
+                .iter()
+                .map(|change| (change.slot[31], change.value[31]))
+                .collect()
+        };
+
+        let diff = da_state_diff(&outcome, 1);
+        assert!(!diff[0].storage_wiped);
+        assert_eq!(values(&diff), vec![(1, 10), (2, 20)]);
+
+        let diff = da_state_diff(&outcome, 2);
+        assert!(diff[0].storage_wiped);
+        assert_eq!(values(&diff), vec![(1, 0), (2, 0), (3, 30)]);
+
+        let diff = da_state_diff(&outcome, 3);
+        assert!(!diff[0].storage_wiped);
+        assert_eq!(values(&diff), vec![(1, 11)]);
+    }
+}
@@ -0,0 +1,148 @@
+use async_trait::async_trait;
+use eyre::{eyre, Error};
+use std::str::FromStr;
+use web3::api::{Eth, Namespace};
+use web3::transports::Http;
+use web3::types::{TransactionId, TransactionReceipt, H256, U256};
+use web3::Transport;
+
+/// Source of the calldata that was posted by `WvmDataSettler::send_wvm_calldata`.
+#[async_trait]
+pub trait CalldataProvider {
+    async fn get_calldata(&self, tx_hash: &str) -> Result<Vec<u8>, Error>;
+}
+
+/// Where a settlement transaction stands on WeaveVM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Known to the node, not mined yet
+    Pending,
+    Mined {
+        block_number: u64,
+        success: bool,
+        /// Fee paid in wei, 0 if the node does not report the gas used and price
+        fee: u128,
+    },
+    /// Unknown to the node: dropped from the mempool or replaced by another transaction
+    Unknown,
+}
+
+/// Chain state needed to follow settlement transactions until finality.
+#[async_trait]
+pub trait ReceiptProvider {
+    async fn block_number(&self) -> Result<u64, Error>;
+
+    async fn tx_status(&self, tx_hash: &str) -> Result<TxStatus, Error>;
+}
+
+/// Current gas price of WeaveVM, used to estimate settlement costs.
+#[async_trait]
+pub trait GasPriceProvider {
+    /// Gas price in wei
+    async fn gas_price(&self) -> Result<u128, Error>;
+
+    /// Blob base fee in wei, `None` on chains without blob transactions (EIP-4844)
+    async fn blob_base_fee(&self) -> Result<Option<u128>, Error> {
+        Ok(None)
+    }
+}
+
+/// Reads settlement calldata from any WeaveVM JSON-RPC endpoint.
+pub struct Web3CalldataProvider {
+    eth: Eth<Http>,
+}
+
+impl Web3CalldataProvider {
+    pub fn new(rpc_url: &str) -> Result<Self, Error> {
+        let transport = Http::new(rpc_url)?;
+        Ok(Self {
+            eth: Eth::new(transport),
+        })
+    }
+}
+
+#[async_trait]
+impl CalldataProvider for Web3CalldataProvider {
+    async fn get_calldata(&self, tx_hash: &str) -> Result<Vec<u8>, Error> {
+        let hash = H256::from_str(tx_hash.trim_start_matches("0x"))?;
+        let tx = self
+            .eth
+            .transaction(TransactionId::Hash(hash))
+            .await?
+            .ok_or_else(|| eyre!("transaction {} not found", tx_hash))?;
+        Ok(tx.input.0)
+    }
+}
+
+#[async_trait]
+impl GasPriceProvider for Web3CalldataProvider {
+    async fn gas_price(&self) -> Result<u128, Error> {
+        Ok(self.eth.gas_price().await?.as_u128())
+    }
+
+    async fn blob_base_fee(&self) -> Result<Option<u128>, Error> {
+        match self
+            .eth
+            .transport()
+            .execute("eth_blobBaseFee", vec![])
+            .await
+        {
+            Ok(fee) => Ok(Some(serde_json::from_value::<U256>(fee)?.as_u128())),
+            // Nodes without EIP-4844 support reject the method
+            Err(web3::Error::Rpc(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl ReceiptProvider for Web3CalldataProvider {
+    async fn block_number(&self) -> Result<u64, Error> {
+        Ok(self.eth.block_number().await?.as_u64())
+    }
+
+    async fn tx_status(&self, tx_hash: &str) -> Result<TxStatus, Error> {
+        let hash = H256::from_str(tx_hash.trim_start_matches("0x"))?;
+        // Read raw, web3's receipt type has no blob gas fields (EIP-4844)
+        let raw_receipt = self
+            .eth
+            .transport()
+            .execute(
+                "eth_getTransactionReceipt",
+                vec![serde_json::to_value(hash)?],
+            )
+            .await?;
+        let blob_field = |name: &str| {
+            raw_receipt
+                .get(name)
+                .cloned()
+                .map(serde_json::from_value::<U256>)
+                .transpose()
+        };
+        let blob_fee = match (blob_field("blobGasUsed")?, blob_field("blobGasPrice")?) {
+            (Some(blob_gas_used), Some(blob_gas_price)) => {
+                (blob_gas_used * blob_gas_price).as_u128()
+            }
+            _ => 0,
+        };
+
+        if let Some(receipt) = serde_json::from_value::<Option<TransactionReceipt>>(raw_receipt)? {
+            if let Some(block_number) = receipt.block_number {
+                let fee = receipt
+                    .gas_used
+                    .zip(receipt.effective_gas_price)
+                    .map_or(0, |(gas_used, gas_price)| (gas_used * gas_price).as_u128())
+                    + blob_fee;
+                return Ok(TxStatus::Mined {
+                    block_number: block_number.as_u64(),
+                    success: receipt.status.is_some_and(|status| status.as_u64() == 1),
+                    fee,
+                });
+            }
+        }
+        match self.eth.transaction(TransactionId::Hash(hash)).await? {
+            Some(_) => Ok(TxStatus::Pending),
+            None => Ok(TxStatus::Unknown),
+        }
+    }
+}
@@ -0,0 +1,582 @@
+use crate::codec::Codec;
+use crate::crypto::Encryption;
+use crate::dedup::OnChainLookup;
+use crate::envelope::{payload_checksum, BlockMeta, Envelope};
+use crate::metrics;
+use crate::{PendingSettlement, WvmDataSettler, WvmDataSettlerError};
+use async_trait::async_trait;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Time budget for settling one payload, backoff included
+    pub deadline: Option<Duration>,
+    /// Fraction of each backoff that is randomized, 0.0 to 1.0
+    pub jitter: f64,
+    /// Gas price increase applied before resubmitting a stuck or underpriced transaction
+    pub gas_bump_percent: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            deadline: Some(Duration::from_secs(300)),
+            jitter: 0.2,
+            gas_bump_percent: 15,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Backoff to wait after the given (1-based) failed attempt
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return backoff;
+        }
+        let factor = rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+        backoff.mul_f64(factor)
+    }
+}
+
+/// Remembers which blocks were already settled so a restart never settles a block twice.
+pub trait IdempotencyStore: Send {
+    fn settled(&self, block: &BlockMeta) -> Option<String>;
+
+    fn record_settled(&mut self, block: &BlockMeta, tx_hash: &str) -> std::io::Result<()>;
+
+    /// Whether a payload of `block` was handed to the settler without a recorded outcome,
+    /// so it may already be on chain
+    fn in_flight(&self, block: &BlockMeta) -> bool;
+
+    /// Recorded before the payload of `block` is sent
+    fn record_in_flight(&mut self, block: &BlockMeta) -> std::io::Result<()>;
+
+    /// Puts the blocks settled by `tx_hash` back in flight, after it was dropped
+    fn forget_tx(&mut self, tx_hash: &str) -> std::io::Result<()>;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryIdempotencyStore {
+    settled: HashMap<BlockMeta, String>,
+    in_flight: HashSet<BlockMeta>,
+}
+
+impl IdempotencyStore for MemoryIdempotencyStore {
+    fn settled(&self, block: &BlockMeta) -> Option<String> {
+        self.settled.get(block).cloned()
+    }
+
+    fn record_settled(&mut self, block: &BlockMeta, tx_hash: &str) -> std::io::Result<()> {
+        self.in_flight.remove(block);
+        self.settled.insert(*block, tx_hash.to_string());
+        Ok(())
+    }
+
+    fn in_flight(&self, block: &BlockMeta) -> bool {
+        self.in_flight.contains(block)
+    }
+
+    fn record_in_flight(&mut self, block: &BlockMeta) -> std::io::Result<()> {
+        self.in_flight.insert(*block);
+        Ok(())
+    }
+
+    fn forget_tx(&mut self, tx_hash: &str) -> std::io::Result<()> {
+        let in_flight = &mut self.in_flight;
+        self.settled.retain(|block, settled_tx| {
+            let forget = settled_tx == tx_hash;
+            if forget {
+                in_flight.insert(*block);
+            }
+            !forget
+        });
+        Ok(())
+    }
+}
+
+/// Append-only file of `chain_id block_number block_hash tx_hash` lines, a `-` tx hash
+/// marks the block as in flight.
+pub struct FileIdempotencyStore {
+    path: PathBuf,
+    settled: HashMap<BlockMeta, String>,
+    in_flight: HashSet<BlockMeta>,
+}
+
+impl FileIdempotencyStore {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut settled = HashMap::new();
+        let mut in_flight = HashSet::new();
+
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if let [chain_id, block_number, block_hash, tx_hash] = fields[..] {
+                    let block = parse_block_meta(chain_id, block_number, block_hash)?;
+                    if tx_hash == "-" {
+                        settled.remove(&block);
+                        in_flight.insert(block);
+                    } else {
+                        in_flight.remove(&block);
+                        settled.insert(block, tx_hash.to_string());
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            path,
+            settled,
+            in_flight,
+        })
+    }
+
+    fn append(&self, block: &BlockMeta, tx_hash: &str) -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(
+            file,
+            "{} {} {} {}",
+            block.chain_id,
+            block.block_number,
+            hex::encode(block.block_hash),
+            tx_hash
+        )?;
+        file.sync_data()
+    }
+}
+
+fn parse_block_meta(
+    chain_id: &str,
+    block_number: &str,
+    block_hash: &str,
+) -> std::io::Result<BlockMeta> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(block_hash, &mut hash).map_err(|e| invalid(e.to_string()))?;
+
+    Ok(BlockMeta {
+        chain_id: chain_id.parse().map_err(|e| invalid(format!("{:?}", e)))?,
+        block_number: block_number
+            .parse()
+            .map_err(|e| invalid(format!("{:?}", e)))?,
+        block_hash: hash,
+    })
+}
+
+impl IdempotencyStore for FileIdempotencyStore {
+    fn settled(&self, block: &BlockMeta) -> Option<String> {
+        self.settled.get(block).cloned()
+    }
+
+    fn record_settled(&mut self, block: &BlockMeta, tx_hash: &str) -> std::io::Result<()> {
+        self.append(block, tx_hash)?;
+        self.in_flight.remove(block);
+        self.settled.insert(*block, tx_hash.to_string());
+        Ok(())
+    }
+
+    fn in_flight(&self, block: &BlockMeta) -> bool {
+        self.in_flight.contains(block)
+    }
+
+    fn record_in_flight(&mut self, block: &BlockMeta) -> std::io::Result<()> {
+        self.append(block, "-")?;
+        self.in_flight.insert(*block);
+        Ok(())
+    }
+
+    fn forget_tx(&mut self, tx_hash: &str) -> std::io::Result<()> {
+        let blocks: Vec<BlockMeta> = self
+            .settled
+            .iter()
+            .filter(|(_, settled_tx)| *settled_tx == tx_hash)
+            .map(|(block, _)| *block)
+            .collect();
+        for block in blocks {
+            self.record_in_flight(&block)?;
+            self.settled.remove(&block);
+        }
+        Ok(())
+    }
+}
+
+/// Wraps a settler with backoff, gas bumping and idempotent resubmission.
+///
+/// A transport error or timeout doesn't tell whether the node accepted the transaction.
+/// With an `OnChainLookup`, the payload is looked up before it is resent after such an
+/// error, and before a block left in flight by an earlier run is sent again. Without one,
+/// a settler using node-managed nonces may settle the block twice.
+pub struct RetryingWvmDataSettler<S, I = MemoryIdempotencyStore> {
+    settler: S,
+    policy: RetryPolicy,
+    store: I,
+    lookup: Option<Box<dyn OnChainLookup + Send + Sync>>,
+    /// Payloads queued in the wrapped settler, resent here if they fail
+    queued: HashMap<u64, (Option<BlockMeta>, Vec<u8>)>,
+}
+
+impl<S, I> RetryingWvmDataSettler<S, I>
+where
+    S: WvmDataSettler + Send,
+    I: IdempotencyStore,
+{
+    pub fn new(settler: S, policy: RetryPolicy, store: I) -> Self {
+        Self {
+            settler,
+            policy,
+            store,
+            lookup: None,
+            queued: HashMap::new(),
+        }
+    }
+
+    /// Consulted before resending a payload that may already be on chain
+    pub fn with_lookup(mut self, lookup: impl OnChainLookup + Send + Sync + 'static) -> Self {
+        self.lookup = Some(Box::new(lookup));
+        self
+    }
+
+    pub fn settler(&self) -> &S {
+        &self.settler
+    }
+
+    pub fn store(&self) -> &I {
+        &self.store
+    }
+
+    ///
+    /// Settles `block_data` unless `block` was already settled, in which case the
+    /// recorded tx hash is returned without resubmitting.
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - block the payload belongs to, used as idempotency key
+    /// * `block_data` - processed block payload
+    pub async fn settle(
+        &mut self,
+        block: BlockMeta,
+        block_data: Vec<u8>,
+    ) -> Result<String, WvmDataSettlerError> {
+        if let Some(tx_hash) = self.store.settled(&block) {
+            return Ok(tx_hash);
+        }
+
+        let posted = match self.store.in_flight(&block) {
+            true => self.find_posted(&block_data).await,
+            false => {
+                self.store
+                    .record_in_flight(&block)
+                    .map_err(WvmDataSettlerError::Store)?;
+                None
+            }
+        };
+        let tx_hash = match posted {
+            Some(tx_hash) => tx_hash,
+            None => self.send_with_retry(block_data).await?,
+        };
+        self.store
+            .record_settled(&block, &tx_hash)
+            .map_err(WvmDataSettlerError::Store)?;
+        Ok(tx_hash)
+    }
+
+    /// Settlement tx of `block_data` according to the lookup, if any
+    async fn find_posted(&self, block_data: &[u8]) -> Option<String> {
+        self.lookup
+            .as_ref()?
+            .find(&payload_checksum(block_data))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Returns the last error once attempts or the deadline are exhausted.
+    async fn send_with_retry(
+        &mut self,
+        block_data: Vec<u8>,
+    ) -> Result<String, WvmDataSettlerError> {
+        let started = Instant::now();
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let error = match self.settler.send_wvm_calldata(block_data.clone()).await {
+                Ok(tx_hash) => return Ok(tx_hash),
+                Err(error) => error,
+            };
+
+            if !error.is_retryable() || attempt >= self.policy.max_attempts {
+                return Err(error);
+            }
+
+            // The node may have accepted the transaction before the error
+            if matches!(
+                error,
+                WvmDataSettlerError::Transport(_) | WvmDataSettlerError::Timeout(_)
+            ) {
+                if let Some(tx_hash) = self.find_posted(&block_data).await {
+                    return Ok(tx_hash);
+                }
+            }
+
+            let backoff = self.policy.backoff(attempt);
+            if let Some(deadline) = self.policy.deadline {
+                if started.elapsed() + backoff > deadline {
+                    return Err(error);
+                }
+            }
+
+            metrics::record_retried_error(&error);
+            if matches!(
+                error,
+                WvmDataSettlerError::Gas(_) | WvmDataSettlerError::Timeout(_)
+            ) {
+                self.settler.bump_gas(self.policy.gas_bump_percent);
+            }
+
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    ///
+    /// Finishes a settlement the wrapped settler queued: a retryable failure is looked up
+    /// and resent with the usual backoff, and the tx hash is recorded for `block`.
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - block of an enveloped payload, recorded as settled
+    /// * `block_data` - payload that was queued
+    /// * `result` - outcome of the queued settlement
+    async fn finish_queued(
+        &mut self,
+        block: Option<BlockMeta>,
+        block_data: Vec<u8>,
+        result: Result<String, WvmDataSettlerError>,
+    ) -> Result<String, WvmDataSettlerError> {
+        let tx_hash = match result {
+            Ok(tx_hash) => tx_hash,
+            Err(error) if error.is_retryable() => {
+                metrics::record_retried_error(&error);
+                let posted = match error {
+                    WvmDataSettlerError::Transport(_) | WvmDataSettlerError::Timeout(_) => {
+                        self.find_posted(&block_data).await
+                    }
+                    _ => None,
+                };
+                match posted {
+                    Some(tx_hash) => tx_hash,
+                    None => self.send_with_retry(block_data).await?,
+                }
+            }
+            Err(error) => return Err(error),
+        };
+        if let Some(block) = block {
+            self.store
+                .record_settled(&block, &tx_hash)
+                .map_err(WvmDataSettlerError::Store)?;
+        }
+        Ok(tx_hash)
+    }
+}
+
+#[async_trait]
+impl<S, I> WvmDataSettler for RetryingWvmDataSettler<S, I>
+where
+    S: WvmDataSettler + Send + Sync,
+    I: IdempotencyStore + Sync,
+{
+    fn codec(&self) -> &dyn Codec {
+        self.settler.codec()
+    }
+
+    fn encryption(&self) -> Option<&Encryption> {
+        self.settler.encryption()
+    }
+
+    fn bump_gas(&mut self, percent: u64) {
+        self.settler.bump_gas(percent)
+    }
+
+    fn forget_tx(&mut self, tx_hash: &str) -> Result<(), WvmDataSettlerError> {
+        self.store
+            .forget_tx(tx_hash)
+            .map_err(WvmDataSettlerError::Store)?;
+        self.settler.forget_tx(tx_hash)
+    }
+
+    /// Enveloped payloads are settled idempotently, keyed on the block in their header
+    async fn send_wvm_calldata(
+        &mut self,
+        block_data: Vec<u8>,
+    ) -> Result<String, WvmDataSettlerError> {
+        match Envelope::decode(&block_data) {
+            Ok(envelope) => self.settle(envelope.header.meta(), block_data).await,
+            Err(_) => self.send_with_retry(block_data).await,
+        }
+    }
+
+    /// Blocks settled or left in flight by an earlier run are handled right away, other
+    /// payloads are queued in the wrapped settler and resent if they fail.
+    async fn submit_wvm_calldata(&mut self, block_data: Vec<u8>) -> PendingSettlement {
+        let block = Envelope::decode(&block_data)
+            .ok()
+            .map(|envelope| envelope.header.meta());
+        if let Some(block) = block {
+            if self.store.settled(&block).is_some() || self.store.in_flight(&block) {
+                return PendingSettlement::Done(self.settle(block, block_data).await);
+            }
+            if let Err(error) = self.store.record_in_flight(&block) {
+                return PendingSettlement::Done(Err(WvmDataSettlerError::Store(error)));
+            }
+        }
+
+        match self.settler.submit_wvm_calldata(block_data.clone()).await {
+            PendingSettlement::Queued(sequence) => {
+                self.queued.insert(sequence, (block, block_data));
+                PendingSettlement::Queued(sequence)
+            }
+            PendingSettlement::Done(result) => {
+                PendingSettlement::Done(self.finish_queued(block, block_data, result).await)
+            }
+        }
+    }
+
+    async fn await_wvm_calldata(
+        &mut self,
+        pending: PendingSettlement,
+    ) -> Result<String, WvmDataSettlerError> {
+        let queued = match &pending {
+            PendingSettlement::Queued(sequence) => self.queued.remove(sequence),
+            PendingSettlement::Done(_) => None,
+        };
+        let result = self.settler.await_wvm_calldata(pending).await;
+        match queued {
+            Some((block, block_data)) => self.finish_queued(block, block_data, result).await,
+            None => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dedup::OnChainLookup;
+    use crate::envelope::{BlockMeta, CodecId, Envelope, SerializationId};
+    use crate::retry::{
+        IdempotencyStore, MemoryIdempotencyStore, RetryPolicy, RetryingWvmDataSettler,
+    };
+    use crate::{WvmDataSettler, WvmDataSettlerError};
+    use async_trait::async_trait;
+    use std::time::Duration;
+
+    #[tokio::test]
+    pub async fn test_retry_and_idempotency() {
+        struct FlakyWvmDa {
+            error: &'static str,
+            failures: u32,
+            attempts: u32,
+            gas_bumps: u32,
+        }
+
+        #[async_trait]
+        impl WvmDataSettler for FlakyWvmDa {
+            fn bump_gas(&mut self, _percent: u64) {
+                self.gas_bumps += 1;
+            }
+
+            async fn send_wvm_calldata(
+                &mut self,
+                _block_data: Vec<u8>,
+            ) -> Result<String, WvmDataSettlerError> {
+                self.attempts += 1;
+                if self.attempts <= self.failures {
+                    Err(WvmDataSettlerError::from_rpc_error(self.error))
+                } else {
+                    Ok(format!("0x{:02x}", self.attempts))
+                }
+            }
+        }
+
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let flaky = FlakyWvmDa {
+            error: "transaction underpriced",
+            failures: 2,
+            attempts: 0,
+            gas_bumps: 0,
+        };
+        let mut settler =
+            RetryingWvmDataSettler::new(flaky, policy.clone(), MemoryIdempotencyStore::default());
+
+        let block = BlockMeta {
+            block_number: 1,
+            ..Default::default()
+        };
+        assert_eq!(settler.settle(block, vec![1]).await.unwrap(), "0x03");
+        assert_eq!(settler.settle(block, vec![1]).await.unwrap(), "0x03");
+        assert_eq!(settler.settler().attempts, 3);
+        assert_eq!(settler.settler().gas_bumps, 2);
+
+        // The trait path keys on the block of the envelope
+        let envelope = Envelope::seal(
+            BlockMeta {
+                block_number: 2,
+                ..Default::default()
+            },
+            CodecId::Brotli,
+            SerializationId::Borsh,
+            vec![2],
+        )
+        .encode()
+        .unwrap();
+        let tx_hash = settler.send_wvm_calldata(envelope.clone()).await.unwrap();
+        assert_eq!(settler.send_wvm_calldata(envelope).await.unwrap(), tx_hash);
+        assert_eq!(settler.settler().attempts, 4);
+
+        // A transaction accepted before a transport error is not sent again
+        struct Posted;
+
+        #[async_trait]
+        impl OnChainLookup for Posted {
+            async fn find(&self, _content_hash: &[u8; 32]) -> eyre::Result<Option<String>> {
+                Ok(Some("0xposted".to_string()))
+            }
+        }
+
+        let flaky = FlakyWvmDa {
+            error: "connection reset",
+            failures: 1,
+            attempts: 0,
+            gas_bumps: 0,
+        };
+        let mut settler =
+            RetryingWvmDataSettler::new(flaky, policy, MemoryIdempotencyStore::default())
+                .with_lookup(Posted);
+        assert_eq!(settler.settle(block, vec![1]).await.unwrap(), "0xposted");
+        assert_eq!(settler.settler().attempts, 1);
+        assert!(!settler.store().in_flight(&block));
+    }
+}
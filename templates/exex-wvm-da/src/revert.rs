@@ -0,0 +1,319 @@
+use crate::batch::{BatchError, BatchPayload, BATCH_MAGIC};
+use crate::chunk::{fetch_payload, CHUNK_MAGIC, MANIFEST_MAGIC};
+use crate::envelope::{Envelope, EnvelopeError, EnvelopeHeader, ENVELOPE_MAGIC};
+use crate::index::{IndexCheckpoint, INDEX_MAGIC};
+use crate::provider::CalldataProvider;
+use borsh::{BorshDeserialize, BorshSerialize};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// Leading bytes of every revert record.
+pub const REVERT_MAGIC: [u8; 4] = *b"WVMR";
+pub const REVERT_VERSION: u8 = 1;
+
+#[derive(Debug, Error)]
+pub enum ArchiveRecordError {
+    #[error("Unknown archive record magic bytes")]
+    UnknownRecord,
+
+    #[error("Unsupported archive record version: {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Invalid revert record: {0}")]
+    InvalidRevertRecord(#[source] std::io::Error),
+
+    #[error("Invalid index checkpoint: {0}")]
+    InvalidIndexCheckpoint(#[source] std::io::Error),
+
+    #[error("Chunked payloads must be reassembled before decoding")]
+    Chunked,
+
+    #[error(transparent)]
+    Envelope(#[from] EnvelopeError),
+
+    #[error(transparent)]
+    Batch(#[from] BatchError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct RevertedBlock {
+    pub block_number: u64,
+    pub block_hash: [u8; 32],
+    /// Settlement transaction of the original archive
+    pub tx_hash: String,
+}
+
+/// Marks previously archived blocks as no longer canonical.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct RevertRecord {
+    pub chain_id: u64,
+    pub reverted: Vec<RevertedBlock>,
+    /// Number and hash of the tip of the chain that replaced the reverted blocks,
+    /// `None` for a plain revert
+    pub superseded_by: Option<(u64, [u8; 32])>,
+}
+
+impl RevertRecord {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buff = REVERT_MAGIC.to_vec();
+        buff.push(REVERT_VERSION);
+        // Writing into a Vec cannot fail
+        borsh::to_writer(&mut buff, self).expect("revert record serializes");
+        buff
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ArchiveRecordError> {
+        if data.len() < 5 || data[..4] != REVERT_MAGIC {
+            return Err(ArchiveRecordError::UnknownRecord);
+        }
+        if data[4] != REVERT_VERSION {
+            return Err(ArchiveRecordError::UnsupportedVersion(data[4]));
+        }
+        borsh::from_slice(&data[5..]).map_err(ArchiveRecordError::InvalidRevertRecord)
+    }
+}
+
+/// Any record the DA ExEx posts to WeaveVM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArchiveRecord {
+    Block(Envelope),
+    Batch(BatchPayload),
+    Revert(RevertRecord),
+    Index(IndexCheckpoint),
+}
+
+impl ArchiveRecord {
+    pub fn decode(data: &[u8]) -> Result<Self, ArchiveRecordError> {
+        match data.get(..4) {
+            Some(magic) if magic == ENVELOPE_MAGIC => {
+                Ok(ArchiveRecord::Block(Envelope::decode(data)?))
+            }
+            Some(magic) if magic == REVERT_MAGIC => {
+                Ok(ArchiveRecord::Revert(RevertRecord::decode(data)?))
+            }
+            Some(magic) if magic == INDEX_MAGIC => {
+                Ok(ArchiveRecord::Index(IndexCheckpoint::decode(data)?))
+            }
+            Some(magic) if magic == BATCH_MAGIC => {
+                Ok(ArchiveRecord::Batch(BatchPayload::decode(data)?))
+            }
+            Some(magic) if magic == CHUNK_MAGIC || magic == MANIFEST_MAGIC => {
+                Err(ArchiveRecordError::Chunked)
+            }
+            _ => Err(ArchiveRecordError::UnknownRecord),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchivedBlock {
+    pub block_hash: [u8; 32],
+    pub tx_hash: String,
+}
+
+/// Canonical chain as seen through the stream of archive and revert records.
+#[derive(Debug)]
+pub struct CanonicalView {
+    chain_id: u64,
+    blocks: BTreeMap<u64, ArchivedBlock>,
+}
+
+impl CanonicalView {
+    /// Records of other chains are ignored, several chains may settle to the same target
+    pub fn new(chain_id: u64) -> Self {
+        Self {
+            chain_id,
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    pub fn apply_archive(&mut self, header: &EnvelopeHeader, tx_hash: &str) {
+        if header.chain_id != self.chain_id {
+            return;
+        }
+        self.blocks.insert(
+            header.block_number,
+            ArchivedBlock {
+                block_hash: header.block_hash,
+                tx_hash: tx_hash.to_string(),
+            },
+        );
+    }
+
+    pub fn apply_revert(&mut self, record: &RevertRecord) {
+        if record.chain_id != self.chain_id {
+            return;
+        }
+        for reverted in &record.reverted {
+            let is_reverted = self
+                .blocks
+                .get(&reverted.block_number)
+                .is_some_and(|block| block.block_hash == reverted.block_hash);
+            if is_reverted {
+                self.blocks.remove(&reverted.block_number);
+            }
+        }
+    }
+
+    ///
+    /// Applies one settled record, in settlement order
+    ///
+    /// # Arguments
+    ///
+    /// * `tx_hash` - settlement transaction the record was read from
+    /// * `data` - calldata of that transaction, reassembled if it was chunked
+    pub fn apply(&mut self, tx_hash: &str, data: &[u8]) -> Result<(), ArchiveRecordError> {
+        match ArchiveRecord::decode(data)? {
+            ArchiveRecord::Block(envelope) => self.apply_archive(&envelope.header, tx_hash),
+            ArchiveRecord::Batch(batch) => {
+                // Decode every block first, a malformed batch must not be half applied
+                let headers = batch
+                    .entries
+                    .iter()
+                    .map(|entry| {
+                        let block_data = batch.entry_data(entry).unwrap_or_default();
+                        Ok(Envelope::decode(block_data)?.header)
+                    })
+                    .collect::<Result<Vec<_>, ArchiveRecordError>>()?;
+                for header in &headers {
+                    self.apply_archive(header, tx_hash);
+                }
+            }
+            ArchiveRecord::Revert(record) => self.apply_revert(&record),
+            ArchiveRecord::Index(_) => {}
+        }
+        Ok(())
+    }
+
+    ///
+    /// Reads a settled record, following its chunk manifest, and applies it
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - source of settlement calldata
+    /// * `tx_hash` - settlement transaction of the record; the transactions of single
+    ///   chunks are skipped, their manifest carries the record
+    pub async fn apply_settled<P>(&mut self, provider: &P, tx_hash: &str) -> eyre::Result<()>
+    where
+        P: CalldataProvider + ?Sized + Sync,
+    {
+        let data = fetch_payload(provider, tx_hash).await?;
+        if data.starts_with(&CHUNK_MAGIC) {
+            return Ok(());
+        }
+        Ok(self.apply(tx_hash, &data)?)
+    }
+
+    pub fn get(&self, block_number: u64) -> Option<&ArchivedBlock> {
+        self.blocks.get(&block_number)
+    }
+
+    pub fn tip(&self) -> Option<(u64, &ArchivedBlock)> {
+        self.blocks
+            .iter()
+            .next_back()
+            .map(|(block_number, block)| (*block_number, block))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &ArchivedBlock)> {
+        self.blocks
+            .iter()
+            .map(|(block_number, block)| (*block_number, block))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::BatchPayload;
+    use crate::chunk::{ChainLimits, ChunkingWvmDataSettler};
+    use crate::envelope::{BlockMeta, CodecId, Envelope, SerializationId};
+    use crate::mock::MockWvmSink;
+    use crate::revert::{CanonicalView, RevertRecord, RevertedBlock};
+    use crate::WvmDataSettler;
+
+    fn archive(chain_id: u64, block_number: u64, block_hash: [u8; 32]) -> Vec<u8> {
+        let meta = BlockMeta {
+            chain_id,
+            block_number,
+            block_hash,
+        };
+        Envelope::seal(meta, CodecId::Identity, SerializationId::Borsh, vec![])
+            .encode()
+            .unwrap()
+    }
+
+    #[test]
+    pub fn test_canonical_view() {
+        let mut view = CanonicalView::new(1);
+        view.apply("0x01", &archive(1, 1, [1; 32])).unwrap();
+        view.apply("0x02", &archive(1, 2, [2; 32])).unwrap();
+
+        let revert = |chain_id: u64| RevertRecord {
+            chain_id,
+            reverted: vec![RevertedBlock {
+                block_number: 2,
+                block_hash: [2; 32],
+                tx_hash: "0x02".to_string(),
+            }],
+            superseded_by: Some((2, [0xb2; 32])),
+        };
+        // Other chains settling to the same target are ignored
+        view.apply("0x03", &revert(2).encode()).unwrap();
+        view.apply("0x04", &archive(2, 3, [3; 32])).unwrap();
+        assert!(view.get(2).is_some());
+        assert!(view.get(3).is_none());
+
+        view.apply("0x05", &revert(1).encode()).unwrap();
+        assert!(view.get(2).is_none());
+
+        view.apply("0x06", &archive(1, 2, [0xb2; 32])).unwrap();
+        let (tip, block) = view.tip().unwrap();
+        assert_eq!(tip, 2);
+        assert_eq!(block.tx_hash, "0x06");
+        assert_eq!(view.iter().count(), 2);
+
+        let batch =
+            BatchPayload::new(&[(3, archive(1, 3, [3; 32])), (4, archive(1, 4, [4; 32]))]).unwrap();
+        view.apply("0x07", &batch.encode()).unwrap();
+        assert_eq!(view.get(3).unwrap().tx_hash, "0x07");
+        assert_eq!(view.tip().unwrap().0, 4);
+    }
+
+    #[tokio::test]
+    pub async fn test_canonical_view_chunked() {
+        let sink = MockWvmSink::new();
+        let mut settler = ChunkingWvmDataSettler::new(
+            sink.clone(),
+            ChainLimits {
+                max_calldata_bytes: 1024,
+            },
+        );
+        let meta = BlockMeta {
+            chain_id: 1,
+            block_number: 1,
+            block_hash: [1; 32],
+        };
+        let envelope = Envelope::seal(
+            meta,
+            CodecId::Identity,
+            SerializationId::Borsh,
+            vec![7; 2000],
+        )
+        .encode()
+        .unwrap();
+        let tx_hash = settler.send_wvm_calldata(envelope).await.unwrap();
+
+        let mut view = CanonicalView::new(1);
+        for submission in sink.submissions() {
+            view.apply_settled(&sink, &submission.tx_hash)
+                .await
+                .unwrap();
+        }
+        assert_eq!(view.get(1).unwrap().tx_hash, tx_hash);
+        assert_eq!(view.iter().count(), 1);
+    }
+}
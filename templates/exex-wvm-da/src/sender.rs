@@ -0,0 +1,507 @@
+use crate::config::{GasPolicy, NonceMode, WvmSettlerConfig};
+use crate::envelope::payload_checksum;
+use crate::error::WvmDataSettlerError;
+use crate::metrics;
+use alloy_consensus::{
+    BlobTransactionSidecar, SignableTransaction, TxEip4844, TxEip4844WithSidecar, TxEnvelope,
+};
+use alloy_eips::eip2718::Encodable2718;
+use alloy_primitives::{PrimitiveSignature as Signature, U256 as AlloyU256};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use web3::signing::{Key, SecretKey, SecretKeyRef};
+use web3::transports::Http;
+use web3::types::{Address, BlockNumber, Bytes, CallRequest, TransactionParameters, H256, U256};
+use web3::Web3;
+
+/// One signing account of a `WvmSettlerConfig`, submitting with explicit nonces.
+#[derive(Clone)]
+pub struct Web3Account {
+    web3: Web3<Http>,
+    chain_id: u64,
+    target: Address,
+    key: SecretKey,
+    address: Address,
+    gas: GasPolicy,
+}
+
+impl Web3Account {
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    pub async fn pending_nonce(&self) -> Result<U256, WvmDataSettlerError> {
+        self.transaction_count(BlockNumber::Pending).await
+    }
+
+    pub async fn transaction_count(&self, block: BlockNumber) -> Result<U256, WvmDataSettlerError> {
+        self.web3
+            .eth()
+            .transaction_count(self.address, Some(block))
+            .await
+            .map_err(WvmDataSettlerError::from_rpc_error)
+    }
+
+    /// Whether the node holds a receipt for `tx_hash`
+    pub async fn is_included(&self, tx_hash: &str) -> Result<bool, WvmDataSettlerError> {
+        let hash = H256::from_str(tx_hash)
+            .map_err(|e| WvmDataSettlerError::Config(format!("invalid tx hash: {}", e)))?;
+        let receipt = self
+            .web3
+            .eth()
+            .transaction_receipt(hash)
+            .await
+            .map_err(WvmDataSettlerError::from_rpc_error)?;
+        Ok(receipt.is_some())
+    }
+
+    ///
+    /// Signs and broadcasts a settlement transaction, returning its hash without waiting
+    /// for inclusion
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - account nonce of the transaction
+    /// * `data` - calldata
+    /// * `gas_bump_percent` - added on top of the configured gas price
+    pub async fn submit(
+        &self,
+        nonce: U256,
+        data: Vec<u8>,
+        gas_bump_percent: u64,
+    ) -> Result<String, WvmDataSettlerError> {
+        let gas_price = self.gas_price(gas_bump_percent).await?;
+        self.submit_at_price(nonce, data, gas_price).await
+    }
+
+    /// `submit` paying exactly `gas_price` wei per gas
+    pub async fn submit_at_price(
+        &self,
+        nonce: U256,
+        data: Vec<u8>,
+        gas_price: U256,
+    ) -> Result<String, WvmDataSettlerError> {
+        let result = self.sign_and_send(nonce, data, gas_price).await;
+        metrics::record_submission(&result);
+        result
+    }
+
+    async fn sign_and_send(
+        &self,
+        nonce: U256,
+        data: Vec<u8>,
+        gas_price: U256,
+    ) -> Result<String, WvmDataSettlerError> {
+        let data = Bytes(data);
+        let gas_limit = self.gas_limit(&data).await?;
+
+        let tx = TransactionParameters {
+            nonce: Some(nonce),
+            to: Some(self.target),
+            gas: gas_limit,
+            gas_price: Some(gas_price),
+            data,
+            chain_id: Some(self.chain_id),
+            ..Default::default()
+        };
+
+        let signed = self
+            .web3
+            .accounts()
+            .sign_transaction(tx, &self.key)
+            .await
+            .map_err(WvmDataSettlerError::from_rpc_error)?;
+        let tx_hash = self
+            .web3
+            .eth()
+            .send_raw_transaction(signed.raw_transaction)
+            .await
+            .map_err(WvmDataSettlerError::from_rpc_error)?;
+
+        metrics::record_broadcast(gas_limit.as_u64(), gas_price.as_u128());
+        Ok(format!("{:?}", tx_hash))
+    }
+
+    ///
+    /// Signs and broadcasts a blob transaction (EIP-4844) carrying `sidecar` and no calldata,
+    /// returning its hash without waiting for inclusion
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - account nonce of the transaction
+    /// * `sidecar` - blobs with their KZG commitments and proofs
+    /// * `max_fee_per_blob_gas` - in wei, before the gas bump
+    /// * `gas_bump_percent` - added on top of the configured gas price and blob fee
+    pub async fn submit_blobs(
+        &self,
+        nonce: U256,
+        sidecar: BlobTransactionSidecar,
+        max_fee_per_blob_gas: u128,
+        gas_bump_percent: u64,
+    ) -> Result<String, WvmDataSettlerError> {
+        let gas_price = self.gas_price(gas_bump_percent).await?;
+        let max_fee_per_blob_gas = max_fee_per_blob_gas * (100 + gas_bump_percent as u128) / 100;
+        self.submit_blobs_at_price(nonce, sidecar, max_fee_per_blob_gas, gas_price)
+            .await
+    }
+
+    /// `submit_blobs` paying exactly `gas_price` and at most `max_fee_per_blob_gas`
+    pub async fn submit_blobs_at_price(
+        &self,
+        nonce: U256,
+        sidecar: BlobTransactionSidecar,
+        max_fee_per_blob_gas: u128,
+        gas_price: U256,
+    ) -> Result<String, WvmDataSettlerError> {
+        let result = self
+            .sign_and_send_blobs(nonce, sidecar, max_fee_per_blob_gas, gas_price)
+            .await;
+        metrics::record_submission(&result);
+        result
+    }
+
+    async fn sign_and_send_blobs(
+        &self,
+        nonce: U256,
+        sidecar: BlobTransactionSidecar,
+        max_fee_per_blob_gas: u128,
+        gas_price: U256,
+    ) -> Result<String, WvmDataSettlerError> {
+        let gas_limit = self.gas_limit(&Bytes::default()).await?;
+        // Paying the full price as priority fee mirrors the legacy transactions of `submit`
+        let gas_price = gas_price.as_u128();
+
+        let tx = TxEip4844 {
+            chain_id: self.chain_id,
+            nonce: nonce.as_u64(),
+            gas_limit: gas_limit.as_u64(),
+            max_fee_per_gas: gas_price,
+            max_priority_fee_per_gas: gas_price,
+            to: self.target.0.into(),
+            blob_versioned_hashes: sidecar.versioned_hashes().collect(),
+            max_fee_per_blob_gas,
+            ..Default::default()
+        };
+        let tx = TxEip4844WithSidecar::from_tx_and_sidecar(tx, sidecar);
+
+        let signature = SecretKeyRef::new(&self.key)
+            .sign_message(tx.signature_hash().as_slice())
+            .map_err(|e| WvmDataSettlerError::Blob(e.into()))?;
+        let signature = Signature::new(
+            AlloyU256::from_be_bytes(signature.r.0),
+            AlloyU256::from_be_bytes(signature.s.0),
+            signature.v == 1,
+        );
+        let raw_transaction = TxEnvelope::from(tx.into_signed(signature)).encoded_2718();
+
+        let tx_hash = self
+            .web3
+            .eth()
+            .send_raw_transaction(Bytes(raw_transaction))
+            .await
+            .map_err(WvmDataSettlerError::from_rpc_error)?;
+
+        metrics::record_broadcast(gas_limit.as_u64(), gas_price);
+
+        Ok(format!("{:?}", tx_hash))
+    }
+
+    async fn gas_limit(&self, data: &Bytes) -> Result<U256, WvmDataSettlerError> {
+        match self.gas.gas_limit {
+            Some(gas_limit) => Ok(U256::from(gas_limit)),
+            None => self
+                .web3
+                .eth()
+                .estimate_gas(
+                    CallRequest {
+                        from: Some(self.address),
+                        to: Some(self.target),
+                        data: Some(data.clone()),
+                        ..Default::default()
+                    },
+                    None,
+                )
+                .await
+                .map_err(WvmDataSettlerError::from_rpc_error),
+        }
+    }
+
+    /// Node gas price with the configured percentage and `gas_bump_percent` applied
+    pub async fn gas_price(&self, gas_bump_percent: u64) -> Result<U256, WvmDataSettlerError> {
+        let node_price = self
+            .web3
+            .eth()
+            .gas_price()
+            .await
+            .map_err(WvmDataSettlerError::from_rpc_error)?;
+        let price = node_price * self.gas.gas_price_percent / 100 * (100 + gas_bump_percent) / 100;
+        Ok(self.cap_gas_price(price))
+    }
+
+    fn cap_gas_price(&self, price: U256) -> U256 {
+        match self.gas.max_gas_price {
+            Some(max_gas_price) => price.min(U256::from(max_gas_price)),
+            None => price,
+        }
+    }
+}
+
+/// geth only accepts a replacement paying at least 10% more than the transaction it replaces
+const MIN_REPLACEMENT_BUMP_PERCENT: u64 = 10;
+
+/// Broadcasts remembered to replace them once they are dropped.
+const SENT_HISTORY: usize = 1024;
+
+/// Account, nonce and gas price of a broadcast transaction.
+#[derive(Debug, Clone, Copy)]
+struct SentTx {
+    account: usize,
+    nonce: U256,
+    gas_price: U256,
+    /// `Outgoing::key` of what the transaction carried
+    payload: [u8; 32],
+}
+
+enum Outgoing {
+    Calldata(Vec<u8>),
+    Blobs {
+        sidecar: BlobTransactionSidecar,
+        max_fee_per_blob_gas: u128,
+    },
+}
+
+impl Outgoing {
+    /// Identifies the payload, so only its resubmission replaces a dropped transaction
+    fn key(&self) -> [u8; 32] {
+        match self {
+            Outgoing::Calldata(data) => payload_checksum(data),
+            Outgoing::Blobs { sidecar, .. } => payload_checksum(
+                &sidecar
+                    .versioned_hashes()
+                    .flat_map(|hash| hash.0)
+                    .collect::<Vec<u8>>(),
+            ),
+        }
+    }
+}
+
+/// Signs and submits settlement transactions with the keys of a `WvmSettlerConfig`,
+/// rotating through them round-robin.
+pub struct Web3Sender {
+    accounts: Vec<Web3Account>,
+    /// Next nonce of each account in `NonceMode::Local`, `None` until fetched from the node
+    next_nonces: Vec<Option<U256>>,
+    next_account: usize,
+    nonce_mode: NonceMode,
+    /// Accumulated `bump_gas` percentage, cleared after a successful submission
+    gas_bump_percent: u64,
+    /// Latest broadcasts by tx hash, oldest first
+    sent: VecDeque<(String, SentTx)>,
+    /// Dropped transaction a resubmission of its payload replaces
+    replacing: Option<SentTx>,
+}
+
+impl Web3Sender {
+    pub fn new(config: &WvmSettlerConfig) -> Result<Self, WvmDataSettlerError> {
+        let transport = Http::new(&config.rpc_url)
+            .map_err(|e| WvmDataSettlerError::Config(format!("invalid rpc url: {}", e)))?;
+        let web3 = Web3::new(transport);
+        let target = Address::from_str(config.target_address.trim_start_matches("0x"))
+            .map_err(|e| WvmDataSettlerError::Config(format!("invalid target address: {}", e)))?;
+
+        if config.signer_keys.is_empty() {
+            return Err(WvmDataSettlerError::Config(
+                "at least one signer key is required".to_string(),
+            ));
+        }
+        let accounts: Vec<Web3Account> = config
+            .signer_keys
+            .iter()
+            .enumerate()
+            .map(|(index, pk)| {
+                let key = hex::decode(pk.trim_start_matches("0x"))
+                    .ok()
+                    .and_then(|bytes| SecretKey::from_slice(&bytes).ok())
+                    .ok_or_else(|| {
+                        WvmDataSettlerError::Config(format!("invalid signer key #{}", index))
+                    })?;
+                Ok(Web3Account {
+                    web3: web3.clone(),
+                    chain_id: config.chain_id,
+                    target,
+                    address: SecretKeyRef::new(&key).address(),
+                    key,
+                    gas: config.gas.clone(),
+                })
+            })
+            .collect::<Result<_, WvmDataSettlerError>>()?;
+
+        Ok(Self {
+            next_nonces: vec![None; accounts.len()],
+            accounts,
+            next_account: 0,
+            nonce_mode: config.nonce,
+            gas_bump_percent: 0,
+            sent: VecDeque::new(),
+            replacing: None,
+        })
+    }
+
+    /// Configured accounts, in rotation order
+    pub fn accounts(&self) -> &[Web3Account] {
+        &self.accounts
+    }
+
+    pub fn bump_gas(&mut self, percent: u64) {
+        self.gas_bump_percent += percent;
+    }
+
+    /// Makes a resubmission of the payload of `tx_hash` replace it: it is sent from the same
+    /// account with the same nonce, bumping the gas price it was sent at. Any other payload
+    /// disarms the replacement, as does a mined nonce, e.g. for reverted transactions.
+    pub fn forget_tx(&mut self, tx_hash: &str) {
+        if let Some((_, sent)) = self.sent.iter().find(|(sent_hash, _)| sent_hash == tx_hash) {
+            self.replacing = Some(*sent);
+        }
+    }
+
+    pub async fn send(&mut self, data: Vec<u8>) -> Result<String, WvmDataSettlerError> {
+        self.send_next(Outgoing::Calldata(data)).await
+    }
+
+    /// Sends a blob transaction from the next account, see `Web3Account::submit_blobs`
+    pub async fn send_blobs(
+        &mut self,
+        sidecar: BlobTransactionSidecar,
+        max_fee_per_blob_gas: u128,
+    ) -> Result<String, WvmDataSettlerError> {
+        self.send_next(Outgoing::Blobs {
+            sidecar,
+            max_fee_per_blob_gas,
+        })
+        .await
+    }
+
+    async fn send_next(&mut self, outgoing: Outgoing) -> Result<String, WvmDataSettlerError> {
+        let key = outgoing.key();
+        let replacing = match self.replacing.take() {
+            Some(stuck) if stuck.payload == key => {
+                match self.accounts[stuck.account]
+                    .transaction_count(BlockNumber::Latest)
+                    .await
+                {
+                    Ok(mined) if mined > stuck.nonce => None,
+                    Ok(_) => Some(stuck),
+                    Err(error) => {
+                        self.replacing = Some(stuck);
+                        return Err(error);
+                    }
+                }
+            }
+            _ => None,
+        };
+        let index = match replacing {
+            Some(stuck) => stuck.account,
+            None => {
+                let index = self.next_account;
+                self.next_account = (index + 1) % self.accounts.len();
+                index
+            }
+        };
+
+        let result = self.send_with(index, outgoing, key, replacing).await;
+        match &result {
+            Ok(_) => self.gas_bump_percent = 0,
+            // The local count drifted from the node, resync on the next submission
+            Err(WvmDataSettlerError::Nonce(_)) => self.next_nonces[index] = None,
+            // Retried with a higher bump
+            Err(_) => self.replacing = replacing,
+        }
+        result
+    }
+
+    async fn send_with(
+        &mut self,
+        index: usize,
+        outgoing: Outgoing,
+        key: [u8; 32],
+        replacing: Option<SentTx>,
+    ) -> Result<String, WvmDataSettlerError> {
+        let account = &self.accounts[index];
+        let nonce = match (replacing, self.nonce_mode, self.next_nonces[index]) {
+            (Some(stuck), _, _) => stuck.nonce,
+            (None, NonceMode::Local, Some(nonce)) => nonce,
+            _ => account.pending_nonce().await?,
+        };
+
+        let mut gas_price = account.gas_price(self.gas_bump_percent).await?;
+        if let Some(stuck) = replacing {
+            let bump = self.gas_bump_percent.max(MIN_REPLACEMENT_BUMP_PERCENT);
+            gas_price = gas_price.max(account.cap_gas_price(stuck.gas_price * (100 + bump) / 100));
+        }
+
+        let tx_hash = match outgoing {
+            Outgoing::Calldata(data) => account.submit_at_price(nonce, data, gas_price).await?,
+            Outgoing::Blobs {
+                sidecar,
+                max_fee_per_blob_gas,
+            } => {
+                let max_fee_per_blob_gas =
+                    max_fee_per_blob_gas * (100 + self.gas_bump_percent as u128) / 100;
+                account
+                    .submit_blobs_at_price(nonce, sidecar, max_fee_per_blob_gas, gas_price)
+                    .await?
+            }
+        };
+
+        if self.nonce_mode == NonceMode::Local && replacing.is_none() {
+            self.next_nonces[index] = Some(nonce + 1);
+        }
+        if self.sent.len() == SENT_HISTORY {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((
+            tx_hash.clone(),
+            SentTx {
+                account: index,
+                nonce,
+                gas_price,
+                payload: key,
+            },
+        ));
+        Ok(tx_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::WvmSettlerConfig;
+    use crate::sender::Web3Sender;
+    use crate::WvmDataSettlerError;
+
+    #[test]
+    pub fn test_sender_from_config() {
+        let mut config = WvmSettlerConfig {
+            rpc_url: "http://localhost:8545".to_string(),
+            chain_id: 9496,
+            signer_keys: vec![
+                "9234bd23a4180e3a37a565150b058e20987dceb6ac63d98a571ec8197222242c".to_string(),
+                "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318".to_string(),
+            ],
+            target_address: "0xa2A0D977847805fE224B789D8C4d3D711ab251e7".to_string(),
+            gas: Default::default(),
+            nonce: Default::default(),
+        };
+
+        let sender = Web3Sender::new(&config).unwrap();
+        let accounts = sender.accounts();
+        assert_eq!(accounts.len(), 2);
+        assert_ne!(accounts[0].address(), accounts[1].address());
+
+        config.signer_keys = vec!["not a key".to_string()];
+        assert!(matches!(
+            Web3Sender::new(&config),
+            Err(WvmDataSettlerError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::backfill::BlockSource;
+use crate::envelope::BlockMeta;
+use crate::profile::{DaPayload, PayloadProfile};
+use async_trait::async_trait;
+use eyre::{eyre, Error};
+use reth::primitives::TransactionVariant;
+use reth::providers::{BlockNumReader, BlockReader, ReceiptProvider as _};
+
+/// Reads historical blocks from a reth provider: the provider of a running node
+/// (`ExExContext::provider`), or a read-only `ProviderFactory` over a node datadir,
+/// which also serves the blocks moved to static files.
+///
+/// Database reads block, they run on tokio's blocking pool.
+pub struct ProviderBlockSource<P> {
+    provider: P,
+    chain_id: u64,
+    profile: PayloadProfile,
+}
+
+impl<P> ProviderBlockSource<P>
+where
+    P: BlockReader,
+{
+    /// Fails for `PayloadProfile::BlockWithStateDiff`, which historical blocks can't be
+    /// archived with.
+    pub fn new(provider: P, chain_id: u64, profile: PayloadProfile) -> Result<Self, Error> {
+        if profile == PayloadProfile::BlockWithStateDiff {
+            return Err(eyre!("State diffs can't be backfilled from a provider"));
+        }
+        Ok(Self {
+            provider,
+            chain_id,
+            profile,
+        })
+    }
+}
+
+impl<P> ProviderBlockSource<P>
+where
+    P: BlockReader,
+{
+    fn read_block(&self, block_number: u64) -> Result<Option<(BlockMeta, DaPayload)>, Error> {
+        let Some(block) = self
+            .provider
+            .sealed_block_with_senders(block_number.into(), TransactionVariant::WithHash)?
+        else {
+            return Ok(None);
+        };
+
+        let receipts = match self.profile {
+            PayloadProfile::BlockWithReceipts => self
+                .provider
+                .receipts_by_block(block_number.into())?
+                .ok_or_else(|| eyre!("Receipts of block {} are pruned", block_number))?,
+            _ => vec![],
+        };
+
+        let meta = BlockMeta {
+            chain_id: self.chain_id,
+            block_number,
+            block_hash: block.hash().0,
+        };
+        let payload = DaPayload::from_block(self.profile, &block, &receipts)
+            .ok_or_else(|| eyre!("Unsupported backfill profile {:?}", self.profile))?;
+
+        Ok(Some((meta, payload)))
+    }
+}
+
+#[async_trait]
+impl<P> BlockSource for ProviderBlockSource<P>
+where
+    P: BlockReader + Clone + Send + Sync + 'static,
+{
+    type Payload = DaPayload;
+
+    async fn tip(&self) -> Result<u64, Error> {
+        let provider = self.provider.clone();
+        Ok(tokio::task::spawn_blocking(move || provider.best_block_number()).await??)
+    }
+
+    async fn block(&self, block_number: u64) -> Result<Option<(BlockMeta, DaPayload)>, Error> {
+        let source = Self {
+            provider: self.provider.clone(),
+            chain_id: self.chain_id,
+            profile: self.profile,
+        };
+        tokio::task::spawn_blocking(move || source.read_block(block_number)).await?
+    }
+}